@@ -0,0 +1,255 @@
+//! # Annotate driver.
+//! <p> Usage: <em> ruperf annotate [-i FILE] [--dso NAME] [--no-source] [--stdio] SYMBOL </em>
+//! Reads the samples of <em> perf.data </em>, or of FILE, and
+//! shows how those in the function SYMBOL spread over its
+//! instructions. SYMBOL is a name as the report shows it,
+//! with or without its hash, or as it is mangled. </p>
+//! <p> The function is disassembled from the file it was
+//! mapped from, x86_64 or aarch64. Lines of source found
+//! through the file's <em> .debug_line </em> are shown above
+//! the instructions compiled from them, and the basic blocks
+//! taking the most samples are highlighted. </p>
+
+mod disasm;
+mod output;
+
+extern crate structopt;
+use crate::bindings::*;
+use crate::event::records::Record;
+use crate::record::data::PerfData;
+use crate::report::demangle::Demangle;
+use crate::report::machine::Machine;
+use crate::report::symbols::{Dso, Symbol};
+use crate::report::{event_name, sorted_records};
+use disasm::Instruction;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Show where samples landed in a function.
+/// See `./ruperf annotate --help' for more information.
+#[derive(Debug, StructOpt)]
+pub struct AnnotateOptions {
+    #[structopt(help = "The function to annotate", value_name = "SYMBOL")]
+    pub symbol: String,
+
+    #[structopt(
+        short,
+        long,
+        help = "Read samples from FILE",
+        value_name = "FILE",
+        default_value = "perf.data",
+        parse(from_os_str)
+    )]
+    pub input: PathBuf,
+
+    #[structopt(
+        long,
+        help = "Only annotate the function in this file",
+        value_name = "NAME"
+    )]
+    pub dso: Option<String>,
+
+    #[structopt(long, help = "Leave out the source lines")]
+    pub no_source: bool,
+
+    #[structopt(long, help = "Print plain text, without colors")]
+    pub stdio: bool,
+}
+
+/// The samples of one event in one function.
+#[derive(Debug, Clone)]
+pub struct Annotation {
+    pub event: String,
+    /// The path of the file holding the function.
+    pub path: String,
+    pub symbol: Symbol,
+    /// The function's demangled name.
+    pub name: String,
+    pub samples: u64,
+    /// Samples by instruction address.
+    pub hits: BTreeMap<u64, u64>,
+}
+
+/// Whether the function mangled as `raw` goes by `name`.
+fn matches(raw: &str, name: &str) -> bool {
+    raw == name || Demangle::On.name(raw) == name || Demangle::NoHash.name(raw) == name
+}
+
+/// The samples of `data` in the functions named by `options`,
+/// one annotation per event and function, most samples first.
+/// Samples in the kernel are left out, its code not being
+/// at hand.
+pub fn annotations(data: &PerfData, options: &AnnotateOptions) -> Vec<Annotation> {
+    let mut machine = Machine::new(Demangle::Off, false);
+    let mut found: HashMap<(usize, String, u64), Annotation> = HashMap::new();
+    // Whether each function, by file and address, is the one.
+    let mut named: HashMap<(String, u64), bool> = HashMap::new();
+    for record in sorted_records(data) {
+        let sample = match &record {
+            Record::Sample(sample) => sample,
+            _ => {
+                machine.apply(&record);
+                continue;
+            }
+        };
+        if sample.misc as u32 & PERF_RECORD_MISC_CPUMODE_MASK == PERF_RECORD_MISC_KERNEL {
+            continue;
+        }
+        let pid = sample.pid.unwrap_or(0);
+        let (dso, address) = match machine.dso_at(pid, sample.ip.unwrap_or(0)) {
+            Some(found) => found,
+            None => continue,
+        };
+        if options
+            .dso
+            .as_deref()
+            .is_some_and(|only| only != dso.short_name())
+        {
+            continue;
+        }
+        let symbol = match dso.find(address) {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+        let function = (dso.path.clone(), symbol.start);
+        if !*named
+            .entry(function.clone())
+            .or_insert_with(|| matches(&symbol.name, &options.symbol))
+        {
+            continue;
+        }
+        let index = data.attr_index(sample.id);
+        let annotation = found
+            .entry((index, function.0, function.1))
+            .or_insert_with(|| Annotation {
+                event: event_name(&data.attrs[index].attr),
+                path: dso.path.clone(),
+                symbol: symbol.clone(),
+                name: Demangle::On.name(&symbol.name),
+                samples: 0,
+                hits: BTreeMap::new(),
+            });
+        annotation.samples += 1;
+        *annotation.hits.entry(address).or_default() += 1;
+    }
+    let mut annotations: Vec<Annotation> = found.into_values().collect();
+    annotations.sort_by(|a, b| {
+        b.samples
+            .cmp(&a.samples)
+            .then_with(|| a.path.cmp(&b.path))
+            .then_with(|| a.event.cmp(&b.event))
+    });
+    annotations
+}
+
+/// The lines of source to show above each of `instructions`:
+/// where the source line changes, its file and number and,
+/// if the file can be read, its text.
+fn source_lines(
+    dso: &Dso,
+    instructions: &[Instruction],
+    files: &mut HashMap<String, Vec<String>>,
+) -> Vec<Vec<String>> {
+    let mut last = None;
+    instructions
+        .iter()
+        .map(|instruction| {
+            let line = dso.line(instruction.address);
+            if line.is_none() || line == last {
+                return Vec::new();
+            }
+            last = line.clone();
+            let (file, number) = line.unwrap();
+            let text = files
+                .entry(file.clone())
+                .or_insert_with(|| {
+                    fs::read_to_string(&file)
+                        .map(|text| text.lines().map(str::to_string).collect())
+                        .unwrap_or_default()
+                })
+                .get(number as usize - 1)
+                .map(|text| text.trim_end().to_string());
+            std::iter::once(format!("{}:{}", file, number))
+                .chain(text)
+                .collect()
+        })
+        .collect()
+}
+
+pub fn run_annotate(options: AnnotateOptions) {
+    let data = match PerfData::open(&options.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.input.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let annotations = annotations(&data, &options);
+    if annotations.is_empty() {
+        eprintln!(
+            "No samples in {} in {}",
+            options.symbol,
+            options.input.display()
+        );
+        std::process::exit(1);
+    }
+    let color = !options.stdio && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let mut out = std::io::stdout();
+    let mut dsos: HashMap<String, Dso> = HashMap::new();
+    let mut files = HashMap::new();
+    for annotation in &annotations {
+        let bytes = match fs::read(&annotation.path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Could not read {}: {}", annotation.path, e);
+                continue;
+            }
+        };
+        let instructions = object::File::parse(&*bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let symbol = &annotation.symbol;
+                let code = disasm::code(&file, symbol.start, symbol.end)
+                    .ok_or_else(|| disasm::DisasmError::NoCode.to_string())?;
+                disasm::disassemble(object::Object::architecture(&file), code, symbol.start)
+                    .map_err(|e| e.to_string())
+            });
+        let instructions = match instructions {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                eprintln!("Could not disassemble {}: {}", annotation.name, e);
+                continue;
+            }
+        };
+        let sources = if options.no_source {
+            vec![Vec::new(); instructions.len()]
+        } else {
+            let dso = dsos
+                .entry(annotation.path.clone())
+                .or_insert_with(|| Dso::load(&annotation.path));
+            source_lines(dso, &instructions, &mut files)
+        };
+        let result = output::print_annotation(&mut out, annotation, &instructions, &sources, color);
+        // A reader such as `head` may stop early.
+        if let Err(e) = result {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                eprintln!("Could not print the annotation: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn matches_test() {
+    let raw = "_ZN4prog4leaf17h9ec7b37d53e3a6e0E";
+    assert!(matches(raw, raw));
+    assert!(matches(raw, "prog::leaf"));
+    assert!(matches(raw, "prog::leaf::h9ec7b37d53e3a6e0"));
+    assert!(!matches(raw, "leaf"));
+    assert!(matches("main", "main"));
+}
@@ -0,0 +1,162 @@
+//! Disassembly of functions, and their basic blocks.
+//! Instructions are decoded by Capstone, for x86_64 and
+//! aarch64 files. A basic block starts at the function's
+//! entry, at the target of any jump within the function, and
+//! after each jump or return, so that samples in a block
+//! were all taken on the same path through the code.
+
+use capstone::arch::{self, BuildsCapstone};
+use capstone::{Capstone, InsnGroupId, InsnGroupType};
+use object::{Architecture, Object, ObjectSection};
+use std::collections::HashSet;
+use std::ops::Range;
+use thiserror::Error;
+
+/// Errors met disassembling a function.
+#[derive(Debug, Error)]
+pub enum DisasmError {
+    #[error("cannot disassemble {0:?} code")]
+    Architecture(Architecture),
+    #[error("no section holds the function's code")]
+    NoCode,
+    #[error("{0}")]
+    Capstone(capstone::Error),
+}
+
+/// Capstone's errors are not `std::error::Error`s.
+impl From<capstone::Error> for DisasmError {
+    fn from(e: capstone::Error) -> Self {
+        DisasmError::Capstone(e)
+    }
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u64,
+    /// The mnemonic and operands.
+    pub text: String,
+    /// Whether the instruction is a jump or a return.
+    pub ends_block: bool,
+    /// Where a direct jump goes.
+    pub target: Option<u64>,
+}
+
+/// The bytes of `[start, end)` in the addresses of `file`.
+pub fn code<'a>(file: &object::File<'a>, start: u64, end: u64) -> Option<&'a [u8]> {
+    file.sections()
+        .find_map(|section| section.data_range(start, end - start).ok().flatten())
+}
+
+/// Decode `code`, found at `address` in a file for `architecture`.
+pub fn disassemble(
+    architecture: Architecture,
+    code: &[u8],
+    address: u64,
+) -> Result<Vec<Instruction>, DisasmError> {
+    let cs = match architecture {
+        Architecture::X86_64 => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .detail(true)
+            .build()?,
+        Architecture::Aarch64 => Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .detail(true)
+            .build()?,
+        other => return Err(DisasmError::Architecture(other)),
+    };
+    let is = |group: InsnGroupId, kind: InsnGroupType::Type| group.0 as InsnGroupType::Type == kind;
+    let mut instructions = Vec::new();
+    for insn in cs.disasm_all(code, address)?.iter() {
+        let detail = cs.insn_detail(&insn)?;
+        let jump = detail
+            .groups()
+            .any(|group| is(group, InsnGroupType::CS_GRP_JUMP));
+        let ret = detail
+            .groups()
+            .any(|group| is(group, InsnGroupType::CS_GRP_RET));
+        let operands = insn.op_str().unwrap_or("");
+        // A direct target is the last operand: `0x1234` on
+        // x86_64, `#0x1234` on aarch64.
+        let target = operands
+            .rsplit([' ', ','])
+            .next()
+            .map(|operand| operand.trim_start_matches('#'))
+            .and_then(|operand| operand.strip_prefix("0x"))
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .filter(|_| jump);
+        instructions.push(Instruction {
+            address: insn.address(),
+            text: format!("{} {}", insn.mnemonic().unwrap_or("?"), operands)
+                .trim_end()
+                .to_string(),
+            ends_block: jump || ret,
+            target,
+        });
+    }
+    Ok(instructions)
+}
+
+/// The basic blocks of a function's `instructions`, as
+/// ranges of indices.
+pub fn blocks(instructions: &[Instruction]) -> Vec<Range<usize>> {
+    let targets: HashSet<u64> = instructions.iter().filter_map(|i| i.target).collect();
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for (at, instruction) in instructions.iter().enumerate() {
+        if at > start && targets.contains(&instruction.address) {
+            blocks.push(start..at);
+            start = at;
+        }
+        if instruction.ends_block {
+            blocks.push(start..at + 1);
+            start = at + 1;
+        }
+    }
+    if start < instructions.len() {
+        blocks.push(start..instructions.len());
+    }
+    blocks
+}
+
+#[cfg(test)]
+#[test]
+fn disassemble_test() {
+    // test edi, edi; je +3; mov eax, 1; ret; xor eax, eax; ret
+    let x86 = [
+        0x85, 0xff, 0x74, 0x06, 0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3, 0x31, 0xc0, 0xc3,
+    ];
+    let instructions = disassemble(Architecture::X86_64, &x86, 0x1000).unwrap();
+    let texts: Vec<&str> = instructions.iter().map(|i| i.text.as_str()).collect();
+    assert_eq!(
+        texts,
+        vec![
+            "test edi, edi",
+            "je 0x100a",
+            "mov eax, 1",
+            "ret",
+            "xor eax, eax",
+            "ret"
+        ]
+    );
+    assert_eq!(instructions[1].target, Some(0x100a));
+    assert!(instructions[3].ends_block && !instructions[2].ends_block);
+    assert_eq!(blocks(&instructions), vec![0..2, 2..4, 4..6]);
+
+    // cbz x0, +8; mov x0, #1; ret
+    let aarch64 = [
+        0x40, 0x00, 0x00, 0xb4, 0x20, 0x00, 0x80, 0xd2, 0xc0, 0x03, 0x5f, 0xd6,
+    ];
+    let instructions = disassemble(Architecture::Aarch64, &aarch64, 0x2000).unwrap();
+    assert_eq!(instructions[0].text, "cbz x0, #0x2008");
+    assert_eq!(instructions[0].target, Some(0x2008));
+    assert!(instructions[2].ends_block);
+    assert_eq!(blocks(&instructions), vec![0..1, 1..2, 2..3]);
+
+    assert!(matches!(
+        disassemble(Architecture::Mips, &x86, 0),
+        Err(DisasmError::Architecture(Architecture::Mips))
+    ));
+}
@@ -0,0 +1,167 @@
+//! Printing of annotated functions.
+//! Each instruction is shown with the share of the function's
+//! samples taken on it, under the lines of source it came
+//! from. The hottest basic blocks are headed with their rank
+//! and share, and shown in red on terminals.
+
+use super::disasm::{self, Instruction};
+use super::Annotation;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// The number of basic blocks highlighted in a function.
+pub const HOT_BLOCKS: usize = 3;
+
+const RED: &str = "\x1b[0;31m";
+const RESET: &str = "\x1b[0m";
+
+/// A basic block, with its samples and, if it is among the
+/// hottest, its rank from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub range: Range<usize>,
+    pub samples: u64,
+    pub rank: Option<usize>,
+}
+
+/// The basic blocks of `instructions`, ranked by the samples
+/// of `annotation` they took. Blocks without samples are
+/// never hot.
+pub fn hot_blocks(annotation: &Annotation, instructions: &[Instruction]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = disasm::blocks(instructions)
+        .into_iter()
+        .map(|range| Block {
+            samples: instructions[range.clone()]
+                .iter()
+                .filter_map(|i| annotation.hits.get(&i.address))
+                .sum(),
+            range,
+            rank: None,
+        })
+        .collect();
+    let mut order: Vec<usize> = (0..blocks.len())
+        .filter(|at| blocks[*at].samples > 0)
+        .collect();
+    order.sort_by(|a, b| blocks[*b].samples.cmp(&blocks[*a].samples).then(a.cmp(b)));
+    for (rank, at) in order.into_iter().take(HOT_BLOCKS).enumerate() {
+        blocks[at].rank = Some(rank + 1);
+    }
+    blocks
+}
+
+/// Print `annotation` over its `instructions`, each under
+/// its `sources` lines, in red where hot if `color`.
+pub fn print_annotation(
+    out: &mut impl Write,
+    annotation: &Annotation,
+    instructions: &[Instruction],
+    sources: &[Vec<String>],
+    color: bool,
+) -> io::Result<()> {
+    let percent = |samples: u64| samples as f64 * 100.0 / annotation.samples.max(1) as f64;
+    let dso = Path::new(&annotation.path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&annotation.path);
+    let title = format!(
+        " Percent |  Source code & Disassembly of {} for {} ({} samples)",
+        dso, annotation.event, annotation.samples
+    );
+    writeln!(out, "{}", title)?;
+    writeln!(out, "{}", "-".repeat(title.len()))?;
+    writeln!(
+        out,
+        "         :  {:016x} <{}>:",
+        annotation.symbol.start, annotation.name
+    )?;
+    for block in hot_blocks(annotation, instructions) {
+        let (start, end) = if color && block.rank.is_some() {
+            (RED, RESET)
+        } else {
+            ("", "")
+        };
+        if let Some(rank) = block.rank {
+            writeln!(
+                out,
+                "{}         :  ; hot block #{}: {:.2}%{}",
+                start,
+                rank,
+                percent(block.samples),
+                end
+            )?;
+        }
+        for at in block.range {
+            for line in sources.get(at).into_iter().flatten() {
+                writeln!(out, "         :  {}", line)?;
+            }
+            let instruction = &instructions[at];
+            let hits = annotation.hits.get(&instruction.address).copied();
+            writeln!(
+                out,
+                "{}{:8.2} :    {:x}:  {}{}",
+                start,
+                percent(hits.unwrap_or(0)),
+                instruction.address,
+                instruction.text,
+                end
+            )?;
+        }
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+#[test]
+fn print_annotation_test() {
+    use crate::report::symbols::Symbol;
+    let instruction = |address, text: &str, ends_block, target| Instruction {
+        address,
+        text: text.to_string(),
+        ends_block,
+        target,
+    };
+    let instructions = vec![
+        instruction(0x1000, "test edi, edi", false, None),
+        instruction(0x1002, "je 0x100a", true, Some(0x100a)),
+        instruction(0x1004, "mov eax, 1", false, None),
+        instruction(0x1009, "ret", true, None),
+        instruction(0x100a, "xor eax, eax", false, None),
+        instruction(0x100c, "ret", true, None),
+    ];
+    let annotation = Annotation {
+        event: "cpu-clock".to_string(),
+        path: "/usr/bin/prog".to_string(),
+        symbol: Symbol {
+            start: 0x1000,
+            end: 0x100d,
+            name: "f".to_string(),
+        },
+        name: "f".to_string(),
+        samples: 4,
+        hits: vec![(0x1000, 1), (0x1004, 3)].into_iter().collect(),
+    };
+    let blocks = hot_blocks(&annotation, &instructions);
+    let ranks: Vec<Option<usize>> = blocks.iter().map(|block| block.rank).collect();
+    assert_eq!(ranks, vec![Some(2), Some(1), None]);
+
+    let mut sources = vec![Vec::new(); instructions.len()];
+    sources[0] = vec!["f.c:2".to_string(), "int f(int x) {".to_string()];
+    let mut out = Vec::new();
+    print_annotation(&mut out, &annotation, &instructions, &sources, false).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(
+        lines[0],
+        " Percent |  Source code & Disassembly of prog for cpu-clock (4 samples)"
+    );
+    assert_eq!(lines[2], "         :  0000000000001000 <f>:");
+    assert_eq!(lines[3], "         :  ; hot block #2: 25.00%");
+    assert_eq!(lines[4], "         :  f.c:2");
+    assert_eq!(lines[5], "         :  int f(int x) {");
+    assert_eq!(lines[6], "   25.00 :    1000:  test edi, edi");
+    assert_eq!(lines[8], "         :  ; hot block #1: 75.00%");
+    assert_eq!(lines[9], "   75.00 :    1004:  mov eax, 1");
+    assert_eq!(lines[11], "    0.00 :    100a:  xor eax, eax");
+    assert!(!text.contains(RED));
+}
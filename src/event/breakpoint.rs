@@ -0,0 +1,225 @@
+//! Hardware breakpoint events.
+//! A `PERF_TYPE_BREAKPOINT` event counts every access
+//! to an address through the CPU's debug registers:
+//! <ul>
+//! <li><em> mem:0xADDR[:ACCESS][/LEN] </em>, or with the length first as in `perf`</li>
+//! <li><em> mem:SYMBOL[:ACCESS][/LEN] </em>, a symbol of the traced program</li>
+//! </ul>
+//! ACCESS is any of `r` and `w`, or `x` alone, and
+//! defaults to `rw`. LEN is 1, 2, 4 or 8 bytes.
+//! Symbols are resolved in the traced process once it
+//! is running, through its ELF symbol table and load
+//! address in `/proc/PID/maps`.
+
+use crate::bindings::*;
+use crate::event::probe;
+use crate::utils::ParseError;
+use object::{Object, ObjectSegment};
+use std::fs;
+use std::path::Path;
+
+/// Where a breakpoint is placed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// A virtual address in the traced process.
+    Address(u64),
+    /// A symbol of the traced program, not yet resolved.
+    Symbol(String),
+}
+
+/// A breakpoint on an address or symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    /// The spec as written on the command line.
+    pub name: String,
+    pub location: Location,
+    /// The `HW_BREAKPOINT_*` access type.
+    pub access: u32,
+    /// The number of bytes watched.
+    pub len: u64,
+}
+
+impl Breakpoint {
+    /// The watched address, once resolved.
+    pub fn address(&self) -> Option<u64> {
+        match self.location {
+            Location::Address(address) => Some(address),
+            Location::Symbol(_) => None,
+        }
+    }
+
+    /// Resolve a symbol breakpoint to its address in the
+    /// executable of `pid`. Address breakpoints are kept as is.
+    pub fn resolve(&self, pid: i32) -> Result<Breakpoint, ParseError> {
+        let symbol = match &self.location {
+            Location::Address(_) => return Ok(self.clone()),
+            Location::Symbol(symbol) => symbol,
+        };
+        if pid <= 0 {
+            return Err(ParseError::UnresolvedBreakpoint(self.name.clone()));
+        }
+        let exe = fs::read_link(format!("/proc/{}/exe", pid))
+            .map_err(|_| ParseError::InvalidBinary(format!("/proc/{}/exe", pid)))?;
+        let address = symbol_in_process(pid, &exe, symbol)?;
+        Ok(Breakpoint {
+            location: Location::Address(address),
+            ..self.clone()
+        })
+    }
+}
+
+/// Whether `s` is a breakpoint spec.
+pub fn is_breakpoint(s: &str) -> bool {
+    s.starts_with("mem:")
+}
+
+/// Parse a breakpoint spec.
+pub fn parse(s: &str) -> Result<Breakpoint, ParseError> {
+    let invalid = || ParseError::InvalidBreakpoint(s.to_string());
+    let rest = s.strip_prefix("mem:").ok_or_else(invalid)?;
+    // The access may come before or after the length.
+    let (rest, len, access) = match rest.rfind('/') {
+        Some(at) => {
+            let (len, access) = match rest[at + 1..].split_once(':') {
+                Some((len, access)) => (len, Some(access)),
+                None => (&rest[at + 1..], None),
+            };
+            let len = len.parse::<u64>().map_err(|_| invalid())?;
+            (&rest[..at], Some(len), access)
+        }
+        None => (rest, None, None),
+    };
+    let (location, access) = match (access, rest.rfind(':')) {
+        (Some(access), _) => (rest, access),
+        (None, Some(at)) => (&rest[..at], &rest[at + 1..]),
+        (None, None) => (rest, "rw"),
+    };
+    let access = parse_access(access).ok_or_else(invalid)?;
+    let location = if location.starts_with("0x") {
+        Location::Address(probe::parse_number(location).ok_or_else(invalid)?)
+    } else if !location.is_empty() && !location.contains(':') {
+        Location::Symbol(location.to_string())
+    } else {
+        return Err(invalid());
+    };
+    // Like `perf`, watch a word of data, or one instruction.
+    let len = match len {
+        Some(len) => len,
+        None if access == HW_BREAKPOINT_X => std::mem::size_of::<libc::c_long>() as u64,
+        None => HW_BREAKPOINT_LEN_4 as u64,
+    };
+    if ![1, 2, 4, 8].contains(&len) {
+        return Err(invalid());
+    }
+    Ok(Breakpoint {
+        name: s.to_string(),
+        location,
+        access,
+        len,
+    })
+}
+
+/// Parse an access type: any of `r` and `w`, or `x` alone.
+fn parse_access(s: &str) -> Option<u32> {
+    let mut access = 0;
+    for c in s.chars() {
+        let bit = match c {
+            'r' => HW_BREAKPOINT_R,
+            'w' => HW_BREAKPOINT_W,
+            'x' => HW_BREAKPOINT_X,
+            _ => return None,
+        };
+        if access & bit != 0 {
+            return None;
+        }
+        access |= bit;
+    }
+    match access {
+        0 => None,
+        x if x & HW_BREAKPOINT_X != 0 && x != HW_BREAKPOINT_X => None,
+        x => Some(x),
+    }
+}
+
+/// The address of `symbol` in the running process `pid`,
+/// whose executable is `exe`. Position-independent
+/// executables are offset by where they were loaded.
+fn symbol_in_process(pid: i32, exe: &Path, symbol: &str) -> Result<u64, ParseError> {
+    let unreadable = || ParseError::InvalidBinary(exe.display().to_string());
+    let data = fs::read(format!("/proc/{}/exe", pid)).map_err(|_| unreadable())?;
+    let file = object::File::parse(&*data).map_err(|_| unreadable())?;
+    let address = probe::symbol_address(&file, symbol)
+        .ok_or_else(|| ParseError::UnknownSymbol(exe.display().to_string(), symbol.to_string()))?;
+    // `e_type`, in the byte order of the running system.
+    let e_type = data.get(16..18).map(|x| u16::from_ne_bytes([x[0], x[1]]));
+    if e_type != Some(object::elf::ET_DYN) {
+        return Ok(address);
+    }
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(|_| unreadable())?;
+    let lowest = file.segments().map(|seg| seg.address()).min().unwrap_or(0);
+    let base = load_base(&maps, exe).ok_or_else(unreadable)?;
+    Ok(base + address - (lowest & !0xfff))
+}
+
+/// Where the first page of `exe` is mapped, from the
+/// contents of a `/proc/PID/maps` file.
+fn load_base(maps: &str, exe: &Path) -> Option<u64> {
+    maps.lines().find_map(|line| {
+        // start-end perms offset dev inode path
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let first_page = fields.len() >= 6 && fields[2].trim_start_matches('0').is_empty();
+        if !first_page || Path::new(fields[5]) != exe {
+            return None;
+        }
+        let start = fields[0].split('-').next()?;
+        u64::from_str_radix(start, 16).ok()
+    })
+}
+
+#[cfg(test)]
+#[test]
+fn parse_breakpoint_test() {
+    let bp = parse("mem:0x1000").unwrap();
+    assert_eq!(bp.location, Location::Address(0x1000));
+    assert_eq!(bp.access, HW_BREAKPOINT_RW);
+    assert_eq!(bp.len, 4);
+    let bp = parse("mem:0x1000:rw/8").unwrap();
+    assert_eq!((bp.access, bp.len), (HW_BREAKPOINT_RW, 8));
+    assert_eq!(bp.name, "mem:0x1000:rw/8");
+    let bp = parse("mem:0x1000/2:w").unwrap();
+    assert_eq!((bp.access, bp.len), (HW_BREAKPOINT_W, 2));
+    assert_eq!(bp.name, "mem:0x1000/2:w");
+    let bp = parse("mem:my_static:w").unwrap();
+    assert_eq!(bp.location, Location::Symbol("my_static".to_string()));
+    assert_eq!(bp.address(), None);
+    let bp = parse("mem:main:x").unwrap();
+    assert_eq!(bp.len, std::mem::size_of::<libc::c_long>() as u64);
+    assert!(parse("mem:").is_err());
+    assert!(parse("mem:0x1000:rx").is_err());
+    assert!(parse("mem:0x1000:rr").is_err());
+    assert!(parse("mem:0x1000/3").is_err());
+    assert!(parse("mem:0xzz").is_err());
+}
+
+/// A static for `resolve_test` to find by name.
+#[cfg(test)]
+#[no_mangle]
+static RUPERF_BREAKPOINT_TEST: u64 = 0;
+
+#[test]
+fn resolve_test() {
+    let pid = std::process::id() as i32;
+    let bp = parse("mem:RUPERF_BREAKPOINT_TEST:r/8").unwrap();
+    let resolved = bp.resolve(pid).unwrap();
+    let expected = &RUPERF_BREAKPOINT_TEST as *const u64 as u64;
+    assert_eq!(resolved.address(), Some(expected));
+    assert_eq!(resolved.name, bp.name);
+    assert!(matches!(
+        bp.resolve(-1),
+        Err(ParseError::UnresolvedBreakpoint(_))
+    ));
+    assert!(matches!(
+        parse("mem:no_such_symbol_in_ruperf").unwrap().resolve(pid),
+        Err(ParseError::UnknownSymbol(_, _))
+    ));
+}
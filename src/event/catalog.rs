@@ -0,0 +1,522 @@
+//! Catalog of the kernel's generic events.
+//! Every generic hardware, software and hardware
+//! cache event, under the names and aliases `perf`
+//! accepts, along with the `type` and `config`
+//! each one is opened with. Raw and PMU events
+//! are parsed in `pmu`, tracepoints in `tracepoint`
+//! and dynamic probes in `probe`.
+
+use crate::bindings::*;
+use crate::stat::StatEvent;
+
+/// The fields of `perf_event_attr` that select an event.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct EventConfig {
+    pub type_: u32,
+    pub config: u64,
+    pub config1: u64,
+    pub config2: u64,
+}
+
+/// A cache, as in `perf_hw_cache_id`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheId {
+    L1D,
+    L1I,
+    LL,
+    Dtlb,
+    Itlb,
+    Bpu,
+    Node,
+}
+
+/// An operation on a cache, as in `perf_hw_cache_op_id`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheOp {
+    Read,
+    Write,
+    Prefetch,
+}
+
+/// The outcome of a cache operation, as in `perf_hw_cache_op_result_id`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheResult {
+    Access,
+    Miss,
+}
+
+/// A generic event and the names it is known by.
+/// The first name is the canonical one.
+pub struct EventNames {
+    pub event: StatEvent,
+    pub names: &'static [&'static str],
+}
+
+/// Generic hardware events, `PERF_TYPE_HARDWARE`.
+pub const HARDWARE_EVENTS: &[EventNames] = &[
+    EventNames {
+        event: StatEvent::Cycles,
+        names: &["cycles", "cpu-cycles"],
+    },
+    EventNames {
+        event: StatEvent::Instructions,
+        names: &["instructions"],
+    },
+    EventNames {
+        event: StatEvent::CacheReferences,
+        names: &["cache-references"],
+    },
+    EventNames {
+        event: StatEvent::CacheMisses,
+        names: &["cache-misses"],
+    },
+    EventNames {
+        event: StatEvent::BranchInstructions,
+        names: &["branches", "branch-instructions"],
+    },
+    EventNames {
+        event: StatEvent::BranchMisses,
+        names: &["branch-misses"],
+    },
+    EventNames {
+        event: StatEvent::BusCycles,
+        names: &["bus-cycles"],
+    },
+    EventNames {
+        event: StatEvent::StalledCyclesFrontend,
+        names: &["stalled-cycles-frontend", "idle-cycles-frontend"],
+    },
+    EventNames {
+        event: StatEvent::StalledCyclesBackend,
+        names: &["stalled-cycles-backend", "idle-cycles-backend"],
+    },
+    EventNames {
+        event: StatEvent::RefCycles,
+        names: &["ref-cycles"],
+    },
+];
+
+/// Generic software events, `PERF_TYPE_SOFTWARE`.
+pub const SOFTWARE_EVENTS: &[EventNames] = &[
+    EventNames {
+        event: StatEvent::CpuClock,
+        names: &["cpu-clock"],
+    },
+    EventNames {
+        event: StatEvent::TaskClock,
+        names: &["task-clock"],
+    },
+    EventNames {
+        event: StatEvent::PageFaults,
+        names: &["page-faults", "faults"],
+    },
+    EventNames {
+        event: StatEvent::ContextSwitches,
+        names: &["context-switches", "cs"],
+    },
+    EventNames {
+        event: StatEvent::CpuMigrations,
+        names: &["cpu-migrations", "migrations"],
+    },
+    EventNames {
+        event: StatEvent::MinorFaults,
+        names: &["minor-faults"],
+    },
+    EventNames {
+        event: StatEvent::MajorFaults,
+        names: &["major-faults"],
+    },
+    EventNames {
+        event: StatEvent::AlignmentFaults,
+        names: &["alignment-faults"],
+    },
+    EventNames {
+        event: StatEvent::EmulationFaults,
+        names: &["emulation-faults"],
+    },
+    EventNames {
+        event: StatEvent::Dummy,
+        names: &["dummy"],
+    },
+    EventNames {
+        event: StatEvent::BpfOutput,
+        names: &["bpf-output"],
+    },
+];
+
+/// Names of each cache, canonical first. `L1D-cache` and
+/// `L1I-cache` are kept for the names ruperf used before.
+const CACHE_NAMES: &[(CacheId, &[&str])] = &[
+    (
+        CacheId::L1D,
+        &["L1-dcache", "l1-d", "l1d", "L1-data", "L1D-cache"],
+    ),
+    (
+        CacheId::L1I,
+        &["L1-icache", "l1-i", "l1i", "L1-instruction", "L1I-cache"],
+    ),
+    (CacheId::LL, &["LLC", "L2"]),
+    (CacheId::Dtlb, &["dTLB", "d-tlb", "Data-TLB"]),
+    (CacheId::Itlb, &["iTLB", "i-tlb", "Instruction-TLB"]),
+    (CacheId::Bpu, &["branch", "branches", "bpu", "btb", "bpc"]),
+    (CacheId::Node, &["node"]),
+];
+
+/// Names of each operation. The first two are the canonical
+/// plural and singular forms, e.g. `loads` and `load-misses`.
+const OP_NAMES: &[(CacheOp, &[&str])] = &[
+    (CacheOp::Read, &["loads", "load", "read", "reads"]),
+    (CacheOp::Write, &["stores", "store", "write", "writes"]),
+    (
+        CacheOp::Prefetch,
+        &[
+            "prefetches",
+            "prefetch",
+            "speculative-read",
+            "speculative-load",
+        ],
+    ),
+];
+
+/// Names of each result.
+const RESULT_NAMES: &[(CacheResult, &[&str])] = &[
+    (CacheResult::Access, &["refs", "reference", "ops", "access"]),
+    (CacheResult::Miss, &["misses", "miss"]),
+];
+
+/// Whether the kernel's generic cache events support
+/// `op` on `cache`. Mirrors the table `perf` uses.
+pub fn is_valid_cache_op(cache: CacheId, op: CacheOp) -> bool {
+    match cache {
+        CacheId::L1I => op != CacheOp::Write,
+        CacheId::Itlb | CacheId::Bpu => op == CacheOp::Read,
+        _ => true,
+    }
+}
+
+/// Every supported hardware cache event, in `perf list` order.
+pub fn cache_events() -> Vec<StatEvent> {
+    let mut events = Vec::new();
+    for (cache, _) in CACHE_NAMES {
+        for (op, _) in OP_NAMES {
+            if !is_valid_cache_op(*cache, *op) {
+                continue;
+            }
+            for (result, _) in RESULT_NAMES {
+                events.push(StatEvent::HwCache(*cache, *op, *result));
+            }
+        }
+    }
+    events
+}
+
+/// Look up an event by any of its names.
+/// Cache names are matched without regard to case, as `perf` does.
+pub fn parse(s: &str) -> Option<StatEvent> {
+    HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .find(|e| e.names.contains(&s))
+        .map(|e| e.event.clone())
+        .or_else(|| parse_cache(&s.to_lowercase()))
+}
+
+/// Parse `cache-op[-result]` or `cache-result`.
+/// A missing op is a read, a missing result an access.
+fn parse_cache(s: &str) -> Option<StatEvent> {
+    for (cache, cache_names) in CACHE_NAMES {
+        for rest in cache_names.iter().filter_map(|name| strip(s, name)) {
+            for (op, op_names) in OP_NAMES {
+                for result_name in op_names.iter().filter_map(|name| strip(rest, name)) {
+                    if let Some(result) = parse_result(result_name) {
+                        if is_valid_cache_op(*cache, *op) {
+                            return Some(StatEvent::HwCache(*cache, *op, result));
+                        }
+                    }
+                }
+            }
+            if let Some(result) = parse_result(rest).filter(|_| !rest.is_empty()) {
+                return Some(StatEvent::HwCache(*cache, CacheOp::Read, result));
+            }
+        }
+    }
+    None
+}
+
+/// Strip the name part `name` and the dash that follows
+/// it from the start of `s`.
+fn strip<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    if s == name {
+        Some("")
+    } else if s.starts_with(&name) && s[name.len()..].starts_with('-') {
+        Some(&s[name.len() + 1..])
+    } else {
+        None
+    }
+}
+
+/// Parse the result part of a cache event name, where
+/// an empty string means an access.
+fn parse_result(s: &str) -> Option<CacheResult> {
+    if s.is_empty() {
+        return Some(CacheResult::Access);
+    }
+    RESULT_NAMES
+        .iter()
+        .find(|(_, names)| names.contains(&s))
+        .map(|(result, _)| *result)
+}
+
+/// The names `event` is known by, canonical first.
+pub fn names(event: &StatEvent) -> Vec<String> {
+    match event {
+        StatEvent::HwCache(cache, op, result) => {
+            let cache_name = CACHE_NAMES.iter().find(|(c, _)| c == cache).unwrap().1[0];
+            let op_names = OP_NAMES.iter().find(|(o, _)| o == op).unwrap().1;
+            let name = match result {
+                CacheResult::Access => format!("{}-{}", cache_name, op_names[0]),
+                CacheResult::Miss => format!("{}-{}-misses", cache_name, op_names[1]),
+            };
+            vec![name]
+        }
+        StatEvent::Raw(config) => vec![format!("r{:x}", config)],
+        StatEvent::Pmu(pmu) => vec![pmu.name.clone()],
+        StatEvent::Tracepoint(tp) => vec![tp.name.clone()],
+        StatEvent::Probe(probe) => vec![probe.name.clone()],
+        StatEvent::Breakpoint(bp) => vec![bp.name.clone()],
+        StatEvent::Tracepoints(tps) => tps.iter().map(|tp| tp.name.clone()).collect(),
+        // PMU events take their modifiers after the closing slash.
+        StatEvent::Modified(base, modifiers) => names(base)
+            .into_iter()
+            .map(|name| match **base {
+                StatEvent::Pmu(_) => format!("{}{}", name, modifiers),
+                _ => format!("{}:{}", name, modifiers),
+            })
+            .collect(),
+        _ => HARDWARE_EVENTS
+            .iter()
+            .chain(SOFTWARE_EVENTS)
+            .find(|e| e.event == *event)
+            .map(|e| e.names.iter().map(|x| x.to_string()).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// The canonical name of `event`.
+pub fn name(event: &StatEvent) -> String {
+    names(event).into_iter().next().unwrap_or_default()
+}
+
+/// The fields of `perf_event_attr` that select `event`.
+pub fn config(event: &StatEvent) -> EventConfig {
+    let hw = |id| (perf_type_id_PERF_TYPE_HARDWARE, id as u64);
+    let sw = |id| (perf_type_id_PERF_TYPE_SOFTWARE, id as u64);
+    let (type_, config) = match event {
+        StatEvent::Cycles => hw(perf_hw_id_PERF_COUNT_HW_CPU_CYCLES),
+        StatEvent::Instructions => hw(perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS),
+        StatEvent::CacheReferences => hw(perf_hw_id_PERF_COUNT_HW_CACHE_REFERENCES),
+        StatEvent::CacheMisses => hw(perf_hw_id_PERF_COUNT_HW_CACHE_MISSES),
+        StatEvent::BranchInstructions => hw(perf_hw_id_PERF_COUNT_HW_BRANCH_INSTRUCTIONS),
+        StatEvent::BranchMisses => hw(perf_hw_id_PERF_COUNT_HW_BRANCH_MISSES),
+        StatEvent::BusCycles => hw(perf_hw_id_PERF_COUNT_HW_BUS_CYCLES),
+        StatEvent::StalledCyclesFrontend => hw(perf_hw_id_PERF_COUNT_HW_STALLED_CYCLES_FRONTEND),
+        StatEvent::StalledCyclesBackend => hw(perf_hw_id_PERF_COUNT_HW_STALLED_CYCLES_BACKEND),
+        StatEvent::RefCycles => hw(perf_hw_id_PERF_COUNT_HW_REF_CPU_CYCLES),
+        StatEvent::CpuClock => sw(perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK),
+        StatEvent::TaskClock => sw(perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK),
+        StatEvent::PageFaults => sw(perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS),
+        StatEvent::ContextSwitches => sw(perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES),
+        StatEvent::CpuMigrations => sw(perf_sw_ids_PERF_COUNT_SW_CPU_MIGRATIONS),
+        StatEvent::MinorFaults => sw(perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS_MIN),
+        StatEvent::MajorFaults => sw(perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS_MAJ),
+        StatEvent::AlignmentFaults => sw(perf_sw_ids_PERF_COUNT_SW_ALIGNMENT_FAULTS),
+        StatEvent::EmulationFaults => sw(perf_sw_ids_PERF_COUNT_SW_EMULATION_FAULTS),
+        StatEvent::Dummy => sw(perf_sw_ids_PERF_COUNT_SW_DUMMY),
+        StatEvent::BpfOutput => sw(perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT),
+        StatEvent::HwCache(cache, op, result) => {
+            // The config of a cache event packs the cache id,
+            // op and result into its low three bytes.
+            // See the `perf_event_open()` man page for details.
+            let id = match cache {
+                CacheId::L1D => perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
+                CacheId::L1I => perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
+                CacheId::LL => perf_hw_cache_id_PERF_COUNT_HW_CACHE_LL,
+                CacheId::Dtlb => perf_hw_cache_id_PERF_COUNT_HW_CACHE_DTLB,
+                CacheId::Itlb => perf_hw_cache_id_PERF_COUNT_HW_CACHE_ITLB,
+                CacheId::Bpu => perf_hw_cache_id_PERF_COUNT_HW_CACHE_BPU,
+                CacheId::Node => perf_hw_cache_id_PERF_COUNT_HW_CACHE_NODE,
+            };
+            let op = match op {
+                CacheOp::Read => perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
+                CacheOp::Write => perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
+                CacheOp::Prefetch => perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_PREFETCH,
+            };
+            let result = match result {
+                CacheResult::Access => perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
+                CacheResult::Miss => perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
+            };
+            (
+                perf_type_id_PERF_TYPE_HW_CACHE,
+                (id as u64) | ((op as u64) << 8) | ((result as u64) << 16),
+            )
+        }
+        StatEvent::Raw(config) => (perf_type_id_PERF_TYPE_RAW, *config),
+        StatEvent::Pmu(pmu) => return pmu.config,
+        StatEvent::Tracepoint(tp) => (perf_type_id_PERF_TYPE_TRACEPOINT, tp.id),
+        StatEvent::Probe(probe) => return probe.attr_config(),
+        // `bp_addr` and `bp_len` share their place with `config1` and `config2`.
+        StatEvent::Breakpoint(bp) => {
+            return EventConfig {
+                type_: perf_type_id_PERF_TYPE_BREAKPOINT,
+                config: 0,
+                config1: bp
+                    .address()
+                    .expect("breakpoint symbols are resolved before opening"),
+                config2: bp.len,
+            }
+        }
+        StatEvent::Tracepoints(_) => unreachable!("tracepoint globs are expanded before opening"),
+        StatEvent::Modified(base, _) => return config(base),
+    };
+    EventConfig {
+        type_,
+        config,
+        ..Default::default()
+    }
+}
+
+/// The event opened with `type_` and `config`, as read back
+/// from a `perf_event_attr`. Events outside the catalog are
+/// given as raw events where the `type` allows it.
+pub fn event_of(type_: u32, config: u64) -> Option<StatEvent> {
+    if type_ == perf_type_id_PERF_TYPE_RAW {
+        return Some(StatEvent::Raw(config));
+    }
+    HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .map(|e| e.event.clone())
+        .chain(cache_events())
+        .find(|event| {
+            let c = self::config(event);
+            (c.type_, c.config) == (type_, config)
+        })
+}
+
+/// Whether `event` only happens in kernel mode, so
+/// counting it must not exclude the kernel.
+pub fn counts_in_kernel(event: &StatEvent) -> bool {
+    match event {
+        StatEvent::ContextSwitches | StatEvent::CpuMigrations => true,
+        StatEvent::Tracepoint(_) => true,
+        StatEvent::Probe(probe) => probe.kernel,
+        StatEvent::Modified(base, _) => counts_in_kernel(base),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_test() {
+    assert_eq!(parse("cycles"), Some(StatEvent::Cycles));
+    assert_eq!(parse("cpu-cycles"), Some(StatEvent::Cycles));
+    assert_eq!(parse("cs"), Some(StatEvent::ContextSwitches));
+    assert_eq!(parse("branch-misses"), Some(StatEvent::BranchMisses));
+    let l1d_read = StatEvent::HwCache(CacheId::L1D, CacheOp::Read, CacheResult::Access);
+    assert_eq!(parse("L1-dcache-loads"), Some(l1d_read.clone()));
+    assert_eq!(parse("l1d-load"), Some(l1d_read.clone()));
+    assert_eq!(parse("L1D-cache-reads"), Some(l1d_read));
+    assert_eq!(
+        parse("L1D-cache-read-misses"),
+        Some(StatEvent::HwCache(
+            CacheId::L1D,
+            CacheOp::Read,
+            CacheResult::Miss
+        ))
+    );
+    assert_eq!(
+        parse("LLC-misses"),
+        Some(StatEvent::HwCache(
+            CacheId::LL,
+            CacheOp::Read,
+            CacheResult::Miss
+        ))
+    );
+    assert_eq!(
+        parse("branch-load-misses"),
+        Some(StatEvent::HwCache(
+            CacheId::Bpu,
+            CacheOp::Read,
+            CacheResult::Miss
+        ))
+    );
+    // The instruction cache cannot be written to.
+    assert_eq!(parse("L1-icache-stores"), None);
+    assert_eq!(parse("L1-dcache"), None);
+    assert_eq!(parse("not-an-event"), None);
+}
+
+#[test]
+fn names_test() {
+    assert_eq!(
+        name(&StatEvent::HwCache(
+            CacheId::Dtlb,
+            CacheOp::Write,
+            CacheResult::Miss
+        )),
+        "dTLB-store-misses"
+    );
+    assert_eq!(
+        names(&StatEvent::PageFaults),
+        vec!["page-faults".to_string(), "faults".to_string()]
+    );
+    // Every event round-trips through its names.
+    let all = HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .map(|e| e.event.clone())
+        .chain(cache_events());
+    for event in all {
+        for name in names(&event) {
+            assert_eq!(parse(&name), Some(event.clone()), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn config_test() {
+    let cache = config(&StatEvent::HwCache(
+        CacheId::LL,
+        CacheOp::Prefetch,
+        CacheResult::Miss,
+    ));
+    assert_eq!(cache.type_, perf_type_id_PERF_TYPE_HW_CACHE);
+    assert_eq!(cache.config, 0x1_02_02);
+    let faults = config(&StatEvent::MajorFaults);
+    assert_eq!(faults.type_, perf_type_id_PERF_TYPE_SOFTWARE);
+    assert_eq!(
+        faults.config,
+        perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS_MAJ as u64
+    );
+    let raw = config(&StatEvent::Raw(0x1a8));
+    assert_eq!(raw.type_, perf_type_id_PERF_TYPE_RAW);
+    assert_eq!(name(&StatEvent::Raw(0x1a8)), "r1a8");
+    let user_cycles = StatEvent::Modified(Box::new(StatEvent::Cycles), "u".parse().unwrap());
+    assert_eq!(config(&user_cycles), config(&StatEvent::Cycles));
+    assert_eq!(name(&user_cycles), "cycles:u");
+    assert_eq!(
+        event_of(perf_type_id_PERF_TYPE_SOFTWARE, faults.config),
+        Some(StatEvent::MajorFaults)
+    );
+    assert_eq!(
+        event_of(cache.type_, cache.config),
+        Some(StatEvent::HwCache(
+            CacheId::LL,
+            CacheOp::Prefetch,
+            CacheResult::Miss
+        ))
+    );
+    assert_eq!(event_of(raw.type_, 0x1a8), Some(StatEvent::Raw(0x1a8)));
+    assert_eq!(event_of(perf_type_id_PERF_TYPE_TRACEPOINT, 1), None);
+}
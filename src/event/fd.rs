@@ -12,34 +12,148 @@ use crate::event::sys::linux::*;
 use crate::event::sys::wrapper::*;
 use crate::event::utils::*;
 use libc::{c_int, c_ulong, pid_t, syscall, SYS_perf_event_open};
+use std::ops::BitOr;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 
-/// Stores a raw file descriptor
+/// Owns a raw file descriptor
 /// for use in various `perf_event_open()`
-/// system call wrappers.
+/// system call wrappers, along with the
+/// `read_format` it was opened with.
+/// The descriptor is closed when dropped.
 #[derive(Debug)]
-pub struct FileDesc(i32);
+pub struct FileDesc {
+    fd: i32,
+    read_format: u64,
+}
+
+/// Flags passed to `perf_event_open()`.
+/// The default is `FD_CLOEXEC`, so counters are not
+/// inherited by commands the caller goes on to exec.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const NONE: OpenFlags = OpenFlags(0);
+    /// Do not join the group of `group_fd`.
+    pub const FD_NO_GROUP: OpenFlags = OpenFlags(PERF_FLAG_FD_NO_GROUP);
+    /// Send samples to the ring buffer of `group_fd`.
+    pub const FD_OUTPUT: OpenFlags = OpenFlags(PERF_FLAG_FD_OUTPUT);
+    /// `pid` is a file descriptor of a cgroup directory,
+    /// and every task in that cgroup is counted on `cpu`.
+    pub const PID_CGROUP: OpenFlags = OpenFlags(PERF_FLAG_PID_CGROUP);
+    /// Close the descriptor on `exec()`.
+    pub const FD_CLOEXEC: OpenFlags = OpenFlags(PERF_FLAG_FD_CLOEXEC);
+
+    /// The raw `PERF_FLAG_*` bits.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Whether every flag in `other` is set.
+    pub fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for OpenFlags {
+    fn default() -> Self {
+        OpenFlags::FD_CLOEXEC
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// A counter value along with how long the event was
+/// enabled and how long it was actually counting on the PMU.
+/// When the kernel multiplexes more events than there are
+/// hardware counters, `running` is less than `enabled`.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct CounterValue {
+    pub raw: u64,
+    pub enabled: u64,
+    pub running: u64,
+}
+
+/// Summing counts is used to total
+/// the same event across several tasks.
+impl std::ops::AddAssign for CounterValue {
+    fn add_assign(&mut self, other: Self) {
+        self.raw = self.raw.wrapping_add(other.raw);
+        self.enabled = self.enabled.wrapping_add(other.enabled);
+        self.running = self.running.wrapping_add(other.running);
+    }
+}
+
+impl CounterValue {
+    /// Estimate of what the count would have been had the
+    /// event been on the PMU for its whole enabled time.
+    /// Events opened without time fields are returned unscaled.
+    pub fn scaled(&self) -> u64 {
+        if self.running == 0 || self.running >= self.enabled {
+            return self.raw;
+        }
+        (self.raw as f64 * self.enabled as f64 / self.running as f64).round() as u64
+    }
+    /// Percentage of enabled time the event was running.
+    pub fn running_percent(&self) -> f64 {
+        if self.enabled == 0 {
+            return 100.0;
+        }
+        self.running as f64 * 100.0 / self.enabled as f64
+    }
+    /// Whether the kernel had to multiplex this event.
+    pub fn is_multiplexed(&self) -> bool {
+        self.running < self.enabled
+    }
+    /// Difference between this value and an earlier one.
+    pub fn delta(&self, earlier: &CounterValue) -> CounterValue {
+        CounterValue {
+            raw: self.raw.wrapping_sub(earlier.raw),
+            enabled: self.enabled.wrapping_sub(earlier.enabled),
+            running: self.running.wrapping_sub(earlier.running),
+        }
+    }
+}
 
 impl FileDesc {
     /// Set up performance monitoring for
-    /// configured event without any flags.
-    /// Panics if `perf_event_open()` fails.
-    pub fn new(event: &mut perf_event_attr, pid: Option<i32>, cpu: i32, group_fd: i32) -> Self {
-        let ret: i32;
+    /// configured event with `flags`, which
+    /// are usually `OpenFlags::default()`.
+    /// Pass the file descriptor of a group
+    /// leader as `group_fd` to join its group,
+    /// or -1 to create a new group.
+    pub fn new(
+        event: &mut perf_event_attr,
+        pid: Option<i32>,
+        cpu: i32,
+        group_fd: i32,
+        flags: OpenFlags,
+    ) -> Result<Self, OpenError> {
         let pid = match pid {
             Some(x) => x as pid_t,
             None => 0_i32,
         };
-        ret = perf_event_open(event, pid as pid_t, cpu, group_fd, 0) as i32;
+        let ret = perf_event_open(event, pid as pid_t, cpu, group_fd, flags.bits() as usize) as i32;
         if ret == -1 {
-            panic!("Panic: system call perf_event_open() failed in FileDesc::new()");
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            let ctx = OpenContext::new(errno, event, pid, cpu);
+            return Err(OpenError::from_context(ctx));
         }
-        Self(ret)
+        Ok(Self {
+            fd: ret,
+            read_format: event.read_format,
+        })
     }
     /// Enable the performance counter
     /// associated with `fd`.
     pub fn enable(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, ENABLE as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, ENABLE as u64, 0) };
         if ret == -1 {
             return Err(SysErr::IoFail);
         }
@@ -49,7 +163,37 @@ impl FileDesc {
     /// associated with `fd`.
     pub fn disable(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, DISABLE as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, DISABLE as u64, 0) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
+    }
+    /// Enable every counter in the group
+    /// led by the event associated with `fd`.
+    pub fn enable_group(&self) -> Result<(), SysErr> {
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                ENABLE as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
+    }
+    /// Disable every counter in the group
+    /// led by the event associated with `fd`.
+    pub fn disable_group(&self) -> Result<(), SysErr> {
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                DISABLE as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
         if ret == -1 {
             return Err(SysErr::IoFail);
         }
@@ -69,7 +213,7 @@ impl FileDesc {
             return Err(SysErr::IoArg);
         }
         let arg: *const usize = &count;
-        ret = unsafe { libc::ioctl(self.0, REFRESH as u64, arg) };
+        ret = unsafe { libc::ioctl(self.fd, REFRESH as u64, arg) };
         if ret == -1 {
             return Err(SysErr::IoFail);
         }
@@ -78,7 +222,22 @@ impl FileDesc {
     /// Reset the performance counter to 0.
     pub fn reset(&self) -> Result<(), SysErr> {
         let ret: i32;
-        ret = unsafe { libc::ioctl(self.0, RESET as u64, 0) };
+        ret = unsafe { libc::ioctl(self.fd, RESET as u64, 0) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
+    }
+    /// Reset every counter in the group
+    /// led by the event associated with `fd` to 0.
+    pub fn reset_group(&self) -> Result<(), SysErr> {
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd,
+                RESET as u64,
+                perf_event_ioc_flags_PERF_IOC_FLAG_GROUP,
+            )
+        };
         if ret == -1 {
             return Err(SysErr::IoFail);
         }
@@ -91,21 +250,32 @@ impl FileDesc {
     pub fn overflow_period(&self, interval: usize) -> Result<(), SysErr> {
         let ret: i32;
         let arg: *const usize = &interval;
-        ret = unsafe { libc::ioctl(self.0, PERIOD as u64, arg) };
+        ret = unsafe { libc::ioctl(self.fd, PERIOD as u64, arg) };
         if ret == -1 {
             return Err(SysErr::IoFail);
         }
         Ok(())
     }
-    /// Report counter information to
-    /// specific file descriptor.
-    pub fn set_output(&self) -> Result<(), SysErr> {
-        todo!()
+    /// Send the samples of this event to the ring
+    /// buffer of `output`, so several events can share
+    /// one buffer. Both must be on the same CPU, or
+    /// both on the same task when counting any CPU.
+    pub fn set_output(&self, output: &FileDesc) -> Result<(), SysErr> {
+        let ret = unsafe { libc::ioctl(self.fd, SET_OUTPUT as u64, output.fd as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
-    /// Ignore counter output for event
-    /// associated with `fd`.
+    /// Stop sending samples to another event's
+    /// ring buffer, undoing `set_output`.
     pub fn ignore_output(&self) -> Result<(), SysErr> {
-        todo!()
+        // The kernel compares the whole `unsigned long` to -1.
+        let ret = unsafe { libc::ioctl(self.fd, SET_OUTPUT as u64, -1_i64 as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Return event ID value
     /// associated with `fd`.
@@ -114,7 +284,7 @@ impl FileDesc {
         let mut ret: usize = 0;
         ret = unsafe {
             let result: *mut usize = &mut ret;
-            if libc::ioctl(self.0, ID as u64, result) == -1 {
+            if libc::ioctl(self.fd, ID as u64, result) == -1 {
                 return Err(SysErr::IoFail);
             }
             *result
@@ -126,27 +296,113 @@ impl FileDesc {
     }
     /// Pause writing to ring-buffer
     /// for associated file descriptor.
+    /// The ring buffer must be mapped.
     pub fn pause_output(&self) -> Result<(), SysErr> {
-        todo!()
+        self.toggle_output(1)
     }
     /// Resume writing to ring-buffer
     /// for associated file descriptor.
     pub fn resume_output(&self) -> Result<(), SysErr> {
-        todo!()
+        self.toggle_output(0)
+    }
+    /// Set the paused state of the ring buffer.
+    fn toggle_output(&self, paused: u32) -> Result<(), SysErr> {
+        let ret = unsafe { libc::ioctl(self.fd, PAUSE_OUTPUT as u64, paused as c_ulong) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Modify the attributes for
-    /// a specified event.
-    pub fn modify_attributes(&self, _event: *const perf_event_attr) -> Result<(), SysErr> {
-        todo!()
+    /// a specified event in place. The kernel
+    /// only supports this for breakpoints, whose
+    /// address, length and type may be changed;
+    /// `event.type_` must match the open event.
+    pub fn modify_attributes(&self, event: &perf_event_attr) -> Result<(), SysErr> {
+        let arg: *const perf_event_attr = event;
+        let ret = unsafe { libc::ioctl(self.fd, MODIFY_ATTRIBUTES as u64, arg) };
+        if ret == -1 {
+            return Err(SysErr::IoFail);
+        }
+        Ok(())
     }
     /// Read counter value associated
     /// with field of `FileDesc` caller.
-    pub fn read(&self) -> Result<isize, SysErr> {
-        let ret = read_wrap(self.0);
+    /// `enabled` and `running` are filled in when the
+    /// event was opened with `PERF_FORMAT_TOTAL_TIME_ENABLED`
+    /// and `PERF_FORMAT_TOTAL_TIME_RUNNING`.
+    pub fn read(&self) -> Result<CounterValue, SysErr> {
+        // Layout is { value, [time_enabled], [time_running], [id] }.
+        let mut buf = [0_u64; 4];
+        let ret = read_wrap(self.fd, &mut buf);
         if ret == -1 {
             return Err(SysErr::ReadFail);
         }
-        Ok(ret)
+        let mut words = buf.iter();
+        let mut value = CounterValue {
+            raw: *words.next().unwrap(),
+            ..Default::default()
+        };
+        self.read_times(&mut words, &mut value);
+        Ok(value)
+    }
+    /// Read every counter in the group in a
+    /// single `read()`. The event must have been
+    /// opened with `PERF_FORMAT_GROUP | PERF_FORMAT_ID`.
+    /// Returns `(value, id)` pairs in group order.
+    pub fn read_group(&self, nr: usize) -> Result<Vec<(CounterValue, u64)>, SysErr> {
+        // Layout is { nr, [time_enabled], [time_running], { value, id }[nr] }.
+        let mut buf = vec![0_u64; 3 + 2 * nr];
+        let ret = read_wrap(self.fd, &mut buf);
+        if ret == -1 {
+            return Err(SysErr::ReadFail);
+        }
+        let mut words = buf.iter();
+        if *words.next().unwrap() as usize != nr {
+            return Err(SysErr::ReadFail);
+        }
+        let mut times = CounterValue::default();
+        self.read_times(&mut words, &mut times);
+        let mut values = Vec::with_capacity(nr);
+        for _ in 0..nr {
+            let raw = *words.next().unwrap();
+            let id = *words.next().unwrap();
+            values.push((CounterValue { raw, ..times }, id));
+        }
+        Ok(values)
+    }
+    /// Fill in the time fields of `value` from `words`,
+    /// according to the `read_format` of this event.
+    fn read_times<'a>(&self, words: &mut impl Iterator<Item = &'a u64>, value: &mut CounterValue) {
+        let enabled = perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED as u64;
+        let running = perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING as u64;
+        if self.read_format & enabled != 0 {
+            value.enabled = *words.next().unwrap();
+        }
+        if self.read_format & running != 0 {
+            value.running = *words.next().unwrap();
+        }
+    }
+}
+
+impl AsRawFd for FileDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Give up ownership, leaving the descriptor open.
+impl IntoRawFd for FileDesc {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for FileDesc {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
     }
 }
 
@@ -216,3 +472,218 @@ fn read_test() {
     assert_ne!(cnt, 0);
     assert!(cnt > 0, "cnt = {}", cnt);
 }
+
+#[test]
+fn read_group_test() {
+    let read_format =
+        (perf_event_read_format_PERF_FORMAT_GROUP | perf_event_read_format_PERF_FORMAT_ID) as u64;
+    let attr = |config| {
+        let mut event = perf_event_attr {
+            type_: perf_type_id_PERF_TYPE_SOFTWARE,
+            size: std::mem::size_of::<perf_event_attr>() as u32,
+            config: config as u64,
+            read_format,
+            ..Default::default()
+        };
+        event.set_disabled(1);
+        event.set_exclude_kernel(1);
+        event.set_exclude_hv(1);
+        event
+    };
+    let leader = FileDesc::new(
+        &mut attr(perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK),
+        None,
+        -1,
+        -1,
+        OpenFlags::default(),
+    )
+    .unwrap();
+    let member = FileDesc::new(
+        &mut attr(perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK),
+        None,
+        -1,
+        leader.as_raw_fd(),
+        OpenFlags::default(),
+    )
+    .unwrap();
+    leader.reset_group().unwrap();
+    leader.enable_group().unwrap();
+    leader.disable_group().unwrap();
+    let values = leader.read_group(2).unwrap();
+    assert_eq!(values.len(), 2);
+    assert!(values[0].0.enabled >= values[0].0.running);
+    assert_eq!(values[0].1, leader.id().unwrap() as u64);
+    assert_eq!(values[1].1, member.id().unwrap() as u64);
+}
+
+#[test]
+fn counter_value_scaled_test() {
+    let full = CounterValue {
+        raw: 100,
+        enabled: 10,
+        running: 10,
+    };
+    assert_eq!(full.scaled(), 100);
+    assert!(!full.is_multiplexed());
+    let half = CounterValue {
+        raw: 100,
+        enabled: 10,
+        running: 5,
+    };
+    assert_eq!(half.scaled(), 200);
+    assert!(half.is_multiplexed());
+    assert!((half.running_percent() - 50.0).abs() < f64::EPSILON);
+    assert_eq!(half.delta(&full).raw, 0);
+}
+
+#[test]
+fn open_error_test() {
+    let mut event = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: 0xffff,
+        ..Default::default()
+    };
+    let err = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap_err();
+    assert!(err.is_not_supported(), "{}", err);
+    event.config = perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64;
+    let err = FileDesc::new(&mut event, Some(i32::MAX), -1, -1, OpenFlags::default()).unwrap_err();
+    assert!(matches!(err, OpenError::NoSuchTask(_)), "{}", err);
+}
+
+#[test]
+fn open_flags_test() {
+    let mut event = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap();
+    let fd_flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    assert_eq!(fd_flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::NONE).unwrap();
+    let fd_flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    assert_eq!(fd_flags & libc::FD_CLOEXEC, 0);
+    let raw = fd.into_raw_fd();
+    assert_ne!(unsafe { libc::fcntl(raw, libc::F_GETFD) }, -1);
+    unsafe { libc::close(raw) };
+    assert!(OpenFlags::default().contains(OpenFlags::FD_CLOEXEC));
+    assert_eq!((OpenFlags::FD_OUTPUT | OpenFlags::PID_CGROUP).bits(), 6);
+}
+
+/// A software event on this task, with `sample_period`
+/// set so that it writes records to a ring buffer.
+#[cfg(test)]
+fn sampling_attr() -> perf_event_attr {
+    let mut event = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        __bindgen_anon_1: perf_event_attr__bindgen_ty_1 {
+            sample_period: 100_000,
+        },
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    event.set_exclude_kernel(1);
+    event.set_exclude_hv(1);
+    event
+}
+
+/// Map a one page ring buffer for `fd`, to be unmapped
+/// with `munmap(ptr, len)`.
+#[cfg(test)]
+fn map_ring_buffer(fd: &FileDesc) -> (*mut libc::c_void, usize) {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let len = page * 2;
+    let buf = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(buf, libc::MAP_FAILED);
+    (buf, len)
+}
+
+#[test]
+fn set_output_test() {
+    let leader = FileDesc::new(&mut sampling_attr(), None, -1, -1, OpenFlags::default()).unwrap();
+    let member = FileDesc::new(&mut sampling_attr(), None, -1, -1, OpenFlags::default()).unwrap();
+    // Output can only be sent to an event with a mapped buffer.
+    assert!(member.set_output(&leader).is_err());
+    let (buf, len) = map_ring_buffer(&leader);
+    member.set_output(&leader).unwrap();
+    member.ignore_output().unwrap();
+    // An event cannot take its own output.
+    assert!(leader.set_output(&leader).is_err());
+    unsafe { libc::munmap(buf, len) };
+}
+
+#[test]
+fn pause_output_test() {
+    let fd = FileDesc::new(&mut sampling_attr(), None, -1, -1, OpenFlags::default()).unwrap();
+    // Without a mapped ring buffer there is nothing to pause.
+    assert!(fd.pause_output().is_err());
+    let (buf, len) = map_ring_buffer(&fd);
+    fd.pause_output().unwrap();
+    fd.resume_output().unwrap();
+    unsafe { libc::munmap(buf, len) };
+}
+
+#[test]
+fn modify_attributes_test() {
+    // Software events cannot be modified in place.
+    let mut event = sampling_attr();
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap();
+    assert!(fd.modify_attributes(&event).is_err());
+
+    static WATCHED: [std::sync::atomic::AtomicU64; 2] = [
+        std::sync::atomic::AtomicU64::new(0),
+        std::sync::atomic::AtomicU64::new(0),
+    ];
+    let breakpoint = |index: usize| {
+        let mut event = perf_event_attr {
+            type_: perf_type_id_PERF_TYPE_BREAKPOINT,
+            size: std::mem::size_of::<perf_event_attr>() as u32,
+            bp_type: HW_BREAKPOINT_W,
+            __bindgen_anon_3: perf_event_attr__bindgen_ty_3 {
+                bp_addr: &WATCHED[index] as *const _ as u64,
+            },
+            __bindgen_anon_4: perf_event_attr__bindgen_ty_4 {
+                bp_len: HW_BREAKPOINT_LEN_8 as u64,
+            },
+            ..Default::default()
+        };
+        event.set_disabled(1);
+        event.set_exclude_kernel(1);
+        event.set_exclude_hv(1);
+        event
+    };
+    let mut event = breakpoint(0);
+    // Not every machine exposes debug registers to perf.
+    let fd = match FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()) {
+        Ok(fd) => fd,
+        Err(_) => return,
+    };
+    fd.enable().unwrap();
+    WATCHED[0].store(1, std::sync::atomic::Ordering::SeqCst);
+    WATCHED[1].store(1, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(fd.read().unwrap().raw, 1);
+    // The new attributes also decide whether it stays enabled.
+    let mut moved = breakpoint(1);
+    moved.set_disabled(0);
+    fd.modify_attributes(&moved).unwrap();
+    WATCHED[0].store(2, std::sync::atomic::Ordering::SeqCst);
+    WATCHED[1].store(2, std::sync::atomic::Ordering::SeqCst);
+    WATCHED[1].store(3, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(fd.read().unwrap().raw, 3);
+}
@@ -6,9 +6,19 @@
 // Disable cargo build warnings created due to using bindgen.
 #![allow(dead_code)]
 
+pub mod breakpoint;
+pub mod catalog;
 mod fd;
+pub mod modifiers;
 pub mod open;
+pub mod pmu;
+pub mod probe;
+pub mod rdpmc;
+pub mod records;
+pub mod regs;
+pub mod ring;
 mod sys;
+pub mod tracepoint;
 mod utils;
 
 pub fn perf_event_hello() {
@@ -32,12 +42,12 @@ fn wrapper_test() {
     event.set_exclude_kernel(1);
     event.set_exclude_hv(1);
     // Panic on failure.
-    let fd = fd::FileDesc::new(event, Some(0), -1, -1);
+    let fd = fd::FileDesc::new(event, Some(0), -1, -1, fd::OpenFlags::default()).unwrap();
     // Make sure ioctls are working.
     fd.reset().unwrap();
     fd.disable().unwrap();
     fd.enable().unwrap();
-    let cnt: isize = fd.read().unwrap();
+    let cnt = fd.read().unwrap().raw;
     fd.id().unwrap();
     // change overflow sampling period
     fd.overflow_period(2).unwrap();
@@ -0,0 +1,157 @@
+//! Event modifiers.
+//! A modifier suffix such as `cycles:u` or
+//! `cpu/event=0x3c/k` restricts where an event
+//! is counted, or asks for precise sampling,
+//! in the same way as `perf`.
+
+use crate::bindings::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// The modifiers given on one event.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    /// `u`: count in user space.
+    pub user: bool,
+    /// `k`: count in the kernel.
+    pub kernel: bool,
+    /// `h`: count in the hypervisor.
+    pub hv: bool,
+    /// `G`: count only in guests.
+    pub guest: bool,
+    /// `H`: count only on the host.
+    pub host: bool,
+    /// `p`, `pp` or `ppp`: the level of skid requested.
+    pub precise: u8,
+}
+
+impl FromStr for Modifiers {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(());
+        }
+        let mut modifiers = Modifiers::default();
+        for c in s.chars() {
+            let flag = match c {
+                'u' => &mut modifiers.user,
+                'k' => &mut modifiers.kernel,
+                'h' => &mut modifiers.hv,
+                'G' => &mut modifiers.guest,
+                'H' => &mut modifiers.host,
+                'p' if modifiers.precise < 3 => {
+                    modifiers.precise += 1;
+                    continue;
+                }
+                _ => return Err(()),
+            };
+            if *flag {
+                return Err(());
+            }
+            *flag = true;
+        }
+        Ok(modifiers)
+    }
+}
+
+/// Print modifiers in a fixed order, e.g. `ukpp`.
+impl fmt::Display for Modifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.user, "u"),
+            (self.kernel, "k"),
+            (self.hv, "h"),
+            (self.guest, "G"),
+            (self.host, "H"),
+        ];
+        for (set, c) in flags.iter() {
+            if *set {
+                f.write_str(c)?;
+            }
+        }
+        f.write_str(&"p".repeat(self.precise as usize))
+    }
+}
+
+impl Modifiers {
+    /// Apply the modifiers to `attr`. When any of `u`, `k` or `h`
+    /// is given, every privilege level not given is excluded;
+    /// otherwise the event's default exclusions are kept.
+    pub fn apply(&self, attr: &mut perf_event_attr) {
+        if self.user || self.kernel || self.hv {
+            attr.set_exclude_user(!self.user as u64);
+            attr.set_exclude_kernel(!self.kernel as u64);
+            attr.set_exclude_hv(!self.hv as u64);
+        }
+        if self.guest {
+            attr.set_exclude_host(1);
+        }
+        if self.host {
+            attr.set_exclude_guest(1);
+        }
+        attr.set_precise_ip(self.precise as u64);
+    }
+}
+
+/// Split the modifiers off an event spec: after the last `:`,
+/// or after the closing `/` of a PMU event. Returns `None`
+/// when the spec has no modifiers.
+pub fn split(s: &str) -> Option<(&str, Modifiers)> {
+    let (base, suffix) = if s.contains('/') && !s.ends_with('/') {
+        let at = s.rfind('/')? + 1;
+        (&s[..at], s[at..].trim_start_matches(':'))
+    } else {
+        let at = s.rfind(':')?;
+        (&s[..at], &s[at + 1..])
+    };
+    let modifiers = suffix.parse::<Modifiers>().ok()?;
+    Some((base, modifiers))
+}
+
+#[cfg(test)]
+#[test]
+fn modifiers_parse_test() {
+    let m: Modifiers = "ukppp".parse().unwrap();
+    assert!(m.user && m.kernel && !m.hv);
+    assert_eq!(m.precise, 3);
+    assert_eq!(m.to_string(), "ukppp");
+    assert_eq!("Hku".parse::<Modifiers>().unwrap().to_string(), "ukH");
+    assert!("pppp".parse::<Modifiers>().is_err());
+    assert!("uu".parse::<Modifiers>().is_err());
+    assert!("x".parse::<Modifiers>().is_err());
+    assert!("".parse::<Modifiers>().is_err());
+}
+
+#[test]
+fn split_test() {
+    let (base, m) = split("cycles:u").unwrap();
+    assert_eq!(base, "cycles");
+    assert!(m.user && !m.kernel);
+    let (base, m) = split("cpu/event=0x3c/kp").unwrap();
+    assert_eq!(base, "cpu/event=0x3c/");
+    assert!(m.kernel);
+    assert_eq!(m.precise, 1);
+    let (base, _) = split("r1a8:p").unwrap();
+    assert_eq!(base, "r1a8");
+    assert!(split("cycles").is_none());
+    assert!(split("cpu/event=0x3c/").is_none());
+    assert!(split("sched:sched_switch").is_none());
+}
+
+#[test]
+fn modifiers_apply_test() {
+    let mut attr = perf_event_attr::default();
+    attr.set_exclude_kernel(1);
+    attr.set_exclude_hv(1);
+    "k".parse::<Modifiers>().unwrap().apply(&mut attr);
+    assert_eq!(attr.exclude_user(), 1);
+    assert_eq!(attr.exclude_kernel(), 0);
+    assert_eq!(attr.exclude_hv(), 1);
+
+    let mut attr = perf_event_attr::default();
+    attr.set_exclude_kernel(1);
+    "ppG".parse::<Modifiers>().unwrap().apply(&mut attr);
+    assert_eq!(attr.exclude_kernel(), 1);
+    assert_eq!(attr.exclude_host(), 1);
+    assert_eq!(attr.precise_ip(), 2);
+}
@@ -6,169 +6,147 @@
 //! related file descriptors.
 
 use crate::bindings::*;
+use crate::event::catalog;
 use crate::event::fd;
-use crate::event::utils::*;
+pub use crate::event::fd::CounterValue;
+use crate::event::rdpmc::ControlPage;
+pub use crate::event::utils::{perf_event_max_sample_rate, EventErr, OpenError, SysErr};
 use crate::stat::StatEvent;
+use std::os::unix::io::AsRawFd;
 
 const PERF_EVENT_ATTR_SIZE: u32 = std::mem::size_of::<perf_event_attr>() as u32;
 
+/// `read_format` used by every event, so that counts
+/// can be scaled when the kernel multiplexes counters.
+const COUNT_READ_FORMAT: u64 = (perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED
+    | perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+
+/// `read_format` used by every member of an `EventGroup`,
+/// so that the whole group can be read with one `read()`.
+const GROUP_READ_FORMAT: u64 = COUNT_READ_FORMAT
+    | (perf_event_read_format_PERF_FORMAT_GROUP | perf_event_read_format_PERF_FORMAT_ID) as u64;
+
 ///Event enum contains file descriptor and event type
 //simple starting options. Add more as needed
 pub struct Event {
     pub fd: fd::FileDesc,
     pub event: StatEvent,
+    /// Mapped by `map_control_page` for `read_fast`.
+    control: Option<ControlPage>,
 }
 
-/// Initialize perf attributes. Currently set up to match on intended event.
-/// Returns the initialized perf_event_attr data structure or an error.
+/// Initialize perf attributes for any event in the catalog.
+/// Returns the initialized perf_event_attr data structure or an error,
+/// for events that must be expanded or resolved first.
 pub fn event_open(event: &StatEvent) -> Result<perf_event_attr, EventErr> {
-    // To measure hardware CPU cache events
-    // when `type_` is set to `PERF_TYPE_HW_CACHE`
-    // the value of config must be computed.
-    // See the `perf_event_open()` man page for details.
-    let cache_config = |id, op, rs| (id as u64) | ((op as u64) << 8) | ((rs as u64) << 16);
-    match &event {
-        StatEvent::Cycles => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HARDWARE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config: perf_hw_id_PERF_COUNT_HW_CPU_CYCLES as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::Instructions => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HARDWARE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config: perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::TaskClock => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_SOFTWARE,
-                size: std::mem::size_of::<perf_event_attr>() as u32,
-                config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::ContextSwitches => {
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_SOFTWARE,
-                size: std::mem::size_of::<perf_event_attr>() as u32,
-                config: perf_sw_ids_PERF_COUNT_SW_CONTEXT_SWITCHES as u64,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(0);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheRead => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheWrite => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_WRITE,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_ACCESS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1DCacheReadMiss => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1D,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
-        }
-        StatEvent::L1ICacheReadMiss => {
-            let config: u64 = cache_config(
-                perf_hw_cache_id_PERF_COUNT_HW_CACHE_L1I,
-                perf_hw_cache_op_id_PERF_COUNT_HW_CACHE_OP_READ,
-                perf_hw_cache_op_result_id_PERF_COUNT_HW_CACHE_RESULT_MISS,
-            );
-            let event_open = &mut perf_event_attr {
-                type_: perf_type_id_PERF_TYPE_HW_CACHE,
-                size: PERF_EVENT_ATTR_SIZE,
-                config,
-                ..Default::default()
-            };
-            event_open.set_disabled(1);
-            event_open.set_exclude_kernel(1);
-            event_open.set_exclude_hv(1);
-            Ok(*event_open)
+    match event.base() {
+        StatEvent::Tracepoints(_) => return Err(EventErr::InvalidEvent),
+        StatEvent::Breakpoint(bp) if bp.address().is_none() => {
+            return Err(EventErr::Unresolved(bp.name.clone()))
         }
+        _ => {}
+    }
+    let config = catalog::config(event);
+    let event_open = &mut perf_event_attr {
+        type_: config.type_,
+        size: PERF_EVENT_ATTR_SIZE,
+        read_format: COUNT_READ_FORMAT,
+        config: config.config,
+        __bindgen_anon_3: perf_event_attr__bindgen_ty_3 {
+            config1: config.config1,
+        },
+        __bindgen_anon_4: perf_event_attr__bindgen_ty_4 {
+            config2: config.config2,
+        },
+        ..Default::default()
+    };
+    if let StatEvent::Breakpoint(bp) = event.base() {
+        event_open.bp_type = bp.access;
     }
+    event_open.set_disabled(1);
+    if catalog::counts_in_kernel(event) {
+        event_open.set_exclude_kernel(0);
+    } else {
+        event_open.set_exclude_kernel(1);
+    }
+    event_open.set_exclude_hv(1);
+    if let StatEvent::Modified(_, modifiers) = event {
+        modifiers.apply(event_open);
+    }
+    Ok(*event_open)
 }
 
 impl Event {
     /// Construct a new event.
-    pub fn new(event: StatEvent, pid: Option<i32>) -> Self {
-        let e: &mut perf_event_attr = &mut event_open(&event).unwrap();
-        let fd = fd::FileDesc::new(e, pid, -1, -1);
-        Self { fd, event }
+    pub fn new(event: StatEvent, pid: Option<i32>) -> Result<Self, OpenError> {
+        Self::on_cpu(event, pid, -1)
+    }
+    /// Construct a new event counting only on `cpu`.
+    /// With a `pid` of -1 every task on that CPU is counted.
+    pub fn on_cpu(event: StatEvent, pid: Option<i32>, cpu: i32) -> Result<Self, OpenError> {
+        let e: &mut perf_event_attr = &mut event_open(&event)?;
+        let fd = fd::FileDesc::new(e, pid, cpu, -1, fd::OpenFlags::default())?;
+        Ok(Self {
+            fd,
+            event,
+            control: None,
+        })
+    }
+    /// Open `event` with an `attr` built by the caller, such
+    /// as one set up for sampling from `event_open(&event)`.
+    pub fn with_attr(
+        event: StatEvent,
+        attr: &mut perf_event_attr,
+        pid: Option<i32>,
+        cpu: i32,
+    ) -> Result<Self, OpenError> {
+        let fd = fd::FileDesc::new(attr, pid, cpu, -1, fd::OpenFlags::default())?;
+        Ok(Self {
+            fd,
+            event,
+            control: None,
+        })
     }
     /// Start the counter on an event.
-    pub fn start_counter(&self) -> Result<isize, SysErr> {
+    pub fn start_counter(&self) -> Result<CounterValue, SysErr> {
         match self.fd.enable() {
             Ok(_) => self.fd.read(),
             Err(e) => Err(e),
         }
     }
     ///Stop the counter on an event.
-    pub fn stop_counter(&self) -> Result<isize, SysErr> {
+    pub fn stop_counter(&self) -> Result<CounterValue, SysErr> {
         match self.fd.disable() {
             Ok(_) => self.fd.read(),
             Err(e) => Err(e),
         }
     }
 
+    /// Read the counter on an event without stopping it.
+    pub fn read_counter(&self) -> Result<CounterValue, SysErr> {
+        self.fd.read()
+    }
+
+    /// Map the event's control page, so `read_fast` can
+    /// read the counter without a system call.
+    pub fn map_control_page(&mut self) -> Result<(), SysErr> {
+        if self.control.is_none() {
+            self.control = Some(ControlPage::new(&self.fd)?);
+        }
+        Ok(())
+    }
+
+    /// Read the counter from user space with `rdpmc`, for
+    /// timing small regions of the calling thread. Falls back
+    /// to `read()` when the control page is not mapped or the
+    /// kernel does not allow `rdpmc` for this event.
+    pub fn read_fast(&self) -> Result<CounterValue, SysErr> {
+        match self.control.as_ref().and_then(|page| page.read()) {
+            Some(value) => Ok(value),
+            None => self.fd.read(),
+        }
+    }
+
     /// Reset the counter to 0.
     pub fn reset_counter(&self) -> Result<(), SysErr> {
         match self.fd.reset() {
@@ -178,85 +156,261 @@ impl Event {
     }
 }
 
+/// A set of events scheduled onto the PMU together.
+/// The first event is the group leader; every member
+/// counts over exactly the same window, which keeps
+/// ratios such as instructions/cycles consistent.
+pub struct EventGroup {
+    pub events: Vec<Event>,
+    ids: Vec<usize>,
+}
+
+impl EventGroup {
+    /// Open `events` as one group, with the first as leader,
+    /// on `cpu` (or any CPU when -1). Fails if the kernel cannot
+    /// schedule the group, for example when it needs more
+    /// counters than the PMU has.
+    pub fn new(events: &[StatEvent], pid: Option<i32>, cpu: i32) -> Result<Self, OpenError> {
+        let mut members: Vec<Event> = Vec::new();
+        let mut ids = Vec::new();
+        for event in events {
+            let e: &mut perf_event_attr = &mut event_open(event)?;
+            e.read_format = GROUP_READ_FORMAT;
+            let group_fd = match members.first() {
+                Some(leader) => leader.fd.as_raw_fd(),
+                None => -1,
+            };
+            let fd = fd::FileDesc::new(e, pid, cpu, group_fd, fd::OpenFlags::default())?;
+            ids.push(fd.id().map_err(|_| OpenError::NoGroupId)?);
+            members.push(Event {
+                fd,
+                event: event.clone(),
+                control: None,
+            });
+        }
+        if members.is_empty() {
+            return Err(OpenError::EmptyGroup);
+        }
+        Ok(Self {
+            events: members,
+            ids,
+        })
+    }
+
+    /// The event whose file descriptor controls the group.
+    fn leader(&self) -> &Event {
+        &self.events[0]
+    }
+
+    /// Start every counter in the group at once.
+    /// Returns the count of each event, in group order.
+    pub fn start_counters(&self) -> Result<Vec<CounterValue>, SysErr> {
+        self.leader().fd.enable_group()?;
+        self.read_counters()
+    }
+
+    /// Stop every counter in the group at once.
+    /// Returns the count of each event, in group order.
+    pub fn stop_counters(&self) -> Result<Vec<CounterValue>, SysErr> {
+        self.leader().fd.disable_group()?;
+        self.read_counters()
+    }
+
+    /// Reset every counter in the group to 0.
+    pub fn reset_counters(&self) -> Result<(), SysErr> {
+        self.leader().fd.reset_group()
+    }
+
+    /// Read the whole group in one `read()`, matching
+    /// each value to its event by kernel-assigned ID.
+    pub fn read_counters(&self) -> Result<Vec<CounterValue>, SysErr> {
+        let values = self.leader().fd.read_group(self.ids.len())?;
+        self.ids
+            .iter()
+            .map(|id| {
+                values
+                    .iter()
+                    .find(|(_, v_id)| *v_id == *id as u64)
+                    .map(|(value, _)| *value)
+                    .ok_or(SysErr::ReadFail)
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 #[test]
 fn cycles_open_test() {
-    let event = Event::new(StatEvent::Cycles, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::Cycles, None).unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn inst_open_test() {
-    let event = Event::new(StatEvent::Instructions, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::Instructions, None).unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn taskclock_open_test() {
-    let event = Event::new(StatEvent::TaskClock, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(StatEvent::TaskClock, None).unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 fn l1_data_cache_read_open_test() {
-    let event = Event::new(StatEvent::L1DCacheRead, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(
+        StatEvent::HwCache(
+            catalog::CacheId::L1D,
+            catalog::CacheOp::Read,
+            catalog::CacheResult::Access,
+        ),
+        None,
+    )
+    .unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn cs_open_test() {
-    let event = Event::new(StatEvent::ContextSwitches, None);
-    let cnt: isize = event.start_counter().unwrap();
-    assert_ne!(cnt, -1);
+    let event = Event::new(StatEvent::ContextSwitches, None).unwrap();
+    let cnt = event.start_counter().unwrap();
     let cnt_2 = event.stop_counter().unwrap();
-    assert_ne!(cnt_2, -1);
+    assert!(cnt_2.raw >= cnt.raw);
 }
 fn l1_data_cache_write_open_test() {
-    let event = Event::new(StatEvent::L1DCacheWrite, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(
+        StatEvent::HwCache(
+            catalog::CacheId::L1D,
+            catalog::CacheOp::Write,
+            catalog::CacheResult::Access,
+        ),
+        None,
+    )
+    .unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn l1_data_cache_read_miss_open_test() {
-    let event = Event::new(StatEvent::L1DCacheReadMiss, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(
+        StatEvent::HwCache(
+            catalog::CacheId::L1D,
+            catalog::CacheOp::Read,
+            catalog::CacheResult::Miss,
+        ),
+        None,
+    )
+    .unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
 
 #[test]
 fn l1_inst_cache_read_miss_open_test() {
-    let event = Event::new(StatEvent::L1ICacheReadMiss, None);
-    let cnt: isize = event.start_counter().unwrap();
+    let event = Event::new(
+        StatEvent::HwCache(
+            catalog::CacheId::L1I,
+            catalog::CacheOp::Read,
+            catalog::CacheResult::Miss,
+        ),
+        None,
+    )
+    .unwrap();
+    let cnt = event.start_counter().unwrap().raw;
     assert_ne!(cnt, 0);
-    assert_ne!(cnt, -1);
-    let cnt_2 = event.stop_counter().unwrap();
+    let cnt_2 = event.stop_counter().unwrap().raw;
     assert_ne!(cnt, cnt_2);
     assert!(cnt < cnt_2);
 }
+
+#[test]
+fn group_open_test() {
+    let group = EventGroup::new(
+        &[StatEvent::TaskClock, StatEvent::ContextSwitches],
+        None,
+        -1,
+    )
+    .unwrap();
+    group.reset_counters().unwrap();
+    let start = group.start_counters().unwrap();
+    assert_eq!(start.len(), 2);
+    let mut x: u64 = 0;
+    for i in 0..100_000 {
+        x = x.wrapping_add(i);
+    }
+    assert_ne!(x, 0);
+    let stop = group.stop_counters().unwrap();
+    assert!(start[0].raw < stop[0].raw);
+    assert!(stop[1].raw >= start[1].raw);
+    assert_eq!(stop[0].enabled, stop[1].enabled);
+}
+
+#[test]
+fn read_fast_test() {
+    // Software events always fall back to `read()`.
+    let mut event = Event::new(StatEvent::TaskClock, None).unwrap();
+    let start = event.start_counter().unwrap();
+    assert!(event.read_fast().unwrap().raw > start.raw);
+    event.map_control_page().unwrap();
+    let first = event.read_fast().unwrap();
+    let second = event.read_fast().unwrap();
+    assert!(first.raw < second.raw);
+    assert!(second.enabled >= second.running);
+
+    // Hardware counters are read with `rdpmc` where allowed,
+    // and agree with `read()`.
+    let mut event = match Event::new(StatEvent::Instructions, None) {
+        Ok(event) => event,
+        Err(_) => return,
+    };
+    event.map_control_page().unwrap();
+    event.start_counter().unwrap();
+    let fast = event.read_fast().unwrap();
+    let slow = event.read_counter().unwrap();
+    let faster = event.read_fast().unwrap();
+    assert!(fast.raw <= slow.raw && slow.raw <= faster.raw);
+    assert!(fast.enabled <= slow.enabled && slow.enabled <= faster.enabled);
+}
+
+#[test]
+fn modifier_attr_test() {
+    let attr = event_open(&"cycles:kpp".parse().unwrap()).unwrap();
+    assert_eq!(attr.exclude_user(), 1);
+    assert_eq!(attr.exclude_kernel(), 0);
+    assert_eq!(attr.precise_ip(), 2);
+    let attr = event_open(&"context-switches:u".parse().unwrap()).unwrap();
+    assert_eq!(attr.exclude_user(), 0);
+    assert_eq!(attr.exclude_kernel(), 1);
+}
+
+#[test]
+fn unresolved_open_test() {
+    let event: StatEvent = "mem:RUPERF_NO_SUCH_SYMBOL:w".parse().unwrap();
+    assert!(matches!(event_open(&event), Err(EventErr::Unresolved(_))));
+    assert!(matches!(
+        Event::new(event, None),
+        Err(OpenError::Event(EventErr::Unresolved(_)))
+    ));
+}
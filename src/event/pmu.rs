@@ -0,0 +1,218 @@
+//! Raw and PMU-specific events.
+//! Model-specific events are given either as a raw
+//! `rNNNN` config for the CPU's PMU, or in the
+//! `pmu/term=value,.../` syntax, where the PMU's type
+//! and the bit layout of each term are read from
+//! `/sys/bus/event_source/devices/<pmu>`.
+
+use crate::event::catalog::EventConfig;
+use crate::utils::ParseError;
+use std::fs;
+use std::path::Path;
+
+const SYSFS_PMU: &str = "/sys/bus/event_source/devices";
+
+/// An event on a specific PMU, as written on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PmuEvent {
+    pub name: String,
+    pub config: EventConfig,
+}
+
+/// Where a term's value goes: which config word,
+/// and which bits of it, lowest first.
+#[derive(Debug, Clone, PartialEq)]
+struct FormatField {
+    word: usize,
+    bits: Vec<(u32, u32)>,
+}
+
+impl FormatField {
+    /// Parse a sysfs format such as `config:0-7,21` or `config1:0-63`.
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.trim().splitn(2, ':');
+        let word = match parts.next()? {
+            "config" => 0,
+            "config1" => 1,
+            "config2" => 2,
+            _ => return None,
+        };
+        let mut bits = Vec::new();
+        for range in parts.next()?.split(',') {
+            let mut bounds = range.splitn(2, '-').map(|x| x.trim().parse::<u32>());
+            let lo = bounds.next()?.ok()?;
+            let hi = match bounds.next() {
+                Some(x) => x.ok()?,
+                None => lo,
+            };
+            if hi < lo || hi > 63 {
+                return None;
+            }
+            bits.push((lo, hi));
+        }
+        Some(Self { word, bits })
+    }
+
+    /// Deposit `value` into the bits of this field. Fails if
+    /// `value` does not fit in the field.
+    fn apply(&self, value: u64, words: &mut [u64; 3]) -> bool {
+        let mut rest = value;
+        for (lo, hi) in &self.bits {
+            let width = hi - lo + 1;
+            let mask = if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            };
+            words[self.word] |= (rest & mask) << lo;
+            rest = if width == 64 { 0 } else { rest >> width };
+        }
+        rest == 0
+    }
+}
+
+/// Parse a raw event `rNNNN`, where NNNN is the config in hex.
+pub fn parse_raw(s: &str) -> Option<u64> {
+    let hex = s.strip_prefix('r')?;
+    if hex.is_empty() || hex.len() > 16 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Parse `pmu/term=value,.../` against the PMUs in sysfs.
+pub fn parse(s: &str) -> Result<PmuEvent, ParseError> {
+    parse_in(Path::new(SYSFS_PMU), s)
+}
+
+/// Parse `pmu/term=value,.../` against the PMUs under `root`.
+/// Besides the PMU's own format terms, `config`, `config1`
+/// and `config2` set a whole config word, and a term naming
+/// one of the PMU's `events/` expands to that event's terms.
+fn parse_in(root: &Path, s: &str) -> Result<PmuEvent, ParseError> {
+    let invalid = || ParseError::InvalidTerm(s.to_string());
+    let body = s.strip_suffix('/').ok_or_else(invalid)?;
+    let mut parts = body.splitn(2, '/');
+    let pmu = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?;
+    let terms = parts.next().ok_or_else(invalid)?;
+    let dir = root.join(pmu);
+    let type_ = fs::read_to_string(dir.join("type"))
+        .ok()
+        .and_then(|x| x.trim().parse::<u32>().ok())
+        .ok_or_else(|| ParseError::UnknownPmu(pmu.to_string()))?;
+
+    let mut words = [0u64; 3];
+    let mut pending: Vec<String> = terms
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect();
+    pending.reverse();
+    let mut expanded = 0;
+    while let Some(term) = pending.pop() {
+        let mut kv = term.splitn(2, '=');
+        let key = kv.next().unwrap().trim();
+        let value = match kv.next() {
+            Some(v) => parse_value(v.trim()).ok_or_else(invalid)?,
+            None => {
+                // A bare term is either a named event of the PMU,
+                // or a flag in the format that is set to 1.
+                if let Ok(alias) = fs::read_to_string(dir.join("events").join(key)) {
+                    expanded += 1;
+                    if expanded > 16 {
+                        return Err(invalid());
+                    }
+                    pending.extend(alias.trim().split(',').rev().map(|x| x.to_string()));
+                    continue;
+                }
+                1
+            }
+        };
+        match key {
+            "config" => words[0] = value,
+            "config1" => words[1] = value,
+            "config2" => words[2] = value,
+            _ => {
+                let format = fs::read_to_string(dir.join("format").join(key))
+                    .map_err(|_| ParseError::UnknownTerm(pmu.to_string(), key.to_string()))?;
+                let field = FormatField::parse(&format).ok_or_else(invalid)?;
+                if !field.apply(value, &mut words) {
+                    return Err(invalid());
+                }
+            }
+        }
+    }
+    Ok(PmuEvent {
+        name: s.to_string(),
+        config: EventConfig {
+            type_,
+            config: words[0],
+            config1: words[1],
+            config2: words[2],
+        },
+    })
+}
+
+/// Parse a term value, in hex with a `0x` prefix or in decimal.
+fn parse_value(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn format_field_test() {
+    let field = FormatField::parse("config:0-7,21").unwrap();
+    let mut words = [0u64; 3];
+    assert!(field.apply(0x1ff, &mut words));
+    assert_eq!(words[0], 0xff | (1 << 21));
+    assert!(!field.apply(0x200, &mut [0u64; 3]));
+    let field = FormatField::parse("config1:0-63\n").unwrap();
+    let mut words = [0u64; 3];
+    assert!(field.apply(u64::MAX, &mut words));
+    assert_eq!(words, [0, u64::MAX, 0]);
+    assert!(FormatField::parse("config:7-0").is_none());
+    assert!(FormatField::parse("period:0-7").is_none());
+}
+
+#[test]
+fn parse_raw_test() {
+    assert_eq!(parse_raw("r1a8"), Some(0x1a8));
+    assert_eq!(parse_raw("rFF"), Some(0xff));
+    assert!(parse_raw("ref-cycles").is_none());
+    assert!(parse_raw("r").is_none());
+}
+
+#[test]
+fn parse_pmu_test() {
+    let root = std::env::temp_dir().join(format!("ruperf-pmu-test-{}", std::process::id()));
+    let cpu = root.join("cpu");
+    fs::create_dir_all(cpu.join("format")).unwrap();
+    fs::create_dir_all(cpu.join("events")).unwrap();
+    fs::write(cpu.join("type"), "4\n").unwrap();
+    fs::write(cpu.join("format/event"), "config:0-7\n").unwrap();
+    fs::write(cpu.join("format/umask"), "config:8-15\n").unwrap();
+    fs::write(cpu.join("format/edge"), "config:18\n").unwrap();
+    fs::write(cpu.join("events/cycles-t"), "event=0x3c,umask=0x1\n").unwrap();
+
+    let event = parse_in(&root, "cpu/event=0x3c,umask=0x2,edge/").unwrap();
+    assert_eq!(event.name, "cpu/event=0x3c,umask=0x2,edge/");
+    assert_eq!(event.config.type_, 4);
+    assert_eq!(event.config.config, 0x3c | (0x2 << 8) | (1 << 18));
+    let event = parse_in(&root, "cpu/cycles-t,config1=10/").unwrap();
+    assert_eq!(event.config.config, 0x13c);
+    assert_eq!(event.config.config1, 10);
+    assert!(matches!(
+        parse_in(&root, "cpu/bogus=1/"),
+        Err(ParseError::UnknownTerm(_, _))
+    ));
+    assert!(matches!(
+        parse_in(&root, "nope/event=1/"),
+        Err(ParseError::UnknownPmu(_))
+    ));
+    assert!(parse_in(&root, "cpu/event=0x100/").is_err());
+    assert!(parse_in(&root, "cpu/event=1").is_err());
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,174 @@
+//! Dynamic probe events.
+//! Calls into a kernel function or a function of
+//! any binary are counted by creating a probe
+//! through the `kprobe` or `uprobe` dynamic PMU:
+//! <ul>
+//! <li><em> kprobe:FUNC[+OFFSET] </em> and <em> kretprobe:FUNC </em></li>
+//! <li><em> uprobe:PATH:SYMBOL </em> and <em> uretprobe:PATH:SYMBOL </em></li>
+//! <li><em> probe_EXE:SYMBOL </em>, a uprobe on EXE found in `$PATH`</li>
+//! </ul>
+//! Symbols are resolved to file offsets through the
+//! binary's ELF symbol table.
+
+use crate::event::catalog::EventConfig;
+use crate::event::pmu;
+use crate::utils::ParseError;
+use object::{Object, ObjectSegment, ObjectSymbol};
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A probe on a kernel function or a user-space binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeEvent {
+    /// The spec as written on the command line.
+    pub name: String,
+    /// The probe PMU's type and the `retprobe` flag.
+    pub config: EventConfig,
+    /// The kernel function, or the path of the binary.
+    pub target: CString,
+    /// Offset into the function, or into the binary's file.
+    pub offset: u64,
+    /// Whether this is a kernel probe.
+    pub kernel: bool,
+}
+
+impl ProbeEvent {
+    /// The fields of `perf_event_attr` for this probe. `config1`
+    /// points at `target`, so the result must not outlive `self`.
+    pub fn attr_config(&self) -> EventConfig {
+        EventConfig {
+            config1: self.target.as_ptr() as u64,
+            config2: self.offset,
+            ..self.config
+        }
+    }
+}
+
+/// Whether `s` is a probe spec.
+pub fn is_probe(s: &str) -> bool {
+    ["kprobe:", "kretprobe:", "uprobe:", "uretprobe:", "probe_"]
+        .iter()
+        .any(|prefix| s.starts_with(prefix))
+}
+
+/// Parse a probe spec.
+pub fn parse(s: &str) -> Result<ProbeEvent, ParseError> {
+    let invalid = || ParseError::InvalidProbe(s.to_string());
+    let (kind, rest) = match s.find(':') {
+        Some(at) => (&s[..at], &s[at + 1..]),
+        None => return Err(invalid()),
+    };
+    let (pmu_name, retprobe) = match kind {
+        "kprobe" => ("kprobe", false),
+        "kretprobe" => ("kprobe", true),
+        "uprobe" => ("uprobe", false),
+        "uretprobe" => ("uprobe", true),
+        _ if kind.starts_with("probe_") => ("uprobe", false),
+        _ => return Err(invalid()),
+    };
+    let pmu_spec = format!("{}/{}/", pmu_name, if retprobe { "retprobe" } else { "" });
+    let config = pmu::parse(&pmu_spec)?.config;
+
+    let (target, offset) = if pmu_name == "kprobe" {
+        let mut parts = rest.splitn(2, '+');
+        let func = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?;
+        let offset = match parts.next() {
+            Some(x) => parse_number(x).ok_or_else(invalid)?,
+            None => 0,
+        };
+        (func.to_string(), offset)
+    } else {
+        let (path, symbol) = match kind.strip_prefix("probe_") {
+            Some(exe) => (find_in_path(exe).ok_or_else(invalid)?, rest),
+            None => {
+                let at = rest.rfind(':').ok_or_else(invalid)?;
+                (PathBuf::from(&rest[..at]), &rest[at + 1..])
+            }
+        };
+        let offset = symbol_offset(&path, symbol)?;
+        (path.to_string_lossy().into_owned(), offset)
+    };
+    Ok(ProbeEvent {
+        name: s.to_string(),
+        config,
+        target: CString::new(target).map_err(|_| invalid())?,
+        offset,
+        kernel: pmu_name == "kprobe",
+    })
+}
+
+/// Parse a number in hex with a `0x` prefix or in decimal.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+/// Find an executable by name, in the current directory or `$PATH`.
+fn find_in_path(exe: &str) -> Option<PathBuf> {
+    let local = Path::new(".").join(exe);
+    if local.is_file() {
+        return fs::canonicalize(local).ok();
+    }
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(exe))
+            .find(|path| path.is_file())
+    })
+}
+
+/// The virtual address of the definition of `symbol` in `file`.
+pub fn symbol_address(file: &object::File, symbol: &str) -> Option<u64> {
+    file.symbols()
+        .chain(file.dynamic_symbols())
+        .find(|sym| sym.is_definition() && sym.name() == Ok(symbol))
+        .map(|sym| sym.address())
+}
+
+/// The file offset of `symbol` in the ELF binary at `path`.
+/// `symbol` may also be a virtual address in hex.
+pub fn symbol_offset(path: &Path, symbol: &str) -> Result<u64, ParseError> {
+    let unreadable = || ParseError::InvalidBinary(path.display().to_string());
+    let data = fs::read(path).map_err(|_| unreadable())?;
+    let file = object::File::parse(&*data).map_err(|_| unreadable())?;
+    let address = match parse_number(symbol).filter(|_| symbol.starts_with("0x")) {
+        Some(address) => address,
+        None => symbol_address(&file, symbol).ok_or_else(|| {
+            ParseError::UnknownSymbol(path.display().to_string(), symbol.to_string())
+        })?,
+    };
+    // Uprobes are placed by file offset, so find the
+    // loaded segment holding the address.
+    file.segments()
+        .find(|seg| address >= seg.address() && address < seg.address() + seg.size())
+        .map(|seg| address - seg.address() + seg.file_range().0)
+        .ok_or_else(|| ParseError::UnknownSymbol(path.display().to_string(), symbol.to_string()))
+}
+
+#[cfg(test)]
+#[test]
+fn symbol_offset_test() {
+    let exe = std::env::current_exe().unwrap();
+    let offset = symbol_offset(&exe, "main").unwrap();
+    assert!(offset > 0);
+    assert!(offset < fs::metadata(&exe).unwrap().len());
+    assert!(matches!(
+        symbol_offset(&exe, "no_such_symbol_in_ruperf"),
+        Err(ParseError::UnknownSymbol(_, _))
+    ));
+    assert!(matches!(
+        symbol_offset(Path::new("/proc/self/status"), "main"),
+        Err(ParseError::InvalidBinary(_))
+    ));
+}
+
+#[test]
+fn is_probe_test() {
+    assert!(is_probe("kprobe:do_sys_open"));
+    assert!(is_probe("uprobe:/bin/ls:main"));
+    assert!(is_probe("probe_ls:main"));
+    assert!(!is_probe("sched:sched_switch"));
+    assert!(parse("kprobe").is_err());
+}
@@ -0,0 +1,194 @@
+//! Counter reads from user space.
+//! Mapping the first page of an event gives its
+//! `perf_event_mmap_page`, which the kernel keeps up to
+//! date while the event is scheduled. On x86_64 the
+//! counter itself can then be read with `rdpmc`, and the
+//! enabled and running times extended with the TSC,
+//! without a `read()` system call.
+//!
+//! The kernel updates the page under a sequence lock:
+//! a read is retried until `lock` is the same before
+//! and after it. See the `perf_event_open` man page.
+//! Only an event counting the calling thread can be
+//! read this way, since `rdpmc` reads the counter of
+//! whatever CPU it runs on.
+
+use crate::bindings::*;
+use crate::event::fd::{CounterValue, FileDesc};
+use crate::event::ring::page_size;
+use crate::event::utils::*;
+use std::os::unix::io::AsRawFd;
+use std::ptr::read_volatile;
+use std::sync::atomic::{fence, Ordering};
+
+/// The mapped control page of an event.
+#[derive(Debug)]
+pub struct ControlPage {
+    page: *mut perf_event_mmap_page,
+    len: usize,
+}
+
+impl ControlPage {
+    /// Map the control page of `fd`, with no data pages.
+    pub fn new(fd: &FileDesc) -> Result<Self, SysErr> {
+        let len = page_size();
+        let page = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if page == libc::MAP_FAILED {
+            return Err(SysErr::MmapFail);
+        }
+        Ok(Self {
+            page: page as *mut perf_event_mmap_page,
+            len,
+        })
+    }
+
+    /// Whether the kernel lets this event be read with `rdpmc`.
+    pub fn can_rdpmc(&self) -> bool {
+        unsafe {
+            (*self.page)
+                .__bindgen_anon_1
+                .__bindgen_anon_1
+                .cap_user_rdpmc()
+                != 0
+        }
+    }
+
+    /// Read the counter and its times from user space. Returns
+    /// `None` when that is not possible right now: `rdpmc` or
+    /// the TSC may not be usable, or the event may not be on a
+    /// counter, in which case `read()` must be used instead.
+    pub fn read(&self) -> Option<CounterValue> {
+        let pc = self.page;
+        loop {
+            let seq = unsafe { read_volatile(&(*pc).lock) };
+            fence(Ordering::Acquire);
+            let caps = unsafe { read_volatile(&(*pc).__bindgen_anon_1.capabilities) };
+            let caps = perf_event_mmap_page__bindgen_ty_1 { capabilities: caps };
+            let (rdpmc, user_time) = unsafe {
+                (
+                    caps.__bindgen_anon_1.cap_user_rdpmc() != 0,
+                    caps.__bindgen_anon_1.cap_user_time() != 0,
+                )
+            };
+            let index = unsafe { read_volatile(&(*pc).index) };
+            if !rdpmc || !user_time || index == 0 {
+                return None;
+            }
+            let (enabled, running, offset, width, shift, mult, time_offset) = unsafe {
+                (
+                    read_volatile(&(*pc).time_enabled),
+                    read_volatile(&(*pc).time_running),
+                    read_volatile(&(*pc).offset),
+                    read_volatile(&(*pc).pmc_width),
+                    read_volatile(&(*pc).time_shift),
+                    read_volatile(&(*pc).time_mult),
+                    read_volatile(&(*pc).time_offset),
+                )
+            };
+            let (pmc, cycles) = read_pmc(index - 1)?;
+            fence(Ordering::Acquire);
+            if unsafe { read_volatile(&(*pc).lock) } != seq {
+                continue;
+            }
+            // Both times grew by the time since the kernel
+            // last updated the page, while the event ran.
+            let delta = time_delta(cycles, time_offset, mult, shift);
+            return Some(CounterValue {
+                raw: (offset as u64).wrapping_add(sign_extend(pmc, width)),
+                enabled: enabled.wrapping_add(delta),
+                running: running.wrapping_add(delta),
+            });
+        }
+    }
+}
+
+impl Drop for ControlPage {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.page as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Read hardware counter `counter` and the TSC.
+#[cfg(target_arch = "x86_64")]
+fn read_pmc(counter: u32) -> Option<(u64, u64)> {
+    let (low, high): (u32, u32);
+    unsafe {
+        std::arch::asm!(
+            "rdpmc",
+            in("ecx") counter,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack),
+        );
+        let cycles = std::arch::x86_64::_rdtsc();
+        Some(((high as u64) << 32 | low as u64, cycles))
+    }
+}
+
+/// No user-space counter reads on other architectures yet.
+#[cfg(not(target_arch = "x86_64"))]
+fn read_pmc(_counter: u32) -> Option<(u64, u64)> {
+    None
+}
+
+/// Counters are `width` bits wide; sign-extend a raw value
+/// so that adding it to the page's `offset` wraps correctly.
+fn sign_extend(pmc: u64, width: u16) -> u64 {
+    if width == 0 || width >= 64 {
+        return pmc;
+    }
+    let shift = 64 - width as u32;
+    (((pmc << shift) as i64) >> shift) as u64
+}
+
+/// Nanoseconds since the page was last updated, from a
+/// TSC value and the page's conversion parameters.
+fn time_delta(cycles: u64, time_offset: u64, mult: u32, shift: u16) -> u64 {
+    let quot = cycles >> shift;
+    let rem = cycles & ((1_u64 << shift) - 1);
+    time_offset
+        .wrapping_add(quot.wrapping_mul(mult as u64))
+        .wrapping_add((rem * mult as u64) >> shift)
+}
+
+#[cfg(test)]
+#[test]
+fn sign_extend_test() {
+    assert_eq!(sign_extend(0x7fff_ffff_ffff, 48), 0x7fff_ffff_ffff);
+    assert_eq!(sign_extend(0xffff_ffff_ffff, 48), u64::MAX);
+    assert_eq!(sign_extend(5, 0), 5);
+    // Adding a negative counter to the offset subtracts.
+    assert_eq!(100_u64.wrapping_add(sign_extend(0xffff_ffff_fff6, 48)), 90);
+    // 1 cycle per ns with a shift of 10 is a mult of 1024.
+    assert_eq!(time_delta(5000, 0, 1024, 10), 5000);
+    assert_eq!(time_delta(5000, (-1000_i64) as u64, 1024, 10), 4000);
+    assert_eq!(time_delta(3, 0, 512, 10), 1);
+}
+
+#[test]
+fn control_page_test() {
+    use crate::event::fd::OpenFlags;
+    let mut event = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap();
+    let page = ControlPage::new(&fd).unwrap();
+    fd.enable().unwrap();
+    // Software events are never on a hardware counter.
+    assert!(!page.can_rdpmc());
+    assert_eq!(page.read(), None);
+    fd.disable().unwrap();
+}
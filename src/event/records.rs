@@ -0,0 +1,731 @@
+//! Typed records read from a sampled event.
+//! The kernel writes a stream of records, each a
+//! `perf_event_header` followed by a body whose layout
+//! depends on the record type and, for samples, on the
+//! `sample_type` and related fields of `perf_event_attr`.
+//! The same stream is stored in `perf.data` files, so
+//! these are decoded the same way wherever they come from.
+
+use crate::bindings::*;
+
+/// `sample_type` bits newer than the bindings.
+const PERF_SAMPLE_CGROUP: u64 = 1 << 21;
+const PERF_SAMPLE_DATA_PAGE_SIZE: u64 = 1 << 22;
+const PERF_SAMPLE_CODE_PAGE_SIZE: u64 = 1 << 23;
+const PERF_SAMPLE_WEIGHT_STRUCT: u64 = 1 << 24;
+/// `branch_sample_type` bits newer than the bindings.
+const PERF_SAMPLE_BRANCH_HW_INDEX: u64 = 1 << 17;
+const PERF_SAMPLE_BRANCH_COUNTERS: u64 = 1 << 19;
+/// `misc` bit of an MMAP2 record carrying a build ID
+/// in place of the device and inode.
+const PERF_RECORD_MISC_MMAP_BUILD_ID: u16 = 1 << 14;
+
+/// The size of `perf_event_header`.
+pub const HEADER_SIZE: usize = 8;
+
+/// The fields of `perf_event_attr` that decide how
+/// records of an event are laid out.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct SampleFormat {
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub regs_user: u64,
+    pub regs_intr: u64,
+    pub branch_sample_type: u64,
+    /// Whether records other than samples end in a `SampleId`.
+    pub sample_id_all: bool,
+}
+
+impl SampleFormat {
+    /// The layout of records written for `attr`.
+    pub fn new(attr: &perf_event_attr) -> Self {
+        Self {
+            sample_type: attr.sample_type,
+            read_format: attr.read_format,
+            regs_user: attr.sample_regs_user,
+            regs_intr: attr.sample_regs_intr,
+            branch_sample_type: attr.branch_sample_type,
+            sample_id_all: attr.sample_id_all() != 0,
+        }
+    }
+
+    /// Whether `bit` of `sample_type` is set.
+    fn has(&self, bit: u64) -> bool {
+        self.sample_type & bit != 0
+    }
+}
+
+/// The task, time and event a record is from, appended to
+/// every record other than samples when `sample_id_all` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleId {
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub time: Option<u64>,
+    pub id: Option<u64>,
+    pub stream_id: Option<u64>,
+    pub cpu: Option<u32>,
+}
+
+/// One counter of a `PERF_SAMPLE_READ` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadValue {
+    pub value: u64,
+    pub id: Option<u64>,
+}
+
+/// The counters read along with a sample.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadValues {
+    pub time_enabled: Option<u64>,
+    pub time_running: Option<u64>,
+    pub values: Vec<ReadValue>,
+}
+
+/// One taken branch of the last branch record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BranchEntry {
+    pub from: u64,
+    pub to: u64,
+    pub flags: u64,
+}
+
+/// Registers sampled with `PERF_SAMPLE_REGS_USER` or
+/// `PERF_SAMPLE_REGS_INTR`, in the order of the bits set
+/// in the register mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registers {
+    pub abi: u64,
+    pub values: Vec<u64>,
+}
+
+/// A `PERF_RECORD_SAMPLE`. Fields are set when
+/// their bit is set in `sample_type`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub misc: u16,
+    pub id: Option<u64>,
+    pub ip: Option<u64>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub time: Option<u64>,
+    pub addr: Option<u64>,
+    pub stream_id: Option<u64>,
+    pub cpu: Option<u32>,
+    pub period: Option<u64>,
+    pub read: Option<ReadValues>,
+    pub callchain: Vec<u64>,
+    pub raw: Vec<u8>,
+    pub branches: Vec<BranchEntry>,
+    pub regs_user: Option<Registers>,
+    /// A copy of the user stack, from the stack pointer up.
+    pub stack_user: Vec<u8>,
+    pub weight: Option<u64>,
+    pub data_src: Option<u64>,
+    pub transaction: Option<u64>,
+    pub regs_intr: Option<Registers>,
+    pub phys_addr: Option<u64>,
+    pub cgroup: Option<u64>,
+    pub data_page_size: Option<u64>,
+    pub code_page_size: Option<u64>,
+    pub aux: Vec<u8>,
+}
+
+impl Sample {
+    /// Whether the sample was taken in user space.
+    pub fn is_user(&self) -> bool {
+        self.misc as u32 & PERF_RECORD_MISC_CPUMODE_MASK == PERF_RECORD_MISC_USER
+    }
+}
+
+/// A `PERF_RECORD_MMAP`: an executable mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mmap {
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub filename: String,
+    pub sample_id: SampleId,
+}
+
+/// A `PERF_RECORD_MMAP2`: a mapping with the file's
+/// identity and protection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mmap2 {
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub maj: u32,
+    pub min: u32,
+    pub ino: u64,
+    pub ino_generation: u64,
+    /// Sent in place of the device and inode on newer kernels.
+    pub build_id: Option<Vec<u8>>,
+    pub prot: u32,
+    pub flags: u32,
+    pub filename: String,
+    pub sample_id: SampleId,
+}
+
+/// A `PERF_RECORD_COMM`: a task's name changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comm {
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: String,
+    /// Whether the name changed because the task exec'd.
+    pub exec: bool,
+    pub sample_id: SampleId,
+}
+
+/// A `PERF_RECORD_FORK` or `PERF_RECORD_EXIT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    pub pid: u32,
+    pub ppid: u32,
+    pub tid: u32,
+    pub ptid: u32,
+    pub time: u64,
+    pub sample_id: SampleId,
+}
+
+/// A `PERF_RECORD_LOST`: records dropped because
+/// the ring buffer was full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lost {
+    pub id: u64,
+    pub lost: u64,
+    pub sample_id: SampleId,
+}
+
+/// A `PERF_RECORD_THROTTLE` or `PERF_RECORD_UNTHROTTLE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttle {
+    pub time: u64,
+    pub id: u64,
+    pub stream_id: u64,
+    pub sample_id: SampleId,
+}
+
+/// A record decoded from the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Record {
+    Sample(Sample),
+    Mmap(Mmap),
+    Mmap2(Mmap2),
+    Comm(Comm),
+    Fork(Task),
+    Exit(Task),
+    Lost(Lost),
+    Throttle(Throttle),
+    Unthrottle(Throttle),
+    /// A record type that is not decoded, kept by type.
+    Other(u32),
+}
+
+/// Reads native-endian fields from a record body.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut word = [0; 8];
+        word.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_ne_bytes(word))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_ne_bytes(word))
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let mut word = [0; 2];
+        word.copy_from_slice(self.bytes(2)?);
+        Some(u16::from_ne_bytes(word))
+    }
+
+    /// Read `n` words of 8 bytes.
+    fn u64s(&mut self, n: u64) -> Option<Vec<u64>> {
+        // Never trust a count beyond what is left to read.
+        if n > (self.data.len() - self.pos) as u64 / 8 {
+            return None;
+        }
+        (0..n).map(|_| self.u64()).collect()
+    }
+
+    /// A NUL-terminated string, padded to 8 bytes.
+    fn string(&mut self) -> Option<String> {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|b| *b == 0)?;
+        let s = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += (len + 1 + 7) & !7;
+        Some(s)
+    }
+
+    /// A `u32 pid, tid` pair.
+    fn pid_tid(&mut self) -> Option<(u32, u32)> {
+        Some((self.u32()?, self.u32()?))
+    }
+
+    /// Registers for `mask`, absent when the ABI is none.
+    fn registers(&mut self, mask: u64) -> Option<Option<Registers>> {
+        let abi = self.u64()?;
+        if abi == 0 {
+            return Some(None);
+        }
+        let values = self.u64s(mask.count_ones() as u64)?;
+        Some(Some(Registers { abi, values }))
+    }
+}
+
+/// Decode one record, header included. Returns `None`
+/// when the record is shorter than its layout needs.
+#[allow(non_upper_case_globals)]
+pub fn parse(record: &[u8], format: &SampleFormat) -> Option<Record> {
+    let mut r = Reader {
+        data: record,
+        pos: 0,
+    };
+    let type_ = r.u32()?;
+    let misc = r.u16()?;
+    // The size was used to split the records apart.
+    r.u16()?;
+    Some(match type_ {
+        perf_event_type_PERF_RECORD_SAMPLE => Record::Sample(parse_sample(&mut r, misc, format)?),
+        perf_event_type_PERF_RECORD_MMAP => {
+            let (pid, tid) = r.pid_tid()?;
+            Record::Mmap(Mmap {
+                pid,
+                tid,
+                addr: r.u64()?,
+                len: r.u64()?,
+                pgoff: r.u64()?,
+                filename: r.string()?,
+                sample_id: parse_sample_id(&mut r, format)?,
+            })
+        }
+        perf_event_type_PERF_RECORD_MMAP2 => {
+            let (pid, tid) = r.pid_tid()?;
+            let (addr, len, pgoff) = (r.u64()?, r.u64()?, r.u64()?);
+            let (maj, min, ino, ino_generation, build_id) =
+                if misc & PERF_RECORD_MISC_MMAP_BUILD_ID != 0 {
+                    let header = r.bytes(4)?;
+                    let id = r.bytes(20)?;
+                    let size = (header[0] as usize).min(20);
+                    (0, 0, 0, 0, Some(id[..size].to_vec()))
+                } else {
+                    (r.u32()?, r.u32()?, r.u64()?, r.u64()?, None)
+                };
+            Record::Mmap2(Mmap2 {
+                pid,
+                tid,
+                addr,
+                len,
+                pgoff,
+                maj,
+                min,
+                ino,
+                ino_generation,
+                build_id,
+                prot: r.u32()?,
+                flags: r.u32()?,
+                filename: r.string()?,
+                sample_id: parse_sample_id(&mut r, format)?,
+            })
+        }
+        perf_event_type_PERF_RECORD_COMM => {
+            let (pid, tid) = r.pid_tid()?;
+            Record::Comm(Comm {
+                pid,
+                tid,
+                comm: r.string()?,
+                exec: misc as u32 & PERF_RECORD_MISC_COMM_EXEC != 0,
+                sample_id: parse_sample_id(&mut r, format)?,
+            })
+        }
+        perf_event_type_PERF_RECORD_FORK | perf_event_type_PERF_RECORD_EXIT => {
+            let task = Task {
+                pid: r.u32()?,
+                ppid: r.u32()?,
+                tid: r.u32()?,
+                ptid: r.u32()?,
+                time: r.u64()?,
+                sample_id: parse_sample_id(&mut r, format)?,
+            };
+            match type_ {
+                perf_event_type_PERF_RECORD_FORK => Record::Fork(task),
+                _ => Record::Exit(task),
+            }
+        }
+        perf_event_type_PERF_RECORD_LOST => Record::Lost(Lost {
+            id: r.u64()?,
+            lost: r.u64()?,
+            sample_id: parse_sample_id(&mut r, format)?,
+        }),
+        perf_event_type_PERF_RECORD_THROTTLE | perf_event_type_PERF_RECORD_UNTHROTTLE => {
+            let throttle = Throttle {
+                time: r.u64()?,
+                id: r.u64()?,
+                stream_id: r.u64()?,
+                sample_id: parse_sample_id(&mut r, format)?,
+            };
+            match type_ {
+                perf_event_type_PERF_RECORD_THROTTLE => Record::Throttle(throttle),
+                _ => Record::Unthrottle(throttle),
+            }
+        }
+        _ => Record::Other(type_),
+    })
+}
+
+/// Decode the `SampleId` ending a record other than a sample.
+fn parse_sample_id(r: &mut Reader, format: &SampleFormat) -> Option<SampleId> {
+    let mut id = SampleId::default();
+    if !format.sample_id_all {
+        return Some(id);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_TID) {
+        let (pid, tid) = r.pid_tid()?;
+        id.pid = Some(pid);
+        id.tid = Some(tid);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+        id.time = Some(r.u64()?);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_ID) {
+        id.id = Some(r.u64()?);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+        id.stream_id = Some(r.u64()?);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+        id.cpu = Some(r.pid_tid()?.0);
+    }
+    if format.has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+        id.id = Some(r.u64()?);
+    }
+    Some(id)
+}
+
+/// Decode the body of a `PERF_RECORD_SAMPLE`, whose fields
+/// appear in the fixed order of the `sample_type` bits.
+fn parse_sample(r: &mut Reader, misc: u16, format: &SampleFormat) -> Option<Sample> {
+    let has = |bit| format.has(bit);
+    let mut s = Sample {
+        misc,
+        ..Default::default()
+    };
+    if has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+        s.id = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_IP) {
+        s.ip = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_TID) {
+        let (pid, tid) = r.pid_tid()?;
+        s.pid = Some(pid);
+        s.tid = Some(tid);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+        s.time = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_ADDR) {
+        s.addr = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_ID) {
+        s.id = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+        s.stream_id = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+        s.cpu = Some(r.pid_tid()?.0);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_PERIOD) {
+        s.period = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_READ) {
+        s.read = Some(parse_read(r, format.read_format)?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_CALLCHAIN) {
+        let nr = r.u64()?;
+        s.callchain = r.u64s(nr)?;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_RAW) {
+        let size = r.u32()? as usize;
+        s.raw = r.bytes(size)?.to_vec();
+        // The size and data are padded together to 8 bytes.
+        r.pos = (r.pos + 7) & !7;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_BRANCH_STACK) {
+        let nr = r.u64()?;
+        if format.branch_sample_type & PERF_SAMPLE_BRANCH_HW_INDEX != 0 {
+            r.u64()?;
+        }
+        let words = r.u64s(nr.checked_mul(3)?)?;
+        s.branches = words
+            .chunks(3)
+            .map(|w| BranchEntry {
+                from: w[0],
+                to: w[1],
+                flags: w[2],
+            })
+            .collect();
+        if format.branch_sample_type & PERF_SAMPLE_BRANCH_COUNTERS != 0 {
+            r.u64s(nr)?;
+        }
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_REGS_USER) {
+        s.regs_user = r.registers(format.regs_user)?;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_STACK_USER) {
+        let size = r.u64()? as usize;
+        if size > 0 {
+            let data = r.bytes(size)?;
+            // Only the first `dyn_size` bytes were filled in.
+            let dyn_size = r.u64()? as usize;
+            s.stack_user = data[..dyn_size.min(size)].to_vec();
+        }
+    }
+    if format.sample_type
+        & (perf_event_sample_format_PERF_SAMPLE_WEIGHT | PERF_SAMPLE_WEIGHT_STRUCT)
+        != 0
+    {
+        s.weight = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_DATA_SRC) {
+        s.data_src = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_TRANSACTION) {
+        s.transaction = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_REGS_INTR) {
+        s.regs_intr = r.registers(format.regs_intr)?;
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_PHYS_ADDR) {
+        s.phys_addr = Some(r.u64()?);
+    }
+    if format.has(PERF_SAMPLE_CGROUP) {
+        s.cgroup = Some(r.u64()?);
+    }
+    if format.has(PERF_SAMPLE_DATA_PAGE_SIZE) {
+        s.data_page_size = Some(r.u64()?);
+    }
+    if format.has(PERF_SAMPLE_CODE_PAGE_SIZE) {
+        s.code_page_size = Some(r.u64()?);
+    }
+    if has(perf_event_sample_format_PERF_SAMPLE_AUX) {
+        let size = r.u64()? as usize;
+        s.aux = r.bytes(size)?.to_vec();
+    }
+    Some(s)
+}
+
+/// Decode a `PERF_SAMPLE_READ` value laid out by `read_format`.
+fn parse_read(r: &mut Reader, read_format: u64) -> Option<ReadValues> {
+    let has = |bit: perf_event_read_format| read_format & bit as u64 != 0;
+    let group = has(perf_event_read_format_PERF_FORMAT_GROUP);
+    let nr = if group { r.u64()? } else { 1 };
+    let mut read = ReadValues::default();
+    // A single value comes before the times, a group after.
+    let mut first = None;
+    if !group {
+        first = Some(r.u64()?);
+    }
+    if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_ENABLED) {
+        read.time_enabled = Some(r.u64()?);
+    }
+    if has(perf_event_read_format_PERF_FORMAT_TOTAL_TIME_RUNNING) {
+        read.time_running = Some(r.u64()?);
+    }
+    for _ in 0..nr {
+        let value = match first.take() {
+            Some(value) => value,
+            None => r.u64()?,
+        };
+        let id = match has(perf_event_read_format_PERF_FORMAT_ID) {
+            true => Some(r.u64()?),
+            false => None,
+        };
+        read.values.push(ReadValue { value, id });
+    }
+    Some(read)
+}
+
+/// Build a record from a header and body words, for tests.
+#[cfg(test)]
+fn record(type_: u32, misc: u16, body: &[u64], tail: &[u8]) -> Vec<u8> {
+    let size = HEADER_SIZE + body.len() * 8 + tail.len();
+    let mut bytes = Vec::with_capacity(size);
+    bytes.extend_from_slice(&type_.to_ne_bytes());
+    bytes.extend_from_slice(&misc.to_ne_bytes());
+    bytes.extend_from_slice(&(size as u16).to_ne_bytes());
+    for word in body {
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    bytes.extend_from_slice(tail);
+    bytes
+}
+
+/// Pack a pair of `u32` into one word, as the kernel lays them out.
+#[cfg(test)]
+fn pair(a: u32, b: u32) -> u64 {
+    let mut word = [0; 8];
+    word[..4].copy_from_slice(&a.to_ne_bytes());
+    word[4..].copy_from_slice(&b.to_ne_bytes());
+    u64::from_ne_bytes(word)
+}
+
+#[cfg(test)]
+#[test]
+fn parse_sample_test() {
+    let format = SampleFormat {
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP
+            | perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_TIME
+            | perf_event_sample_format_PERF_SAMPLE_PERIOD
+            | perf_event_sample_format_PERF_SAMPLE_READ
+            | perf_event_sample_format_PERF_SAMPLE_CALLCHAIN
+            | perf_event_sample_format_PERF_SAMPLE_REGS_USER
+            | perf_event_sample_format_PERF_SAMPLE_STACK_USER,
+        read_format: (perf_event_read_format_PERF_FORMAT_GROUP
+            | perf_event_read_format_PERF_FORMAT_ID) as u64,
+        regs_user: 0b101,
+        ..Default::default()
+    };
+    let stack = 0x1122_3344_5566_7788_u64;
+    let bytes = record(
+        perf_event_type_PERF_RECORD_SAMPLE,
+        PERF_RECORD_MISC_USER as u16,
+        &[
+            0x4000,
+            pair(10, 11),
+            99,
+            1000,
+            // Group of two counters with their IDs.
+            2,
+            5,
+            100,
+            6,
+            101,
+            // Two callchain entries.
+            2,
+            0x4000,
+            0x5000,
+            // Registers: ABI 64-bit, two set in the mask.
+            2,
+            7,
+            8,
+            // 16 bytes of stack, 8 of them filled in.
+            16,
+            stack,
+            0,
+            8,
+        ],
+        &[],
+    );
+    let sample = match parse(&bytes, &format).unwrap() {
+        Record::Sample(sample) => sample,
+        other => panic!("{:?}", other),
+    };
+    assert!(sample.is_user());
+    assert_eq!(sample.ip, Some(0x4000));
+    assert_eq!((sample.pid, sample.tid), (Some(10), Some(11)));
+    assert_eq!(sample.time, Some(99));
+    assert_eq!(sample.period, Some(1000));
+    let read = sample.read.unwrap();
+    assert_eq!(read.values.len(), 2);
+    assert_eq!(read.values[1].value, 6);
+    assert_eq!(read.values[1].id, Some(101));
+    assert_eq!(sample.callchain, vec![0x4000, 0x5000]);
+    assert_eq!(sample.regs_user.unwrap().values, vec![7, 8]);
+    assert_eq!(sample.stack_user, stack.to_ne_bytes().to_vec());
+
+    // A record cut short is rejected rather than misread.
+    assert!(parse(&bytes[..bytes.len() - 8], &format).is_none());
+}
+
+#[test]
+fn parse_side_band_test() {
+    let format = SampleFormat {
+        sample_type: perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_TIME,
+        sample_id_all: true,
+        ..Default::default()
+    };
+    let bytes = record(
+        perf_event_type_PERF_RECORD_COMM,
+        PERF_RECORD_MISC_COMM_EXEC as u16,
+        &[pair(7, 7)],
+        b"ls\0\0\0\0\0\0\x07\0\0\0\x07\0\0\0\x2a\0\0\0\0\0\0\0",
+    );
+    let expected = Comm {
+        pid: 7,
+        tid: 7,
+        comm: "ls".to_string(),
+        exec: true,
+        sample_id: SampleId {
+            pid: Some(7),
+            tid: Some(7),
+            time: Some(u64::from_ne_bytes([0x2a, 0, 0, 0, 0, 0, 0, 0])),
+            ..Default::default()
+        },
+    };
+    assert_eq!(parse(&bytes, &format), Some(Record::Comm(expected)));
+
+    let format = SampleFormat::default();
+    let mut name = b"/bin/ls".to_vec();
+    name.resize(8, 0);
+    let bytes = record(
+        perf_event_type_PERF_RECORD_MMAP2,
+        0,
+        &[pair(1, 2), 0x1000, 0x2000, 0, pair(8, 1), 42, 0, pair(5, 2)],
+        &name,
+    );
+    match parse(&bytes, &format).unwrap() {
+        Record::Mmap2(mmap) => {
+            assert_eq!((mmap.addr, mmap.len), (0x1000, 0x2000));
+            assert_eq!((mmap.maj, mmap.min, mmap.ino), (8, 1, 42));
+            assert_eq!((mmap.prot, mmap.flags), (5, 2));
+            assert_eq!(mmap.filename, "/bin/ls");
+        }
+        other => panic!("{:?}", other),
+    }
+    let bytes = record(perf_event_type_PERF_RECORD_LOST, 0, &[3, 17], &[]);
+    assert!(matches!(
+        parse(&bytes, &format),
+        Some(Record::Lost(Lost {
+            id: 3,
+            lost: 17,
+            ..
+        }))
+    ));
+    let bytes = record(
+        perf_event_type_PERF_RECORD_FORK,
+        0,
+        &[pair(2, 1), pair(2, 1), 5],
+        &[],
+    );
+    assert!(matches!(parse(&bytes, &format), Some(Record::Fork(_))));
+    let bytes = record(perf_event_type_PERF_RECORD_SWITCH, 0, &[], &[]);
+    assert_eq!(
+        parse(&bytes, &format),
+        Some(Record::Other(perf_event_type_PERF_RECORD_SWITCH))
+    );
+}
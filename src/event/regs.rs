@@ -0,0 +1,75 @@
+//! User registers in samples.
+//! `sample_regs_user` selects registers by their number in
+//! the kernel's `asm/perf_regs.h`, and a sample holds the
+//! selected ones in increasing order of number. Unwinding a
+//! copied stack needs the instruction pointer, the stack
+//! pointer and the frame pointer, and on aarch64 the link
+//! register, which holds the return address of leaf functions.
+
+use crate::event::records::Registers;
+
+/// Numbers from `arch/x86/include/uapi/asm/perf_regs.h`.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    pub const FP: u32 = 6;
+    pub const SP: u32 = 7;
+    pub const IP: u32 = 8;
+    /// x86_64 has no link register.
+    pub const LR: Option<u32> = None;
+}
+
+/// Numbers from `arch/arm64/include/uapi/asm/perf_regs.h`.
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    pub const FP: u32 = 29;
+    pub const SP: u32 = 31;
+    pub const IP: u32 = 32;
+    pub const LR: Option<u32> = Some(30);
+}
+
+/// Other architectures are left without unwinding registers.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod arch {
+    pub const FP: u32 = 0;
+    pub const SP: u32 = 0;
+    pub const IP: u32 = 0;
+    pub const LR: Option<u32> = None;
+}
+
+pub use arch::*;
+
+/// Whether user stacks can be unwound on this architecture.
+pub const CAN_UNWIND: bool = cfg!(any(target_arch = "x86_64", target_arch = "aarch64"));
+
+/// The registers to sample for unwinding user stacks.
+pub fn unwind_mask() -> u64 {
+    let mut mask = (1 << FP) | (1 << SP) | (1 << IP);
+    if let Some(lr) = LR {
+        mask |= 1 << lr;
+    }
+    mask
+}
+
+/// The value of register `reg` in `regs`, sampled with `mask`.
+pub fn value(regs: &Registers, mask: u64, reg: u32) -> Option<u64> {
+    if mask & (1 << reg) == 0 {
+        return None;
+    }
+    let index = (mask & ((1 << reg) - 1)).count_ones() as usize;
+    regs.values.get(index).copied()
+}
+
+#[cfg(test)]
+#[test]
+fn value_test() {
+    let regs = Registers {
+        abi: 2,
+        values: vec![10, 20, 30],
+    };
+    let mask = (1 << 3) | (1 << 6) | (1 << 9);
+    assert_eq!(value(&regs, mask, 3), Some(10));
+    assert_eq!(value(&regs, mask, 6), Some(20));
+    assert_eq!(value(&regs, mask, 9), Some(30));
+    assert_eq!(value(&regs, mask, 7), None);
+    assert_eq!(unwind_mask().count_ones(), if LR.is_some() { 4 } else { 3 });
+}
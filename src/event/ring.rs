@@ -0,0 +1,271 @@
+//! A `RingBuffer` reads the records a sampled event
+//! writes to its mmapped buffer. The mapping is one
+//! control page, a `perf_event_mmap_page`, followed by
+//! `2^n` data pages the kernel writes records into.
+//!
+//! The kernel advances `data_head` as it writes, and
+//! we advance `data_tail` as records are consumed, so
+//! it never overwrites a record not yet read. Reading
+//! `data_head` must happen before reading the data it
+//! covers, and the data must be read before `data_tail`
+//! hands its space back; see the `perf_event_open` man page.
+
+use crate::bindings::*;
+use crate::event::fd::FileDesc;
+use crate::event::records::{self, Record, SampleFormat, HEADER_SIZE};
+use crate::event::utils::*;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A mapped ring buffer and the layout of its records.
+#[derive(Debug)]
+pub struct RingBuffer {
+    base: *mut u8,
+    len: usize,
+    /// Where the data pages start, and their total size.
+    data_offset: usize,
+    data_size: u64,
+    format: SampleFormat,
+}
+
+impl RingBuffer {
+    /// Map `pages` data pages on `fd`, opened with `attr`.
+    /// `pages` must be a power of two.
+    pub fn new(fd: &FileDesc, attr: &perf_event_attr, pages: usize) -> Result<Self, SysErr> {
+        if !pages.is_power_of_two() {
+            return Err(SysErr::RingSize);
+        }
+        let page = page_size();
+        let len = (pages + 1) * page;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(SysErr::MmapFail);
+        }
+        Ok(Self {
+            base: base as *mut u8,
+            len,
+            data_offset: page,
+            data_size: (pages * page) as u64,
+            format: SampleFormat::new(attr),
+        })
+    }
+
+    /// The layout records are decoded with.
+    pub fn format(&self) -> &SampleFormat {
+        &self.format
+    }
+
+    /// The control page at the start of the mapping.
+    fn control(&self) -> *mut perf_event_mmap_page {
+        self.base as *mut perf_event_mmap_page
+    }
+
+    /// How far the kernel has written. The acquire load
+    /// keeps the data reads after it from being reordered
+    /// before it.
+    fn head(&self) -> u64 {
+        let head = unsafe { &(*self.control()).data_head } as *const u64;
+        unsafe { (*(head as *const AtomicU64)).load(Ordering::Acquire) }
+    }
+
+    /// How far we have read; only we write it.
+    fn tail(&self) -> u64 {
+        unsafe { (*self.control()).data_tail }
+    }
+
+    /// Hand space back to the kernel. The release store
+    /// keeps the data reads before it from being reordered
+    /// after it.
+    fn set_tail(&self, tail: u64) {
+        let ptr = unsafe { &mut (*self.control()).data_tail } as *mut u64;
+        unsafe { (*(ptr as *const AtomicU64)).store(tail, Ordering::Release) }
+    }
+
+    /// Copy `out.len()` bytes starting at `pos`, a position
+    /// in the stream, wrapping around the end of the data.
+    fn copy(&self, pos: u64, out: &mut [u8]) {
+        let start = (pos % self.data_size) as usize;
+        let first = out.len().min(self.data_size as usize - start);
+        unsafe {
+            let data = self.base.add(self.data_offset);
+            std::ptr::copy_nonoverlapping(data.add(start), out.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(data, out[first..].as_mut_ptr(), out.len() - first);
+        }
+    }
+
+    /// Whether every record written so far has been read.
+    pub fn is_empty(&self) -> bool {
+        self.head() == self.tail()
+    }
+
+    /// The next record, header included, as raw bytes,
+    /// or `None` if there is nothing left to read.
+    pub fn next_raw(&mut self) -> Option<Vec<u8>> {
+        let head = self.head();
+        let tail = self.tail();
+        if head == tail {
+            return None;
+        }
+        let mut header = [0; HEADER_SIZE];
+        self.copy(tail, &mut header);
+        let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+        // A record never claims more than was written,
+        // unless the buffer is corrupt; skip to the head.
+        if size < HEADER_SIZE as u64 || size > head - tail {
+            self.set_tail(head);
+            return None;
+        }
+        let mut record = vec![0; size as usize];
+        self.copy(tail, &mut record);
+        self.set_tail(tail + size);
+        Some(record)
+    }
+
+    /// The next record, decoded. Records too short
+    /// for their layout are skipped.
+    pub fn next_record(&mut self) -> Option<Record> {
+        while let Some(raw) = self.next_raw() {
+            if let Some(record) = records::parse(&raw, &self.format) {
+                return Some(record);
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for RingBuffer {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        self.next_record()
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.len) };
+    }
+}
+
+/// The size of a page in bytes.
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// A task-clock event sampling this thread, reporting
+/// its name changes.
+#[cfg(test)]
+fn comm_attr() -> perf_event_attr {
+    let mut event = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_TASK_CLOCK as u64,
+        __bindgen_anon_1: perf_event_attr__bindgen_ty_1 {
+            sample_period: 100_000,
+        },
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP
+            | perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_TIME
+            | perf_event_sample_format_PERF_SAMPLE_PERIOD,
+        ..Default::default()
+    };
+    event.set_disabled(1);
+    event.set_exclude_kernel(1);
+    event.set_exclude_hv(1);
+    event.set_comm(1);
+    event.set_sample_id_all(1);
+    event
+}
+
+/// Rename the calling thread.
+#[cfg(test)]
+fn set_thread_name(name: &str) {
+    let name = std::ffi::CString::new(name).unwrap();
+    unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) };
+}
+
+#[cfg(test)]
+#[test]
+fn ring_buffer_test() {
+    use crate::event::fd::OpenFlags;
+    let mut event = comm_attr();
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap();
+    assert!(matches!(
+        RingBuffer::new(&fd, &event, 3),
+        Err(SysErr::RingSize)
+    ));
+    let mut ring = RingBuffer::new(&fd, &event, 4).unwrap();
+    assert!(ring.is_empty());
+    fd.enable().unwrap();
+    set_thread_name("ruperf-ring");
+    let start = std::time::Instant::now();
+    let mut x = 0u64;
+    while start.elapsed() < std::time::Duration::from_millis(20) {
+        x = x.wrapping_add(std::hint::black_box(x) ^ 1);
+    }
+    fd.disable().unwrap();
+
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+    let records: Vec<Record> = ring.by_ref().collect();
+    assert!(ring.is_empty());
+    let comm = records.iter().find_map(|r| match r {
+        Record::Comm(comm) => Some(comm),
+        _ => None,
+    });
+    let comm = comm.unwrap();
+    assert_eq!((comm.comm.as_str(), comm.tid), ("ruperf-ring", tid));
+    assert_eq!(comm.sample_id.tid, Some(tid));
+    let samples: Vec<&records::Sample> = records
+        .iter()
+        .filter_map(|r| match r {
+            Record::Sample(sample) => Some(sample),
+            _ => None,
+        })
+        .collect();
+    assert!(!samples.is_empty());
+    for sample in samples {
+        assert_eq!(sample.tid, Some(tid));
+        assert!(sample.ip.is_some() && sample.time.is_some());
+        assert!(sample.is_user());
+    }
+}
+
+#[test]
+fn ring_buffer_wrap_test() {
+    use crate::event::fd::OpenFlags;
+    // A dummy event takes no samples, only side-band records.
+    let mut event = comm_attr();
+    event.config = perf_sw_ids_PERF_COUNT_SW_DUMMY as u64;
+    event.sample_type = 0;
+    let fd = FileDesc::new(&mut event, None, -1, -1, OpenFlags::default()).unwrap();
+    let mut ring = RingBuffer::new(&fd, &event, 1).unwrap();
+    fd.enable().unwrap();
+    // Many times around a single page, read in batches
+    // small enough that nothing is lost.
+    let mut seen = 0;
+    for batch in 0..20 {
+        for i in 0..16 {
+            set_thread_name(&format!("ring-{}-{}", batch, i));
+        }
+        for i in 0..16 {
+            match ring.next_record() {
+                Some(Record::Comm(comm)) => assert_eq!(comm.comm, format!("ring-{}-{}", batch, i)),
+                other => panic!("{:?}", other),
+            }
+            seen += 1;
+        }
+        assert!(ring.is_empty());
+    }
+    fd.disable().unwrap();
+    assert!(ring.tail() > page_size() as u64);
+    assert_eq!(seen, 320);
+}
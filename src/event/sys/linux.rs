@@ -63,4 +63,5 @@ pub const SET_BPF: u32 = iocw(8, size_of::<u32>());
 pub const PAUSE_OUTPUT: u32 = iocw(9, size_of::<u32>());
 /** NOT SUPPORTED **/
 pub const QUERY_BPF: u32 = iocwr(10, size_of::<*const perf_event_query_bpf>());
-pub const MODIFY_ATTRIBUTES: u32 = iocwr(11, size_of::<*const perf_event_attr>());
+// The kernel declares this one `_IOW`, unlike `QUERY_BPF`.
+pub const MODIFY_ATTRIBUTES: u32 = iocw(11, size_of::<*const perf_event_attr>());
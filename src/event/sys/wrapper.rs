@@ -5,11 +5,13 @@
 extern crate libc;
 use libc::read;
 
-pub fn read_wrap(fd: i32) -> isize {
-    let mut count: isize = 0;
+/// Read as many `u64` words as fit in `buf`.
+/// The kernel writes a record laid out according
+/// to the `read_format` of the event, so `buf` must
+/// be large enough to hold all requested fields.
+pub fn read_wrap(fd: i32, buf: &mut [u64]) -> isize {
     unsafe {
-        let buf: *mut libc::c_void = &mut count as *mut _ as *mut libc::c_void;
-        read(fd, buf, std::mem::size_of_val(&count));
+        let ptr: *mut libc::c_void = buf.as_mut_ptr() as *mut libc::c_void;
+        read(fd, ptr, std::mem::size_of_val(buf))
     }
-    count
 }
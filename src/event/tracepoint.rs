@@ -0,0 +1,167 @@
+//! Tracepoint events.
+//! A tracepoint is named `subsystem:event`, and its
+//! id, used as the `config` of a `PERF_TYPE_TRACEPOINT`
+//! event, is read from tracefs. Either part of the name
+//! may be a glob pattern, e.g. `syscalls:sys_enter_*`.
+
+use crate::utils::ParseError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where tracefs is mounted, in order of preference.
+/// Older systems only have it under debugfs.
+const TRACEFS_EVENTS: &[&str] = &[
+    "/sys/kernel/tracing/events",
+    "/sys/kernel/debug/tracing/events",
+];
+
+/// A single tracepoint and its tracefs id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tracepoint {
+    pub name: String,
+    pub id: u64,
+}
+
+/// The tracefs `events` directory, if tracefs is mounted.
+/// An unmounted tracefs leaves an empty directory behind.
+fn events_dir() -> Option<PathBuf> {
+    TRACEFS_EVENTS.iter().map(PathBuf::from).find(|dir| {
+        fs::read_dir(dir)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false)
+    })
+}
+
+/// Match `s` against a glob `pattern` of `*` and `?` wildcards.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // Where the last `*` was, and how much of `s` it has taken.
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = star {
+            pi = star_pi + 1;
+            si = star_si + 1;
+            star = Some((star_pi, star_si + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Names of the subdirectories of `dir` matching `pattern`, sorted.
+fn matching_dirs(dir: &Path, pattern: &str) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| glob_match(pattern, name))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Resolve `subsystem:event` to every tracepoint it names.
+pub fn parse(s: &str) -> Result<Vec<Tracepoint>, ParseError> {
+    let root = events_dir().ok_or(ParseError::NoTracefs)?;
+    parse_in(&root, s)
+}
+
+/// Resolve `subsystem:event` against the tracefs events under `root`.
+fn parse_in(root: &Path, s: &str) -> Result<Vec<Tracepoint>, ParseError> {
+    let unknown = || ParseError::UnknownTracepoint(s.to_string());
+    let mut parts = s.splitn(2, ':');
+    let system = parts.next().filter(|x| !x.is_empty()).ok_or_else(unknown)?;
+    let event = parts.next().filter(|x| !x.is_empty()).ok_or_else(unknown)?;
+    let mut tracepoints = Vec::new();
+    for sys in matching_dirs(root, system) {
+        for name in matching_dirs(&root.join(&sys), event) {
+            let id = fs::read_to_string(root.join(&sys).join(&name).join("id"))
+                .ok()
+                .and_then(|x| x.trim().parse::<u64>().ok());
+            if let Some(id) = id {
+                tracepoints.push(Tracepoint {
+                    name: format!("{}:{}", sys, name),
+                    id,
+                });
+            }
+        }
+    }
+    if tracepoints.is_empty() {
+        return Err(unknown());
+    }
+    Ok(tracepoints)
+}
+
+/// Every tracepoint, for `ruperf list`. Empty when
+/// tracefs is not mounted or not readable.
+pub fn list() -> Vec<String> {
+    match events_dir() {
+        Some(root) => parse_in(&root, "*:*")
+            .map(|tracepoints| tracepoints.into_iter().map(|t| t.name).collect())
+            .unwrap_or_default(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn glob_match_test() {
+    assert!(glob_match("sys_enter_*", "sys_enter_openat"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("sched_?witch", "sched_switch"));
+    assert!(glob_match("*_switch", "sched_switch"));
+    assert!(glob_match("a*b*c", "aXbYbZc"));
+    assert!(!glob_match("sys_enter_*", "sys_exit_openat"));
+    assert!(!glob_match("sched", "sched_switch"));
+}
+
+#[test]
+fn parse_tracepoint_test() {
+    let root = std::env::temp_dir().join(format!("ruperf-tracefs-test-{}", std::process::id()));
+    for (sys, name, id) in &[
+        ("sched", "sched_switch", 316),
+        ("syscalls", "sys_enter_read", 700),
+        ("syscalls", "sys_enter_write", 698),
+        ("syscalls", "sys_exit_read", 699),
+    ] {
+        let dir = root.join(sys).join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("id"), format!("{}\n", id)).unwrap();
+    }
+    // Files beside the event directories are ignored.
+    fs::write(root.join("syscalls").join("enable"), "0\n").unwrap();
+
+    let switch = parse_in(&root, "sched:sched_switch").unwrap();
+    assert_eq!(
+        switch,
+        vec![Tracepoint {
+            name: "sched:sched_switch".to_string(),
+            id: 316
+        }]
+    );
+    let enters = parse_in(&root, "syscalls:sys_enter_*").unwrap();
+    let names: Vec<&str> = enters.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec!["syscalls:sys_enter_read", "syscalls:sys_enter_write"]
+    );
+    assert_eq!(parse_in(&root, "*:*").unwrap().len(), 4);
+    assert!(matches!(
+        parse_in(&root, "sched:nope"),
+        Err(ParseError::UnknownTracepoint(_))
+    ));
+    assert!(parse_in(&root, "sched").is_err());
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -3,19 +3,178 @@
 //! type for handling system call failures and
 //! invalid event requests.
 
+use crate::bindings::*;
+use crate::utils::ParseError;
+use std::fmt;
+use thiserror::Error;
+
 type Result<T, E> = std::result::Result<T, E>;
 
 /// Errors related to system calls.
-#[derive(Debug)]
+#[derive(Error, Debug)]
 pub enum SysErr {
+    #[error("read() on a counter failed")]
     ReadFail,
+    #[error("ioctl() on a counter failed")]
     IoFail,
+    #[error("invalid ioctl() argument")]
     IoArg,
+    #[error("counter has no ID")]
     IoId,
+    #[error("mmap() of a ring buffer failed")]
+    MmapFail,
+    #[error("a ring buffer must be a power of two pages")]
+    RingSize,
 }
 
 /// Errors related to handling specific events.
-#[derive(Debug)]
+#[derive(Error, Debug)]
 pub enum EventErr {
+    #[error("a glob of tracepoints must be expanded before opening")]
     InvalidEvent,
+    #[error("breakpoint {0} names a symbol that is not resolved to an address")]
+    Unresolved(String),
+}
+
+/// What `perf_event_open()` was asked to open when it failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenContext {
+    pub errno: i32,
+    pub type_: u32,
+    pub config: u64,
+    pub pid: i32,
+    pub cpu: i32,
+}
+
+impl OpenContext {
+    /// Describe a failed attempt to open `attr` on `pid` and `cpu`.
+    pub fn new(errno: i32, attr: &perf_event_attr, pid: i32, cpu: i32) -> Self {
+        Self {
+            errno,
+            type_: attr.type_,
+            config: attr.config,
+            pid,
+            cpu,
+        }
+    }
+}
+
+impl fmt::Display for OpenContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "perf_event_open(type {}, config {:#x}) on pid {}, cpu {} failed with errno {}",
+            self.type_, self.config, self.pid, self.cpu, self.errno
+        )
+    }
+}
+
+/// Why an event could not be opened, explained
+/// the way `perf` explains it.
+#[derive(Error, Debug)]
+pub enum OpenError {
+    #[error("event not supported by this CPU or kernel ({0})")]
+    NotSupported(OpenContext),
+    #[error("invalid or unsupported event attributes ({0})")]
+    InvalidAttr(OpenContext),
+    #[error(
+        "permission denied, perf_event_paranoid={paranoid}, need CAP_PERFMON \
+         or perf_event_paranoid <= 1 to count this event ({ctx})"
+    )]
+    NoPermission { ctx: OpenContext, paranoid: i32 },
+    #[error("too many open files, raise the limit with `ulimit -n` ({0})")]
+    TooManyFiles(OpenContext),
+    #[error("no such task ({0})")]
+    NoSuchTask(OpenContext),
+    #[error("counters are busy, another tool may own the PMU ({0})")]
+    Busy(OpenContext),
+    #[error("{} ({ctx})", std::io::Error::from_raw_os_error(ctx.errno))]
+    Other { ctx: OpenContext },
+    #[error("could not read the ID of a group member")]
+    NoGroupId,
+    #[error("an event group needs at least one event")]
+    EmptyGroup,
+    #[error(transparent)]
+    Event(#[from] EventErr),
+    #[error("{freq} samples a second is above kernel.perf_event_max_sample_rate, {max}")]
+    SampleRate { freq: u64, max: u64 },
+    #[error(transparent)]
+    Unresolved(Box<ParseError>),
+    #[error("could not map a ring buffer: {0}")]
+    RingBuffer(SysErr),
+    #[error("could not share a ring buffer: {0}")]
+    ShareRing(SysErr),
+    #[error("could not enable the event: {0}")]
+    Enable(SysErr),
+}
+
+impl OpenError {
+    /// Classify a failure by its errno.
+    pub fn from_context(ctx: OpenContext) -> Self {
+        match ctx.errno {
+            libc::ENOENT | libc::ENODEV | libc::ENXIO | libc::EOPNOTSUPP | libc::ENOSYS => {
+                OpenError::NotSupported(ctx)
+            }
+            libc::EINVAL => OpenError::InvalidAttr(ctx),
+            libc::EACCES | libc::EPERM => OpenError::NoPermission {
+                ctx,
+                paranoid: perf_event_paranoid(),
+            },
+            libc::EMFILE | libc::ENFILE => OpenError::TooManyFiles(ctx),
+            libc::ESRCH => OpenError::NoSuchTask(ctx),
+            libc::EBUSY => OpenError::Busy(ctx),
+            _ => OpenError::Other { ctx },
+        }
+    }
+
+    /// Whether the event cannot be counted here at all, so
+    /// `perf stat` would report it as `<not supported>`.
+    pub fn is_not_supported(&self) -> bool {
+        matches!(self, OpenError::NotSupported(_) | OpenError::InvalidAttr(_))
+    }
+}
+
+/// The system's `perf_event_paranoid` setting, or 2,
+/// the kernel's default, if it cannot be read.
+fn perf_event_paranoid() -> i32 {
+    std::fs::read_to_string("/proc/sys/kernel/perf_event_paranoid")
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(2)
+}
+
+/// The most samples a second the kernel takes, if known.
+pub fn perf_event_max_sample_rate() -> Option<u64> {
+    std::fs::read_to_string("/proc/sys/kernel/perf_event_max_sample_rate")
+        .ok()
+        .and_then(|x| x.trim().parse().ok())
+}
+
+#[cfg(test)]
+#[test]
+fn open_error_test() {
+    let attr = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_HARDWARE,
+        config: 7,
+        ..Default::default()
+    };
+    let ctx = |errno| OpenContext::new(errno, &attr, 42, -1);
+    let e = OpenError::from_context(ctx(libc::ENOENT));
+    assert!(e.is_not_supported());
+    assert_eq!(
+        e.to_string(),
+        "event not supported by this CPU or kernel \
+         (perf_event_open(type 0, config 0x7) on pid 42, cpu -1 failed with errno 2)"
+    );
+    let e = OpenError::from_context(ctx(libc::EACCES));
+    assert!(!e.is_not_supported());
+    assert!(e.to_string().contains("perf_event_paranoid="));
+    assert!(matches!(
+        OpenError::from_context(ctx(libc::EMFILE)),
+        OpenError::TooManyFiles(_)
+    ));
+    assert!(matches!(
+        OpenError::from_context(ctx(libc::E2BIG)),
+        OpenError::Other { .. }
+    ));
 }
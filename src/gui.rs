@@ -179,16 +179,72 @@ impl Application for Gui {
                                 }
                             }
                             PerfEvent::Record => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Record),
+                                    Some(data_state.get_options()),
+                                    Some(data_state.input_value.to_string()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Report => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Report),
+                                    Some(data_state.get_options()),
+                                    None,
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Annotate => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Annotate),
+                                    Some(data_state.get_options()),
+                                    Some(data_state.input_value.to_string()),
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Top => {
-                                //TODO: Add program here
+                                let task = task::Task::new(
+                                    Some(PerfEvent::Top),
+                                    Some(data_state.get_options()),
+                                    None,
+                                );
+
+                                match task {
+                                    Ok(t) => {
+                                        run_program(&t, data_state);
+                                        state.tasks.push(t);
+                                    }
+                                    Err(s) => {
+                                        println!("Error: {}", s);
+                                    }
+                                }
                             }
                             PerfEvent::Bench => {
                                 //TODO: Add program here
@@ -130,6 +130,15 @@ pub mod pane {
                     }
                 }
 
+                perf::PerfEvent::Report | perf::PerfEvent::Annotate => {
+                    res.push_str(" --stdio");
+                }
+
+                // The GUI waits for the output, so take one refresh.
+                perf::PerfEvent::Top => {
+                    res.push_str(" --stdio --iterations 1");
+                }
+
                 perf::PerfEvent::Test => {
                     if self.launch_options.json {
                         res.push_str(" --json");
@@ -262,7 +262,10 @@ pub mod task {
             match options {
                 Some(res) => {
                     command.push_str(res.as_str());
-                    if task_event == perf::PerfEvent::Stat {
+                    if task_event == perf::PerfEvent::Stat
+                        || task_event == perf::PerfEvent::Record
+                        || task_event == perf::PerfEvent::Annotate
+                    {
                         command.push(' ');
                     }
                     task_options = res;
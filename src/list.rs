@@ -0,0 +1,84 @@
+//! # List driver.
+//! <p> Usage: <em> ruperf list [CATEGORY] </em>
+//! Prints every event that can be passed to <em> ruperf stat -e </em>,
+//! where CATEGORY is one of hw, sw, cache or tracepoint. </p>
+
+extern crate structopt;
+use crate::event::catalog::{self, EventNames};
+use crate::event::tracepoint;
+use structopt::StructOpt;
+
+/// Configuration settings for running list.
+#[derive(Debug, StructOpt)]
+pub struct ListOptions {
+    #[structopt(
+        possible_values = &[
+            "hw", "hardware", "sw", "software", "cache", "hwcache", "tracepoint", "tp",
+        ],
+        help = "Only list events in this category"
+    )]
+    pub category: Option<String>,
+}
+
+/// Print one event with all of its names.
+fn print_event(names: &[String], category: &str) {
+    println!("  {:<48} [{}]", names.join(" OR "), category);
+}
+
+/// Print every event in a table of generic events.
+fn print_table(table: &[EventNames], category: &str) {
+    for event in table {
+        let names: Vec<String> = event.names.iter().map(|x| x.to_string()).collect();
+        print_event(&names, category);
+    }
+}
+
+/// Print the events in `options.category`, or every event.
+pub fn run_list(options: &ListOptions) {
+    let category = options.category.as_deref();
+    let show = |names: &[&str]| match category {
+        Some(c) => names.contains(&c),
+        None => true,
+    };
+
+    println!("\nList of pre-defined events (to be used in -e):\n");
+    if show(&["hw", "hardware"]) {
+        print_table(catalog::HARDWARE_EVENTS, "Hardware event");
+        println!();
+    }
+    if show(&["sw", "software"]) {
+        print_table(catalog::SOFTWARE_EVENTS, "Software event");
+        println!();
+    }
+    if show(&["cache", "hwcache"]) {
+        for event in catalog::cache_events() {
+            print_event(&catalog::names(&event), "Hardware cache event");
+        }
+        println!();
+    }
+    if show(&["tracepoint", "tp"]) {
+        for name in tracepoint::list() {
+            print_event(&[name], "Tracepoint event");
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn listed_events_test() {
+    use crate::stat::StatEvent;
+    let events: Vec<StatEvent> = catalog::HARDWARE_EVENTS
+        .iter()
+        .chain(catalog::SOFTWARE_EVENTS)
+        .map(|e| e.event.clone())
+        .chain(catalog::cache_events())
+        .collect();
+    // 10 hardware, 11 software and 16 valid cache ops,
+    // each counted as accesses and as misses.
+    assert_eq!(events.len(), 10 + 11 + 32);
+    for event in events {
+        let name = event.to_string();
+        assert_eq!(name.parse::<StatEvent>().unwrap(), event);
+    }
+}
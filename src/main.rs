@@ -4,21 +4,36 @@
 //! <ul>
 //! <li>test</li>
 //! <li>stat</li>
+//! <li>record</li>
+//! <li>report</li>
+//! <li>annotate</li>
+//! <li>top</li>
+//! <li>list</li>
 //! <li>gui</li>
 //! </ul>
 
+mod annotate;
 mod bindings;
 mod event;
 mod gui;
+mod list;
+mod record;
+mod report;
 mod stat;
 mod test;
+mod top;
 mod utils;
 
 extern crate structopt;
+use annotate::*;
 use gui::*;
+use list::*;
+use record::*;
+use report::*;
 use stat::*;
 use structopt::StructOpt;
 use test::*;
+use top::*;
 
 /// Define command line options.
 #[derive(Debug, StructOpt)]
@@ -30,6 +45,30 @@ enum Opt {
         about = "Collects hardware/software event counters",
     )]
     Stat(StatOptions),
+    #[structopt(
+        setting = structopt::clap::AppSettings::TrailingVarArg,
+        setting = structopt::clap::AppSettings::AllowLeadingHyphen,
+        name = "record",
+        about = "Samples a command into perf.data",
+    )]
+    Record(RecordOptions),
+    #[structopt(
+        name = "report",
+        about = "Breaks the samples of perf.data down by function"
+    )]
+    Report(ReportOptions),
+    #[structopt(
+        name = "annotate",
+        about = "Shows the samples of a function by instruction"
+    )]
+    Annotate(AnnotateOptions),
+    #[structopt(
+        name = "top",
+        about = "Shows the functions taking the most samples, live"
+    )]
+    Top(TopOptions),
+    #[structopt(name = "list", about = "Lists the events stat can count")]
+    List(ListOptions),
     #[structopt(
         setting = structopt::clap::AppSettings::TrailingVarArg,
         setting = structopt::clap::AppSettings::AllowLeadingHyphen,
@@ -50,6 +89,11 @@ fn main() {
     let opt = Opt::from_args();
     match opt {
         Opt::Stat(x) => run_stat(x),
+        Opt::Record(x) => run_record(x),
+        Opt::Report(x) => run_report(x),
+        Opt::Annotate(x) => run_annotate(x),
+        Opt::Top(x) => run_top(x),
+        Opt::List(x) => run_list(&x),
         Opt::Test(x) => run_test(&x),
         Opt::Gui(x) => {
             run_gui(&x).unwrap();
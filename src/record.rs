@@ -0,0 +1,559 @@
+//! # Record driver.
+//! <p> Usage: <em> ruperf record [-e EVENT] [-F FREQ | -c PERIOD] COMMAND [ARGS] </em>
+//! Samples COMMAND and writes the samples to <em> perf.data </em>. </p>
+//! <p> Usage: <em> ruperf record -p PID[,PID] | -a | -C CPUS </em>
+//! Samples running processes, or every task on the given
+//! CPUs, until interrupted. </p>
+//! <p> Each sample holds the instruction pointer, task, time,
+//! CPU and period. Names and mappings of the tasks are recorded
+//! alongside, so that `ruperf report` or `perf report` can tell
+//! which program and function each sample landed in. </p>
+//! <p> With <em> -g </em> or <em> --call-graph MODE </em> samples
+//! also hold the call chain: walked along frame pointers with
+//! <em> fp </em>, as a copy of the user stack for `ruperf report`
+//! to unwind with <em> dwarf[,SIZE] </em>, or from the last branch
+//! records with <em> lbr </em>. </p>
+
+pub mod data;
+pub(crate) mod sampling;
+pub(crate) mod synth;
+
+extern crate structopt;
+use crate::bindings::*;
+use crate::event::open::OpenError;
+use crate::event::records::{SampleFormat, HEADER_SIZE};
+use crate::event::regs;
+use crate::stat::target::{self, IdList};
+use crate::stat::topology::{self, CpuList};
+use crate::stat::{self, StatEvent, INTERRUPTED};
+use crate::utils::{parse_seconds, ParseError};
+use data::{DataWriter, FileAttr};
+use os_pipe::pipe;
+use sampling::{Sampling, Target, TargetRing};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Samples per second when neither `-F` nor `-c` is given.
+pub(crate) const DEFAULT_FREQ: u64 = 4000;
+
+/// Bytes of user stack copied into each sample with `dwarf`.
+const DEFAULT_STACK_SIZE: u32 = 8192;
+
+/// The largest stack copy the kernel takes, which must fit
+/// in the 16-bit size of a record, less the sample itself.
+const MAX_STACK_SIZE: u32 = 65528;
+
+/// How call chains are collected.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CallGraph {
+    /// The kernel follows frame pointers.
+    Fp,
+    /// The kernel copies this many bytes of the user stack,
+    /// along with the registers to unwind it from.
+    Dwarf(u32),
+    /// The user chain is read from the last branch records.
+    Lbr,
+}
+
+impl FromStr for CallGraph {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError::InvalidCallGraph(s.to_string());
+        let (mode, size) = match s.split_once(',') {
+            Some((mode, size)) => (mode, Some(size)),
+            None => (s, None),
+        };
+        match (mode, size) {
+            ("fp", None) => Ok(CallGraph::Fp),
+            ("lbr", None) => Ok(CallGraph::Lbr),
+            ("dwarf", None) => Ok(CallGraph::Dwarf(DEFAULT_STACK_SIZE)),
+            ("dwarf", Some(size)) => match size.parse::<u32>() {
+                // The kernel takes whole words only.
+                Ok(size) if size > 0 && size <= MAX_STACK_SIZE => {
+                    Ok(CallGraph::Dwarf((size + 7) & !7))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Sample a command, running processes, or whole CPUs.
+/// See `./ruperf record --help' for more information.
+#[derive(Debug, StructOpt)]
+pub struct RecordOptions {
+    #[structopt(short, long, help = "Event to sample", number_of_values = 1)]
+    pub event: Vec<StatEvent>,
+
+    #[structopt(
+        short = "F",
+        long,
+        help = "Sample this many times a second",
+        value_name = "FREQ",
+        conflicts_with = "count"
+    )]
+    pub freq: Option<u64>,
+
+    #[structopt(
+        short = "c",
+        long,
+        help = "Sample once every PERIOD events",
+        value_name = "PERIOD"
+    )]
+    pub count: Option<u64>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Attach to running processes, sampling all their threads"
+    )]
+    pub pid: Option<IdList>,
+
+    #[structopt(
+        short = "g",
+        help = "Record call chains by frame pointers, as --call-graph fp"
+    )]
+    pub call_chains: bool,
+
+    #[structopt(
+        long,
+        help = "Record call chains with MODE: fp, dwarf[,SIZE] or lbr",
+        value_name = "MODE",
+        conflicts_with = "call-chains"
+    )]
+    pub call_graph: Option<CallGraph>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Sample every task on all CPUs",
+        conflicts_with = "pid"
+    )]
+    pub all_cpus: bool,
+
+    #[structopt(
+        short = "C",
+        long,
+        help = "Sample every task on the listed CPUs, e.g. 0-3,7",
+        conflicts_with_all = &["pid", "all-cpus"]
+    )]
+    pub cpu: Option<CpuList>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Pages of ring buffer for each CPU or thread, a power of two",
+        value_name = "PAGES",
+        default_value = "128"
+    )]
+    pub mmap_pages: usize,
+
+    #[structopt(
+        long,
+        help = "Stop sampling attached tasks after this many seconds",
+        value_name = "SECS",
+        parse(try_from_str = parse_seconds)
+    )]
+    pub duration: Option<Duration>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Write the samples to FILE",
+        value_name = "FILE",
+        default_value = "perf.data",
+        parse(from_os_str)
+    )]
+    pub output: PathBuf,
+
+    // Allows multiple arguments to be passed, collects everything remaining on
+    // the command line
+    #[structopt(
+        required_unless_one = &["pid", "all-cpus", "cpu"],
+        conflicts_with = "pid",
+        help = "Command to run"
+    )]
+    pub command: Vec<String>,
+}
+
+impl RecordOptions {
+    /// How call chains are collected, if they are.
+    fn call_graph(&self) -> Option<CallGraph> {
+        match self.call_graph {
+            Some(mode) => Some(mode),
+            None if self.call_chains => Some(CallGraph::Fp),
+            None => None,
+        }
+    }
+
+    /// How the events are opened, following a command
+    /// from its `exec()` when `follow_exec` is set.
+    fn sampling(&self, follow_exec: bool) -> Sampling {
+        Sampling {
+            period: self.count,
+            freq: self.freq.unwrap_or(DEFAULT_FREQ),
+            call_graph: self.call_graph(),
+            pages: self.mmap_pages,
+            follow_exec,
+        }
+    }
+}
+
+/// Counts what was written, for the summary.
+#[derive(Default)]
+struct Written {
+    samples: u64,
+    lost: u64,
+}
+
+/// Run perf record on the given command, on running processes
+/// when `-p` is given, or on every task of some CPUs when `-a`
+/// or `-C` is given, and write the samples to `-o FILE`.
+pub fn run_record(options: RecordOptions) {
+    let mut options = options;
+    let (events, default_event) = sampling::events_or_default(&options.event);
+    options.event = events;
+    options.sampling(false).check_or_exit();
+    if matches!(options.call_graph(), Some(CallGraph::Dwarf(_))) && !regs::CAN_UNWIND {
+        eprintln!("--call-graph dwarf is not supported on this architecture");
+        std::process::exit(1);
+    }
+
+    let cpus = match &options.cpu {
+        Some(cpus) => Some(cpus.0.clone()),
+        None if options.all_cpus => Some(topology::online_cpus()),
+        None => None,
+    };
+    let system_wide = |cpus: &[i32]| -> Vec<Target> {
+        cpus.iter()
+            .map(|cpu| Target { pid: -1, cpu: *cpu })
+            .collect()
+    };
+
+    // Keep the previous capture, as perf does.
+    if options.output.exists() {
+        let mut old = options.output.clone().into_os_string();
+        old.push(".old");
+        fs::rename(&options.output, old).ok();
+    }
+    let file = match File::create(&options.output) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not create {}: {}", options.output.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let mut writer = DataWriter::new(BufWriter::new(file)).unwrap_or_else(|e| {
+        eprintln!("Could not write perf.data: {}", e);
+        std::process::exit(1);
+    });
+
+    // SIGINT ends sampling of attached tasks. A command gets
+    // it too, and sampling ends when the command exits.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            stat::on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    let (rings, attrs, written) = if let Some(pids) = &options.pid {
+        let tids: Vec<i32> = pids
+            .0
+            .iter()
+            .flat_map(|pid| target::threads_of(*pid))
+            .collect();
+        let targets: Vec<Target> = tids
+            .iter()
+            .map(|tid| Target { pid: *tid, cpu: -1 })
+            .collect();
+        let sampling = options.sampling(false);
+        let (mut rings, attrs) = sampling.open_or_exit(&options.event, default_event, &targets);
+        synthesize(&mut writer, &pids.0, &rings, &attrs);
+        let exited = || !tids.iter().any(|tid| target::is_alive(*tid));
+        let written = sample_until(&mut writer, &options, &mut rings, exited);
+        (rings, attrs, written)
+    } else if options.command.is_empty() {
+        let cpus = cpus.unwrap_or_else(topology::online_cpus);
+        let sampling = options.sampling(false);
+        let (mut rings, attrs) =
+            sampling.open_or_exit(&options.event, default_event, &system_wide(&cpus));
+        synthesize(&mut writer, &synth::all_processes(), &rings, &attrs);
+        let written = sample_until(&mut writer, &options, &mut rings, || false);
+        (rings, attrs, written)
+    } else {
+        record_command(&mut writer, &options, default_event, cpus)
+    };
+    // Make sure the counters are closed before reporting.
+    let ids: Vec<Vec<u64>> = (0..attrs.len())
+        .map(|i| {
+            rings
+                .iter()
+                .filter_map(|ring| ring.events[i].fd.id().ok())
+                .map(|id| id as u64)
+                .collect()
+        })
+        .collect();
+    drop(rings);
+    let file_attrs: Vec<FileAttr> = attrs
+        .into_iter()
+        .zip(ids)
+        .map(|(attr, ids)| FileAttr { attr, ids })
+        .collect();
+    let size = writer.data_size();
+    if let Err(e) = writer.finish(&file_attrs) {
+        eprintln!("Could not write {}: {}", options.output.display(), e);
+        std::process::exit(1);
+    }
+    if written.lost > 0 {
+        eprintln!(
+            "Lost {} samples; raise --mmap-pages or lower -F",
+            written.lost
+        );
+    }
+    eprintln!(
+        "[ ruperf record: Captured and wrote {:.3} MB {} ({} samples) ]",
+        size as f64 / (1024.0 * 1024.0),
+        options.output.display(),
+        written.samples
+    );
+}
+
+/// Launch the command in `options` and sample it until it exits.
+/// When `cpus` is given every task on them is sampled instead,
+/// for as long as the command runs.
+fn record_command(
+    writer: &mut DataWriter<BufWriter<File>>,
+    options: &RecordOptions,
+    default_event: bool,
+    cpus: Option<Vec<i32>>,
+) -> (Vec<TargetRing>, Vec<perf_event_attr>, Written) {
+    let (reader, mut writer_pipe) = pipe().unwrap();
+    let (mut parent_reader, parent_writer) = pipe().unwrap();
+    let child_reader = reader.try_clone().unwrap();
+    let child_writer = parent_writer.try_clone().unwrap();
+    // Symbols can only be found once the command is loaded, so
+    // stop it after exec and open the counters at that point.
+    let traced = options.event.iter().any(StatEvent::needs_task);
+    let pid_child =
+        stat::spawn_command(options.command.clone(), child_reader, child_writer, traced);
+
+    // Wait for child to say it is set up to execute.
+    let mut buf = [0];
+    let nread = parent_reader.read(&mut buf).unwrap();
+    assert_eq!(nread, 1);
+
+    let mut events = options.event.clone();
+    if traced {
+        writer_pipe.write_all(&[1]).unwrap();
+        let mut status: libc::c_int = 0;
+        unsafe { libc::waitpid(pid_child, &mut status, 0) };
+        if !libc::WIFSTOPPED(status) {
+            eprintln!("Could not run '{}'", options.command[0]);
+            std::process::exit(1);
+        }
+        events = stat::resolve_events(&options.event, pid_child).unwrap_or_else(|e| {
+            unsafe { libc::kill(pid_child, libc::SIGKILL) };
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
+    let system_wide = cpus.is_some();
+    let targets: Vec<Target> = match cpus {
+        Some(cpus) => cpus
+            .iter()
+            .map(|cpu| Target { pid: -1, cpu: *cpu })
+            .collect(),
+        // Follow the command and its children onto every CPU.
+        None => topology::online_cpus()
+            .iter()
+            .map(|cpu| Target {
+                pid: pid_child,
+                cpu: *cpu,
+            })
+            .collect(),
+    };
+    let sampling = options.sampling(!traced && !system_wide);
+    let opened = sampling.open_events(&events, default_event, &targets);
+    let (mut rings, attrs) =
+        opened.unwrap_or_else(|(event, e)| exit_unopened(pid_child, &event, e));
+
+    if system_wide {
+        synthesize(writer, &synth::all_processes(), &rings, &attrs);
+    } else if traced {
+        // The command was loaded before the counters were opened.
+        synthesize(writer, &[pid_child], &rings, &attrs);
+    }
+    if !sampling.follow_exec {
+        if let Err((event, e)) = sampling::enable(&rings) {
+            exit_unopened(pid_child, &event, e);
+        }
+    }
+    // Notify child counters are set up.
+    if traced {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_DETACH,
+                pid_child,
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            );
+        }
+    } else {
+        writer_pipe.write_all(&[1]).unwrap();
+    }
+    drop(writer_pipe);
+
+    let mut status: libc::c_int = 0;
+    let exited = || {
+        let result = unsafe { libc::waitpid(pid_child, &mut status, libc::WNOHANG) };
+        result == pid_child
+    };
+    let written = drain_until(writer, &mut rings, exited);
+    (rings, attrs, written)
+}
+
+/// Kill the command being recorded and exit, as `event`
+/// could not be opened or started.
+fn exit_unopened(pid_child: i32, event: &StatEvent, e: OpenError) -> ! {
+    unsafe { libc::kill(pid_child, libc::SIGKILL) };
+    eprintln!("Error: could not open {}: {}", event.to_string(), e);
+    std::process::exit(1);
+}
+
+/// Write records describing the running `pids`, as though
+/// they came from the first counter.
+fn synthesize(
+    writer: &mut DataWriter<BufWriter<File>>,
+    pids: &[i32],
+    rings: &[TargetRing],
+    attrs: &[perf_event_attr],
+) {
+    let format = SampleFormat::new(&attrs[0]);
+    let id = rings
+        .first()
+        .and_then(|ring| ring.events[0].fd.id().ok())
+        .unwrap_or(0) as u64;
+    for pid in pids {
+        for record in synth::task_records(*pid, &format, id) {
+            write_or_exit(writer, &record);
+        }
+    }
+}
+
+/// Sample attached tasks until SIGINT, until `--duration`
+/// has passed, or until `exited` returns true.
+fn sample_until(
+    writer: &mut DataWriter<BufWriter<File>>,
+    options: &RecordOptions,
+    rings: &mut [TargetRing],
+    mut exited: impl FnMut() -> bool,
+) -> Written {
+    let deadline = options.duration;
+    let now = Instant::now();
+    let done = || {
+        INTERRUPTED.load(Ordering::SeqCst)
+            || matches!(deadline, Some(d) if now.elapsed() >= d)
+            || exited()
+    };
+    drain_until(writer, rings, done)
+}
+
+/// Write records from `rings` as they come in, until `done`
+/// returns true. Sampling is then stopped and the rest written.
+fn drain_until(
+    writer: &mut DataWriter<BufWriter<File>>,
+    rings: &mut [TargetRing],
+    mut done: impl FnMut() -> bool,
+) -> Written {
+    let mut written = Written::default();
+    while !done() {
+        let mut polled: Vec<(usize, libc::pollfd)> = rings
+            .iter()
+            .enumerate()
+            .filter(|(_, ring)| !ring.hung_up)
+            .map(|(i, ring)| {
+                let fd = libc::pollfd {
+                    fd: ring.events[0].fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                (i, fd)
+            })
+            .collect();
+        let mut fds: Vec<libc::pollfd> = polled.iter().map(|(_, fd)| *fd).collect();
+        if fds.is_empty() {
+            std::thread::sleep(Duration::from_millis(100));
+        } else {
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 100) };
+        }
+        for ((i, _), fd) in polled.iter_mut().zip(&fds) {
+            if fd.revents & libc::POLLHUP != 0 {
+                rings[*i].hung_up = true;
+            }
+        }
+        drain(writer, rings, &mut written);
+    }
+    for ring in rings.iter() {
+        for event in &ring.events {
+            event.fd.disable().ok();
+        }
+    }
+    drain(writer, rings, &mut written);
+    written
+}
+
+/// Write every record waiting in `rings`.
+fn drain(
+    writer: &mut DataWriter<BufWriter<File>>,
+    rings: &mut [TargetRing],
+    written: &mut Written,
+) {
+    for ring in rings.iter_mut() {
+        while let Some(record) = ring.ring.next_raw() {
+            let type_ = u32::from_ne_bytes([record[0], record[1], record[2], record[3]]);
+            if type_ == perf_event_type_PERF_RECORD_SAMPLE {
+                written.samples += 1;
+            } else if type_ == perf_event_type_PERF_RECORD_LOST {
+                let mut lost = [0; 8];
+                lost.copy_from_slice(&record[HEADER_SIZE + 8..HEADER_SIZE + 16]);
+                written.lost += u64::from_ne_bytes(lost);
+            }
+            write_or_exit(writer, &record);
+        }
+    }
+}
+
+/// Append a record to the output, exiting if it cannot be written.
+fn write_or_exit(writer: &mut DataWriter<BufWriter<File>>, record: &[u8]) {
+    if let Err(e) = writer.write_record(record) {
+        eprintln!("Could not write perf.data: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn call_graph_test() {
+    assert_eq!("fp".parse::<CallGraph>().unwrap(), CallGraph::Fp);
+    assert_eq!("lbr".parse::<CallGraph>().unwrap(), CallGraph::Lbr);
+    assert_eq!(
+        "dwarf".parse::<CallGraph>().unwrap(),
+        CallGraph::Dwarf(DEFAULT_STACK_SIZE)
+    );
+    assert_eq!(
+        "dwarf,1001".parse::<CallGraph>().unwrap(),
+        CallGraph::Dwarf(1008)
+    );
+    assert!("dwarf,70000".parse::<CallGraph>().is_err());
+    assert!("fp,16".parse::<CallGraph>().is_err());
+    assert!("stack".parse::<CallGraph>().is_err());
+}
@@ -0,0 +1,396 @@
+//! Reads and writes `perf.data` files.
+//! A file starts with a `perf_file_header`, whose sections
+//! give where the event attributes and the records are:
+//! <ul>
+//! <li><em> attrs </em>: one `perf_event_attr` per event, each
+//! followed by a section listing the IDs of its counters</li>
+//! <li><em> data </em>: the records, as read from the ring buffers</li>
+//! </ul>
+//! This is the layout `perf` itself uses, so its tools can
+//! read our files and we can read theirs. Optional feature
+//! sections after the data are not written, and are skipped
+//! when reading.
+
+use crate::bindings::*;
+use crate::event::records::{self, Record, SampleFormat, HEADER_SIZE};
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// "PERFILE2" read as a little-endian word.
+const MAGIC: u64 = 0x3245_4c49_4652_4550;
+/// The size of `perf_file_header`.
+const FILE_HEADER_SIZE: u64 = 104;
+/// The size of the header of a file written to a pipe.
+const PIPE_HEADER_SIZE: u64 = 16;
+/// The size of `perf_file_section`.
+const SECTION_SIZE: usize = 16;
+
+/// Errors reading a `perf.data` file.
+#[derive(Error, Debug)]
+pub enum DataError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("not a perf.data file")]
+    NotPerfData,
+    #[error("perf.data was written on a machine of the other byte order")]
+    ByteSwapped,
+    #[error("perf.data written to a pipe is not supported")]
+    Pipe,
+    #[error("perf.data is truncated")]
+    Truncated,
+}
+
+/// An event of a `perf.data` file and the IDs of its counters.
+#[derive(Clone)]
+pub struct FileAttr {
+    pub attr: perf_event_attr,
+    pub ids: Vec<u64>,
+}
+
+/// Writes a `perf.data` file. Records are written as they
+/// come, and the header and attributes once recording is done.
+pub struct DataWriter<W: Write + Seek> {
+    out: W,
+    data_size: u64,
+}
+
+impl<W: Write + Seek> DataWriter<W> {
+    /// Start a file on `out`, leaving room for the header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&[0; FILE_HEADER_SIZE as usize])?;
+        Ok(Self { out, data_size: 0 })
+    }
+
+    /// Append one record, header included.
+    pub fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.out.write_all(record)?;
+        self.data_size += record.len() as u64;
+        Ok(())
+    }
+
+    /// The number of bytes of records written so far.
+    pub fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// Write the attributes of `attrs` and the header.
+    pub fn finish(mut self, attrs: &[FileAttr]) -> io::Result<W> {
+        let attr_size = std::mem::size_of::<perf_event_attr>();
+        // The ID lists, then the attributes pointing at them.
+        let mut at = FILE_HEADER_SIZE + self.data_size;
+        let mut id_sections = Vec::new();
+        for attr in attrs {
+            for id in &attr.ids {
+                self.out.write_all(&id.to_ne_bytes())?;
+            }
+            let size = attr.ids.len() as u64 * 8;
+            id_sections.push((at, size));
+            at += size;
+        }
+        let attrs_offset = at;
+        for (attr, (offset, size)) in attrs.iter().zip(id_sections) {
+            self.out.write_all(attr_bytes(&attr.attr))?;
+            self.out.write_all(&offset.to_ne_bytes())?;
+            self.out.write_all(&size.to_ne_bytes())?;
+        }
+
+        let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+        let words = [
+            MAGIC,
+            FILE_HEADER_SIZE,
+            (attr_size + SECTION_SIZE) as u64,
+            attrs_offset,
+            (attrs.len() * (attr_size + SECTION_SIZE)) as u64,
+            FILE_HEADER_SIZE,
+            self.data_size,
+            // No event types, and no feature sections.
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for word in words.iter() {
+            header.extend_from_slice(&word.to_ne_bytes());
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// The bytes of `attr`, as the kernel and `perf` lay it out.
+fn attr_bytes(attr: &perf_event_attr) -> &[u8] {
+    let size = std::mem::size_of::<perf_event_attr>();
+    unsafe { std::slice::from_raw_parts(attr as *const perf_event_attr as *const u8, size) }
+}
+
+/// A `perf.data` file read into memory.
+pub struct PerfData {
+    pub attrs: Vec<FileAttr>,
+    bytes: Vec<u8>,
+    data: std::ops::Range<usize>,
+}
+
+impl PerfData {
+    /// Read the file at `path`.
+    pub fn open(path: &Path) -> Result<Self, DataError> {
+        Self::parse(fs::read(path)?)
+    }
+
+    /// Read a file from its contents.
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, DataError> {
+        // Offsets and sizes come from the file, so a corrupt one
+        // must not overflow: it points past the end of the file.
+        let offset = |base: u64, n: u64, size: u64| -> Result<u64, DataError> {
+            n.checked_mul(size)
+                .and_then(|n| n.checked_add(base))
+                .ok_or(DataError::Truncated)
+        };
+        let word = |at: u64| -> Result<u64, DataError> {
+            let at = at as usize;
+            let end = at.checked_add(8).ok_or(DataError::Truncated)?;
+            let mut word = [0; 8];
+            word.copy_from_slice(bytes.get(at..end).ok_or(DataError::Truncated)?);
+            Ok(u64::from_ne_bytes(word))
+        };
+        let magic = word(0).map_err(|_| DataError::NotPerfData)?;
+        if magic == MAGIC.swap_bytes() {
+            return Err(DataError::ByteSwapped);
+        }
+        if magic != MAGIC {
+            return Err(DataError::NotPerfData);
+        }
+        match word(8)? {
+            PIPE_HEADER_SIZE => return Err(DataError::Pipe),
+            size if size < FILE_HEADER_SIZE => return Err(DataError::NotPerfData),
+            _ => {}
+        }
+        let attr_size = word(16)?;
+        let (attrs_offset, attrs_size) = (word(24)?, word(32)?);
+        let (data_offset, data_size) = (word(40)?, word(48)?);
+        if attr_size <= SECTION_SIZE as u64 {
+            return Err(DataError::NotPerfData);
+        }
+
+        let mut attrs = Vec::new();
+        for i in 0..attrs_size / attr_size {
+            let at = offset(attrs_offset, i, attr_size)?;
+            // Newer and older tools write larger and smaller
+            // attributes; keep the fields both know of.
+            let len =
+                (attr_size as usize - SECTION_SIZE).min(std::mem::size_of::<perf_event_attr>());
+            let end = offset(at, 1, len as u64)?;
+            let src = bytes
+                .get(at as usize..end as usize)
+                .ok_or(DataError::Truncated)?;
+            let mut attr = perf_event_attr::default();
+            unsafe {
+                let dst = &mut attr as *mut perf_event_attr as *mut u8;
+                std::ptr::copy_nonoverlapping(src.as_ptr(), dst, len);
+            }
+            let ids_at = offset(at, 1, attr_size - SECTION_SIZE as u64)?;
+            let (ids_offset, ids_size) = (word(ids_at)?, word(offset(ids_at, 1, 8)?)?);
+            let ids = (0..ids_size / 8)
+                .map(|n| word(offset(ids_offset, n, 8)?))
+                .collect::<Result<Vec<u64>, DataError>>()?;
+            attrs.push(FileAttr { attr, ids });
+        }
+        if attrs.is_empty() {
+            return Err(DataError::NotPerfData);
+        }
+
+        let start = data_offset as usize;
+        let end = start
+            .checked_add(data_size as usize)
+            .ok_or(DataError::Truncated)?;
+        if end > bytes.len() {
+            return Err(DataError::Truncated);
+        }
+        Ok(Self {
+            attrs,
+            bytes,
+            data: start..end,
+        })
+    }
+
+    /// The index in `attrs` of the event with counter `id`.
+    /// Files of a single event need not carry IDs.
+    pub fn attr_index(&self, id: Option<u64>) -> usize {
+        id.and_then(|id| self.attrs.iter().position(|attr| attr.ids.contains(&id)))
+            .unwrap_or(0)
+    }
+
+    /// The raw records of the data section, headers included.
+    pub fn raw_records(&self) -> RawRecords<'_> {
+        RawRecords {
+            data: &self.bytes[self.data.clone()],
+            pos: 0,
+        }
+    }
+
+    /// Every record of the data section, decoded with the
+    /// layout of the event it came from.
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        self.raw_records()
+            .filter_map(move |raw| records::parse(raw, &self.format_of(raw)))
+    }
+
+    /// The layout of a raw record. When events differ in
+    /// layout, `perf` has them all carry an identifier at a
+    /// fixed place, so that the event can be told first.
+    fn format_of(&self, raw: &[u8]) -> SampleFormat {
+        let first = SampleFormat::new(&self.attrs[0].attr);
+        if self.attrs.len() == 1 {
+            return first;
+        }
+        let identifier = perf_event_sample_format_PERF_SAMPLE_IDENTIFIER;
+        if first.sample_type & identifier == 0 {
+            return first;
+        }
+        let type_ = u32::from_ne_bytes([raw[0], raw[1], raw[2], raw[3]]);
+        let at = if type_ == perf_event_type_PERF_RECORD_SAMPLE {
+            HEADER_SIZE
+        } else if first.sample_id_all && raw.len() >= HEADER_SIZE + 8 {
+            raw.len() - 8
+        } else {
+            return first;
+        };
+        let id = match raw.get(at..at + 8) {
+            Some(word) => {
+                let mut id = [0; 8];
+                id.copy_from_slice(word);
+                u64::from_ne_bytes(id)
+            }
+            None => return first,
+        };
+        SampleFormat::new(&self.attrs[self.attr_index(Some(id))].attr)
+    }
+}
+
+/// Iterates over the raw records of a data section.
+pub struct RawRecords<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for RawRecords<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let header = self.data.get(self.pos..self.pos + HEADER_SIZE)?;
+        let size = u16::from_ne_bytes([header[6], header[7]]) as usize;
+        if size < HEADER_SIZE {
+            return None;
+        }
+        let record = self.data.get(self.pos..self.pos + size)?;
+        self.pos += size;
+        Some(record)
+    }
+}
+
+/// A sampling attribute as `ruperf record` writes it, for tests.
+#[cfg(test)]
+fn test_attr(config: u64) -> perf_event_attr {
+    let mut attr = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config,
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IDENTIFIER
+            | perf_event_sample_format_PERF_SAMPLE_IP
+            | perf_event_sample_format_PERF_SAMPLE_TID,
+        ..Default::default()
+    };
+    attr.set_sample_id_all(1);
+    attr
+}
+
+/// A sample record of `test_attr` for counter `id`.
+#[cfg(test)]
+fn test_sample(id: u64, ip: u64) -> Vec<u8> {
+    let mut raw = Vec::new();
+    raw.extend_from_slice(&perf_event_type_PERF_RECORD_SAMPLE.to_ne_bytes());
+    raw.extend_from_slice(&(PERF_RECORD_MISC_USER as u16).to_ne_bytes());
+    raw.extend_from_slice(&32_u16.to_ne_bytes());
+    raw.extend_from_slice(&id.to_ne_bytes());
+    raw.extend_from_slice(&ip.to_ne_bytes());
+    raw.extend_from_slice(&42_u32.to_ne_bytes());
+    raw.extend_from_slice(&43_u32.to_ne_bytes());
+    raw
+}
+
+#[cfg(test)]
+#[test]
+fn perf_data_test() {
+    let attrs = vec![
+        FileAttr {
+            attr: test_attr(perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64),
+            ids: vec![10, 11],
+        },
+        FileAttr {
+            attr: test_attr(perf_sw_ids_PERF_COUNT_SW_PAGE_FAULTS as u64),
+            ids: vec![20],
+        },
+    ];
+    let mut writer = DataWriter::new(io::Cursor::new(Vec::new())).unwrap();
+    writer.write_record(&test_sample(11, 0x1000)).unwrap();
+    writer.write_record(&test_sample(20, 0x2000)).unwrap();
+    assert_eq!(writer.data_size(), 64);
+    let bytes = writer.finish(&attrs).unwrap().into_inner();
+    assert_eq!(&bytes[..8], b"PERFILE2");
+
+    let data = PerfData::parse(bytes.clone()).unwrap();
+    assert_eq!(data.attrs.len(), 2);
+    assert_eq!(data.attrs[0].ids, vec![10, 11]);
+    assert_eq!(data.attrs[1].attr.config, attrs[1].attr.config);
+    assert_eq!(data.attrs[1].attr.sample_id_all(), 1);
+    let records: Vec<Record> = data.records().collect();
+    assert_eq!(records.len(), 2);
+    match &records[1] {
+        Record::Sample(sample) => {
+            assert_eq!(sample.ip, Some(0x2000));
+            assert_eq!((sample.pid, sample.tid), (Some(42), Some(43)));
+            assert_eq!(data.attr_index(sample.id), 1);
+        }
+        other => panic!("{:?}", other),
+    }
+
+    let mut swapped = bytes.clone();
+    swapped[..8].reverse();
+    assert!(matches!(
+        PerfData::parse(swapped),
+        Err(DataError::ByteSwapped)
+    ));
+    assert!(matches!(
+        PerfData::parse(bytes[..120].to_vec()),
+        Err(DataError::Truncated)
+    ));
+    assert!(matches!(
+        PerfData::parse(b"not a perf file".to_vec()),
+        Err(DataError::NotPerfData)
+    ));
+
+    // Offsets from a corrupt header point past the end, however large.
+    let mut corrupt = bytes.clone();
+    corrupt[24..32].copy_from_slice(&u64::MAX.to_ne_bytes());
+    assert!(matches!(
+        PerfData::parse(corrupt),
+        Err(DataError::Truncated)
+    ));
+    let word = |at: usize| {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[at..at + 8]);
+        u64::from_ne_bytes(word) as usize
+    };
+    let ids_at = word(24) + word(16) - SECTION_SIZE;
+    let mut corrupt = bytes;
+    corrupt[ids_at..ids_at + 8].copy_from_slice(&(u64::MAX - 4).to_ne_bytes());
+    assert!(matches!(
+        PerfData::parse(corrupt),
+        Err(DataError::Truncated)
+    ));
+}
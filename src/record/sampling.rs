@@ -0,0 +1,268 @@
+//! Opening events for sampling, shared by `ruperf record`
+//! and `ruperf top`. Each CPU or task sampled gets one ring
+//! buffer, owned by the first event, and the other events
+//! send their records to it.
+
+use super::CallGraph;
+use crate::bindings::*;
+use crate::event::open::*;
+use crate::event::regs;
+use crate::event::ring::RingBuffer;
+use crate::stat::StatEvent;
+
+/// What each sample records.
+const SAMPLE_TYPE: u64 = perf_event_sample_format_PERF_SAMPLE_IP
+    | perf_event_sample_format_PERF_SAMPLE_TID
+    | perf_event_sample_format_PERF_SAMPLE_TIME
+    | perf_event_sample_format_PERF_SAMPLE_CPU
+    | perf_event_sample_format_PERF_SAMPLE_PERIOD;
+
+/// A task and CPU pair whose samples share one ring buffer.
+/// A `pid` of -1 samples every task on `cpu`, and a
+/// `cpu` of -1 follows `pid` onto any CPU.
+#[derive(Debug, Copy, Clone)]
+pub struct Target {
+    pub pid: i32,
+    pub cpu: i32,
+}
+
+/// The events sampled on one target. The first event owns
+/// the ring buffer, and the others send their records to it.
+pub struct TargetRing {
+    pub events: Vec<Event>,
+    pub ring: RingBuffer,
+    /// Set once the target has gone, so it is no longer polled.
+    pub hung_up: bool,
+}
+
+/// How the events are opened and started.
+pub struct Sampling {
+    /// Sample once every this many events, rather than `freq`
+    /// times a second.
+    pub period: Option<u64>,
+    pub freq: u64,
+    pub call_graph: Option<CallGraph>,
+    /// Pages of each ring buffer.
+    pub pages: usize,
+    /// Whether counters follow a command from its `exec()`,
+    /// and into every task it creates.
+    pub follow_exec: bool,
+}
+
+/// The events given with `-e`, with globs expanded, or the
+/// cycles event when none were. Also returns whether cycles
+/// was taken by default, so that it may fall back to cpu-clock.
+pub fn events_or_default(events: &[StatEvent]) -> (Vec<StatEvent>, bool) {
+    if events.is_empty() {
+        return (vec![StatEvent::Cycles], true);
+    }
+    let events = events.iter().cloned().flat_map(StatEvent::expand).collect();
+    (events, false)
+}
+
+impl Sampling {
+    /// Exit unless each ring buffer is a power of two pages,
+    /// and the period or frequency samples at all.
+    pub fn check_or_exit(&self) {
+        if !self.pages.is_power_of_two() {
+            eprintln!("--mmap-pages must be a power of two");
+            std::process::exit(1);
+        }
+        if self.freq == 0 {
+            eprintln!("-F must be greater than 0");
+            std::process::exit(1);
+        }
+        if self.period == Some(0) {
+            eprintln!("-c must be greater than 0");
+            std::process::exit(1);
+        }
+    }
+
+    /// The attributes to sample `event` with. Names and
+    /// mappings are recorded along with the first event.
+    fn attr(
+        &self,
+        event: &StatEvent,
+        first: bool,
+        several: bool,
+    ) -> Result<perf_event_attr, EventErr> {
+        let mut attr = event_open(event)?;
+        attr.read_format = 0;
+        attr.sample_type = SAMPLE_TYPE;
+        if several {
+            attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_IDENTIFIER;
+        }
+        match self.period {
+            Some(period) => attr.__bindgen_anon_1.sample_period = period,
+            None => {
+                attr.set_freq(1);
+                attr.__bindgen_anon_1.sample_freq = self.freq;
+            }
+        }
+        match self.call_graph {
+            Some(CallGraph::Fp) => {
+                attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_CALLCHAIN;
+            }
+            // The kernel still walks its own part of the chain,
+            // and the user part is unwound from the stack copy.
+            Some(CallGraph::Dwarf(size)) => {
+                attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_CALLCHAIN
+                    | perf_event_sample_format_PERF_SAMPLE_REGS_USER
+                    | perf_event_sample_format_PERF_SAMPLE_STACK_USER;
+                attr.sample_regs_user = regs::unwind_mask();
+                attr.sample_stack_user = size;
+                attr.set_exclude_callchain_user(1);
+            }
+            Some(CallGraph::Lbr) => {
+                attr.sample_type |= perf_event_sample_format_PERF_SAMPLE_CALLCHAIN
+                    | perf_event_sample_format_PERF_SAMPLE_BRANCH_STACK;
+                attr.branch_sample_type = (perf_branch_sample_type_PERF_SAMPLE_BRANCH_USER
+                    | perf_branch_sample_type_PERF_SAMPLE_BRANCH_CALL_STACK)
+                    as u64;
+                attr.set_exclude_callchain_user(1);
+            }
+            None => {}
+        }
+        attr.set_sample_id_all(1);
+        if first {
+            attr.set_mmap(1);
+            attr.set_mmap2(1);
+            attr.set_comm(1);
+            attr.set_comm_exec(1);
+            attr.set_task(1);
+        }
+        if self.follow_exec {
+            attr.set_inherit(1);
+            attr.set_enable_on_exec(1);
+        }
+        Ok(attr)
+    }
+
+    /// The attributes to sample each of `events` with.
+    fn attrs(&self, events: &[StatEvent]) -> Result<Vec<perf_event_attr>, (StatEvent, OpenError)> {
+        events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| {
+                self.attr(event, i == 0, events.len() > 1)
+                    .map_err(|e| (event.clone(), e.into()))
+            })
+            .collect()
+    }
+
+    /// Open every event on every target, with a ring buffer
+    /// for each target. Breakpoint symbols are resolved in
+    /// each target's task, as `ruperf stat` resolves them.
+    /// Returns the attributes of the events on the first target,
+    /// or the event that could not be opened or resolved.
+    fn open(
+        &self,
+        events: &[StatEvent],
+        targets: &[Target],
+    ) -> Result<(Vec<TargetRing>, Vec<perf_event_attr>), (StatEvent, OpenError)> {
+        let mut first_attrs = None;
+        let mut rings = Vec::new();
+        for target in targets {
+            let events = events
+                .iter()
+                .map(|event| {
+                    event
+                        .resolve(target.pid)
+                        .map_err(|e| (event.clone(), OpenError::Unresolved(Box::new(e))))
+                })
+                .collect::<Result<Vec<StatEvent>, _>>()?;
+            let attrs = self.attrs(&events)?;
+            let mut opened: Vec<Event> = Vec::new();
+            for (event, attr) in events.iter().zip(&attrs) {
+                let mut attr = *attr;
+                let opened_event =
+                    Event::with_attr(event.clone(), &mut attr, Some(target.pid), target.cpu)
+                        .map_err(|e| (event.clone(), e))?;
+                opened.push(opened_event);
+            }
+            let ring = RingBuffer::new(&opened[0].fd, &attrs[0], self.pages)
+                .map_err(|e| (events[0].clone(), OpenError::RingBuffer(e)))?;
+            for (event, opened_event) in events.iter().zip(&opened).skip(1) {
+                opened_event
+                    .fd
+                    .set_output(&opened[0].fd)
+                    .map_err(|e| (event.clone(), OpenError::ShareRing(e)))?;
+            }
+            rings.push(TargetRing {
+                events: opened,
+                ring,
+                hung_up: false,
+            });
+            first_attrs.get_or_insert(attrs);
+        }
+        let attrs = match first_attrs {
+            Some(attrs) => attrs,
+            None => self.attrs(events)?,
+        };
+        Ok((rings, attrs))
+    }
+
+    /// Open `events` on `targets`, falling back to cpu-clock
+    /// when the default event is not supported. Attributes the
+    /// kernel rejects are blamed on the frequency when it is
+    /// above the kernel's limit.
+    pub fn open_events(
+        &self,
+        events: &[StatEvent],
+        default_event: bool,
+        targets: &[Target],
+    ) -> Result<(Vec<TargetRing>, Vec<perf_event_attr>), (StatEvent, OpenError)> {
+        match self.open(events, targets) {
+            Err((_, OpenError::NotSupported(_))) if default_event => {
+                eprintln!("cycles is not supported here, sampling cpu-clock instead");
+                self.open(&[StatEvent::CpuClock], targets)
+            }
+            Err((event, OpenError::InvalidAttr(ctx))) => {
+                match (self.period, perf_event_max_sample_rate()) {
+                    (None, Some(max)) if self.freq > max => Err((
+                        event,
+                        OpenError::SampleRate {
+                            freq: self.freq,
+                            max,
+                        },
+                    )),
+                    _ => Err((event, OpenError::InvalidAttr(ctx))),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Open and start `events` on `targets`, as `open_events`
+    /// does. Exits on errors.
+    pub fn open_or_exit(
+        &self,
+        events: &[StatEvent],
+        default_event: bool,
+        targets: &[Target],
+    ) -> (Vec<TargetRing>, Vec<perf_event_attr>) {
+        let opened = self.open_events(events, default_event, targets);
+        let opened = opened.and_then(|(rings, attrs)| {
+            enable(&rings)?;
+            Ok((rings, attrs))
+        });
+        opened.unwrap_or_else(|(event, e)| {
+            eprintln!("Error: could not open {}: {}", event.to_string(), e);
+            std::process::exit(1);
+        })
+    }
+}
+
+/// Start sampling on every target, or return
+/// the first event that could not be started.
+pub fn enable(rings: &[TargetRing]) -> Result<(), (StatEvent, OpenError)> {
+    for ring in rings {
+        for event in &ring.events {
+            event
+                .fd
+                .enable()
+                .map_err(|e| (event.event.clone(), OpenError::Enable(e)))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Records for tasks that were running before recording
+//! began. The kernel only reports names and mappings as they
+//! change, so, like `perf`, we describe the tasks already there
+//! from `/proc`, as `COMM` and `MMAP2` records of our own.
+
+use crate::bindings::*;
+use crate::event::records::{SampleFormat, HEADER_SIZE};
+use std::fs;
+
+/// Builds a record from its fields.
+struct Builder {
+    bytes: Vec<u8>,
+}
+
+impl Builder {
+    fn new(type_: u32, misc: u16) -> Self {
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&type_.to_ne_bytes());
+        bytes.extend_from_slice(&misc.to_ne_bytes());
+        bytes.extend_from_slice(&0_u16.to_ne_bytes());
+        Self { bytes }
+    }
+
+    fn u32(mut self, x: u32) -> Self {
+        self.bytes.extend_from_slice(&x.to_ne_bytes());
+        self
+    }
+
+    fn u64(mut self, x: u64) -> Self {
+        self.bytes.extend_from_slice(&x.to_ne_bytes());
+        self
+    }
+
+    /// A NUL-terminated string, padded to 8 bytes.
+    fn string(mut self, s: &str) -> Self {
+        self.bytes.extend_from_slice(s.as_bytes());
+        let len = (s.len() + 1 + 7) & !7;
+        self.bytes.resize(self.bytes.len() + len - s.len(), 0);
+        self
+    }
+
+    /// The `SampleId` the kernel would append for `pid`, `tid`
+    /// and counter `id`, then the finished record.
+    fn finish(mut self, format: &SampleFormat, pid: u32, tid: u32, id: u64) -> Vec<u8> {
+        if format.sample_id_all {
+            let has = |bit| format.sample_type & bit != 0;
+            if has(perf_event_sample_format_PERF_SAMPLE_TID) {
+                self = self.u32(pid).u32(tid);
+            }
+            if has(perf_event_sample_format_PERF_SAMPLE_TIME) {
+                self = self.u64(0);
+            }
+            if has(perf_event_sample_format_PERF_SAMPLE_ID) {
+                self = self.u64(id);
+            }
+            if has(perf_event_sample_format_PERF_SAMPLE_STREAM_ID) {
+                self = self.u64(id);
+            }
+            if has(perf_event_sample_format_PERF_SAMPLE_CPU) {
+                self = self.u32(0).u32(0);
+            }
+            if has(perf_event_sample_format_PERF_SAMPLE_IDENTIFIER) {
+                self = self.u64(id);
+            }
+        }
+        let size = self.bytes.len() as u16;
+        self.bytes[HEADER_SIZE - 2..HEADER_SIZE].copy_from_slice(&size.to_ne_bytes());
+        self.bytes
+    }
+}
+
+/// One executable mapping of `/proc/PID/maps`.
+#[derive(Debug, PartialEq, Eq)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    pgoff: u64,
+    maj: u32,
+    min: u32,
+    ino: u64,
+    prot: u32,
+    flags: u32,
+    filename: String,
+}
+
+/// Parse a line of `/proc/PID/maps`, keeping executable
+/// mappings only, since samples can only land in those.
+fn parse_mapping(line: &str) -> Option<Mapping> {
+    // start-end perms offset maj:min inode [path]
+    let mut fields = line.splitn(6, char::is_whitespace);
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?.as_bytes();
+    let pgoff = fields.next()?;
+    let (maj, min) = fields.next()?.split_once(':')?;
+    let ino = fields.next()?;
+    if perms.len() < 4 || perms[2] != b'x' {
+        return None;
+    }
+    let filename = match fields.next().map(str::trim) {
+        Some(path) if !path.is_empty() => path.to_string(),
+        _ => "//anon".to_string(),
+    };
+    let bit = |at: usize, c: u8, prot: i32| if perms[at] == c { prot as u32 } else { 0 };
+    Some(Mapping {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        pgoff: u64::from_str_radix(pgoff, 16).ok()?,
+        maj: u32::from_str_radix(maj, 16).ok()?,
+        min: u32::from_str_radix(min, 16).ok()?,
+        ino: ino.parse().ok()?,
+        prot: bit(0, b'r', libc::PROT_READ)
+            | bit(1, b'w', libc::PROT_WRITE)
+            | bit(2, b'x', libc::PROT_EXEC),
+        flags: match perms[3] {
+            b'p' => libc::MAP_PRIVATE as u32,
+            _ => libc::MAP_SHARED as u32,
+        },
+        filename,
+    })
+}
+
+/// `COMM` records for every thread of process `pid`, and
+/// `MMAP2` records for its executable mappings. Records end
+/// in a `SampleId` for counter `id` when `format` has them.
+/// A task that exits meanwhile yields what was read of it.
+pub fn task_records(pid: i32, format: &SampleFormat, id: u64) -> Vec<Vec<u8>> {
+    let mut records = Vec::new();
+    let tids = crate::stat::target::threads_of(pid);
+    for tid in tids {
+        let comm = match fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)) {
+            Ok(comm) => comm,
+            Err(_) => continue,
+        };
+        let record = Builder::new(perf_event_type_PERF_RECORD_COMM, 0)
+            .u32(pid as u32)
+            .u32(tid as u32)
+            .string(comm.trim_end_matches('\n'))
+            .finish(format, pid as u32, tid as u32, id);
+        records.push(record);
+    }
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    for map in maps.lines().filter_map(parse_mapping) {
+        let record = Builder::new(
+            perf_event_type_PERF_RECORD_MMAP2,
+            PERF_RECORD_MISC_USER as u16,
+        )
+        .u32(pid as u32)
+        .u32(pid as u32)
+        .u64(map.start)
+        .u64(map.end - map.start)
+        .u64(map.pgoff)
+        .u32(map.maj)
+        .u32(map.min)
+        .u64(map.ino)
+        .u64(0)
+        .u32(map.prot)
+        .u32(map.flags)
+        .string(&map.filename)
+        .finish(format, pid as u32, pid as u32, id);
+        records.push(record);
+    }
+    records
+}
+
+/// Every process on the system.
+pub fn all_processes() -> Vec<i32> {
+    let mut pids: Vec<i32> = match fs::read_dir("/proc") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    pids.sort_unstable();
+    pids
+}
+
+#[cfg(test)]
+#[test]
+fn parse_mapping_test() {
+    let line = "55d4c0a00000-55d4c0a21000 r-xp 00002000 fd:01 1316 /usr/bin/my prog";
+    assert_eq!(
+        parse_mapping(line),
+        Some(Mapping {
+            start: 0x55d4_c0a0_0000,
+            end: 0x55d4_c0a2_1000,
+            pgoff: 0x2000,
+            maj: 0xfd,
+            min: 1,
+            ino: 1316,
+            prot: (libc::PROT_READ | libc::PROT_EXEC) as u32,
+            flags: libc::MAP_PRIVATE as u32,
+            filename: "/usr/bin/my prog".to_string(),
+        })
+    );
+    let anon = parse_mapping("7f0000000000-7f0000001000 rwxs 00000000 00:00 0 ").unwrap();
+    assert_eq!(anon.filename, "//anon");
+    assert_eq!(anon.flags, libc::MAP_SHARED as u32);
+    assert_eq!(
+        parse_mapping("7f0000000000-7f0000001000 rw-p 00000000 00:00 0 [heap]"),
+        None
+    );
+}
+
+#[test]
+fn task_records_test() {
+    use crate::event::records::{self, Record};
+    let format = SampleFormat {
+        sample_type: perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_IDENTIFIER,
+        sample_id_all: true,
+        ..Default::default()
+    };
+    let pid = std::process::id();
+    let exe = fs::read_link("/proc/self/exe").unwrap();
+    let records: Vec<Record> = task_records(pid as i32, &format, 7)
+        .iter()
+        .map(|raw| records::parse(raw, &format).unwrap())
+        .collect();
+    assert!(records.iter().any(|r| matches!(r, Record::Comm(c)
+        if c.pid == pid && c.sample_id.id == Some(7))));
+    assert!(records.iter().any(|r| matches!(r, Record::Mmap2(m)
+        if m.pid == pid && m.filename == exe.to_str().unwrap())));
+}
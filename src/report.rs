@@ -0,0 +1,560 @@
+//! # Report driver.
+//! <p> Usage: <em> ruperf report [-i FILE] [--sort KEYS] [--no-demangle | --strip-hashes]
+//! [--no-inline] [--stdio | --json] </em>
+//! Reads the samples of <em> perf.data </em>, or of FILE, and
+//! prints how they spread over commands, files and functions. </p>
+//! <p> Names and mappings are replayed in time order to place
+//! each sample in its task's address space, and addresses are
+//! resolved to functions through the ELF symbol tables of the
+//! mapped files. Samples are grouped by the comma-separated
+//! KEYS, any of <em> comm, pid, dso, sym </em>. </p>
+//! <p> Samples recorded with call chains also count towards
+//! every function they passed through: its <em> Children </em>
+//! share, next to the <em> Self </em> share of samples in the
+//! function itself. Each function is followed by the tree of
+//! its callees, or of its callers with <em> -g callee </em>. </p>
+//! <p> Rust and C++ names are demangled, and samples in code
+//! inlined from other functions are placed in those functions,
+//! called from the one they were inlined into, as far as the
+//! files' debugging information tells. </p>
+
+pub(crate) mod callchain;
+pub(crate) mod demangle;
+pub(crate) mod machine;
+mod output;
+pub(crate) mod symbols;
+mod unwind;
+
+extern crate structopt;
+use crate::bindings::*;
+use crate::event::catalog;
+use crate::event::records::Record;
+use crate::record::data::PerfData;
+use crate::utils::ParseError;
+use callchain::CallNode;
+use demangle::Demangle;
+use machine::{Location, Machine};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// A column samples are grouped by.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SortKey {
+    Comm,
+    Pid,
+    Dso,
+    Sym,
+}
+
+impl FromStr for SortKey {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "comm" => Ok(SortKey::Comm),
+            "pid" => Ok(SortKey::Pid),
+            "dso" => Ok(SortKey::Dso),
+            "sym" => Ok(SortKey::Sym),
+            key => Err(ParseError::InvalidSortKey(key.to_string())),
+        }
+    }
+}
+
+/// The columns given with `--sort`, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKeys(pub Vec<SortKey>);
+
+impl FromStr for SortKeys {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for key in s.split(',') {
+            let key = key.parse::<SortKey>()?;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        Ok(SortKeys(keys))
+    }
+}
+
+/// Which way call trees go from the function they are under.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CallOrder {
+    /// Down to the functions it called.
+    Caller,
+    /// Up to the functions that called it.
+    Callee,
+}
+
+impl FromStr for CallOrder {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "caller" => Ok(CallOrder::Caller),
+            "callee" => Ok(CallOrder::Callee),
+            _ => Err(ParseError::InvalidCallOrder(s.to_string())),
+        }
+    }
+}
+
+/// How functions are named and inlined code is placed,
+/// as `report` and `top` both show them.
+#[derive(Debug, StructOpt)]
+pub struct SymbolOptions {
+    #[structopt(long, help = "Show function names as they are in the symbol tables")]
+    pub no_demangle: bool,
+
+    #[structopt(
+        long,
+        help = "Leave the hashes out of demangled Rust names",
+        conflicts_with = "no-demangle"
+    )]
+    pub strip_hashes: bool,
+
+    #[structopt(
+        long,
+        help = "Count samples in inlined code towards the enclosing function"
+    )]
+    pub no_inline: bool,
+}
+
+impl SymbolOptions {
+    /// How function names are shown.
+    pub fn demangle(&self) -> Demangle {
+        if self.no_demangle {
+            Demangle::Off
+        } else if self.strip_hashes {
+            Demangle::NoHash
+        } else {
+            Demangle::On
+        }
+    }
+
+    /// A machine to replay records in, naming functions this way.
+    pub fn machine(&self) -> Machine {
+        Machine::new(self.demangle(), !self.no_inline)
+    }
+}
+
+/// Break samples down by command, file and function.
+/// See `./ruperf report --help' for more information.
+#[derive(Debug, StructOpt)]
+pub struct ReportOptions {
+    #[structopt(
+        short,
+        long,
+        help = "Read samples from FILE",
+        value_name = "FILE",
+        default_value = "perf.data",
+        parse(from_os_str)
+    )]
+    pub input: PathBuf,
+
+    #[structopt(
+        short,
+        long,
+        help = "Group samples by these keys: comm, pid, dso, sym",
+        value_name = "KEYS",
+        default_value = "comm,dso,sym"
+    )]
+    pub sort: SortKeys,
+
+    #[structopt(
+        short = "g",
+        long,
+        help = "Show call trees towards callees (caller) or callers (callee)",
+        value_name = "ORDER"
+    )]
+    pub call_graph: Option<CallOrder>,
+
+    #[structopt(long, help = "Count samples towards their own function only")]
+    pub no_children: bool,
+
+    #[structopt(flatten)]
+    pub symbols: SymbolOptions,
+
+    #[structopt(long, help = "Print the report as text", conflicts_with = "json")]
+    pub stdio: bool,
+
+    #[structopt(long, help = "Print the report as JSON")]
+    pub json: bool,
+}
+
+/// The values of the `--sort` columns samples are grouped by.
+/// Columns not sorted by are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntryKey {
+    pub comm: Option<String>,
+    pub pid: Option<u32>,
+    /// The file name, or `None` when grouped by file but
+    /// the sample landed outside any known mapping.
+    pub dso: Option<Option<String>>,
+    /// The function, or its address if it is not known,
+    /// and whether it is in the kernel.
+    pub symbol: Option<(bool, String)>,
+}
+
+impl EntryKey {
+    /// The columns of `keys` for a sample at `location`.
+    pub(crate) fn new(keys: &SortKeys, location: &Location) -> Self {
+        let mut key = EntryKey::default();
+        for column in &keys.0 {
+            match column {
+                SortKey::Comm => key.comm = Some(location.comm.clone()),
+                SortKey::Pid => key.pid = Some(location.pid),
+                SortKey::Dso => key.dso = Some(location.dso.clone()),
+                SortKey::Sym => key.symbol = Some((location.kernel, location.name())),
+            }
+        }
+        key
+    }
+}
+
+/// Samples that share an `EntryKey`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub key: EntryKey,
+    pub samples: u64,
+    /// The sum of the sample periods.
+    pub period: u64,
+    /// The sum of the periods of samples whose call
+    /// chains pass through the entry, its own included.
+    pub children: u64,
+    /// The calls that led to the samples.
+    pub call_graph: Vec<CallNode>,
+}
+
+/// The samples of one event.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    pub event: String,
+    pub samples: u64,
+    pub period: u64,
+    /// Samples the kernel dropped for lack of ring buffer space.
+    pub lost: u64,
+    /// Whether entries count the samples of their callees.
+    pub children: bool,
+    /// Sorted by period, or by children, largest first.
+    pub entries: Vec<Entry>,
+}
+
+impl Histogram {
+    /// The share of the event's count taken by `entry`, in percent.
+    pub fn overhead(&self, entry: &Entry) -> f64 {
+        self.percent(entry.period)
+    }
+
+    /// `period` as a share of the event's count, in percent.
+    pub fn percent(&self, period: u64) -> f64 {
+        if self.period == 0 {
+            0.0
+        } else {
+            period as f64 * 100.0 / self.period as f64
+        }
+    }
+}
+
+/// The time of a record, if it carries one.
+fn time_of(record: &Record) -> Option<u64> {
+    match record {
+        Record::Sample(sample) => sample.time,
+        Record::Mmap(mmap) => mmap.sample_id.time,
+        Record::Mmap2(mmap) => mmap.sample_id.time,
+        Record::Comm(comm) => comm.sample_id.time,
+        Record::Fork(task) | Record::Exit(task) => Some(task.time),
+        Record::Lost(lost) => lost.sample_id.time,
+        Record::Throttle(throttle) | Record::Unthrottle(throttle) => Some(throttle.time),
+        Record::Other(_) => None,
+    }
+}
+
+/// The records of `data` in time order. Records without a
+/// time keep their place after the record before them.
+pub(crate) fn sorted_records(data: &PerfData) -> Vec<Record> {
+    let mut last = 0;
+    let mut records: Vec<(u64, Record)> = data
+        .records()
+        .map(|record| {
+            last = time_of(&record).unwrap_or(last);
+            (last, record)
+        })
+        .collect();
+    records.sort_by_key(|(time, _)| *time);
+    records.into_iter().map(|(_, record)| record).collect()
+}
+
+/// The name of the event recorded with `attr`.
+pub(crate) fn event_name(attr: &perf_event_attr) -> String {
+    match catalog::event_of(attr.type_, attr.config) {
+        Some(event) => catalog::name(&event),
+        None => format!("{}:{:#x}", attr.type_, attr.config),
+    }
+}
+
+/// Group the samples of `data` by the keys of `options`,
+/// one histogram per event.
+pub fn histograms(data: &PerfData, options: &ReportOptions) -> Vec<Histogram> {
+    let mut machine = options.symbols.machine();
+    let mut groups: Vec<HashMap<EntryKey, Entry>> = vec![HashMap::new(); data.attrs.len()];
+    let mut histograms: Vec<Histogram> = data
+        .attrs
+        .iter()
+        .map(|attr| Histogram {
+            event: event_name(&attr.attr),
+            children: !options.no_children
+                && attr.attr.sample_type & perf_event_sample_format_PERF_SAMPLE_CALLCHAIN != 0,
+            ..Default::default()
+        })
+        .collect();
+    for record in sorted_records(data) {
+        match &record {
+            Record::Sample(sample) => {
+                let index = data.attr_index(sample.id);
+                let regs_mask = data.attrs[index].attr.sample_regs_user;
+                let histogram = &mut histograms[index];
+                let period = sample.period.unwrap_or(1);
+                histogram.samples += 1;
+                histogram.period += period;
+                let locations: Vec<Location> = callchain::frames(&mut machine, sample, regs_mask)
+                    .iter()
+                    .flat_map(|frame| machine.locate(sample, frame))
+                    .collect();
+                add_sample(
+                    &mut groups[index],
+                    histogram.children,
+                    options,
+                    &locations,
+                    period,
+                );
+            }
+            Record::Lost(lost) => {
+                histograms[data.attr_index(lost.sample_id.id)].lost += lost.lost;
+            }
+            _ => machine.apply(&record),
+        }
+    }
+    for (histogram, group) in histograms.iter_mut().zip(groups) {
+        histogram.entries = group.into_values().collect();
+        for entry in &mut histogram.entries {
+            callchain::sort(&mut entry.call_graph);
+        }
+        let children = histogram.children;
+        histogram.entries.sort_by(|a, b| {
+            let order = if children {
+                b.children.cmp(&a.children)
+            } else {
+                std::cmp::Ordering::Equal
+            };
+            order
+                .then_with(|| b.period.cmp(&a.period))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+    }
+    histograms
+}
+
+/// Count a sample of `period` whose call chain is `locations`,
+/// sampled function first, towards the entries of `group`.
+/// With `children` every function in the chain is counted.
+fn add_sample(
+    group: &mut HashMap<EntryKey, Entry>,
+    children: bool,
+    options: &ReportOptions,
+    locations: &[Location],
+    period: u64,
+) {
+    let keys: Vec<EntryKey> = locations
+        .iter()
+        .map(|location| EntryKey::new(&options.sort, location))
+        .collect();
+    let names: Vec<String> = locations.iter().map(Location::name).collect();
+    // Without children there is nothing below a sampled
+    // function, so its callers are shown instead.
+    let order = match options.call_graph {
+        Some(order) => order,
+        None if children => CallOrder::Caller,
+        None => CallOrder::Callee,
+    };
+    for (i, key) in keys.iter().enumerate() {
+        // Functions that recur in the chain count once.
+        if keys[..i].contains(key) {
+            continue;
+        }
+        if i > 0 && !children {
+            break;
+        }
+        let entry = group.entry(key.clone()).or_insert_with(|| Entry {
+            key: key.clone(),
+            ..Default::default()
+        });
+        if i == 0 {
+            entry.samples += 1;
+            entry.period += period;
+        }
+        entry.children += period;
+        let path: Vec<String> = match order {
+            CallOrder::Caller => names[..i].iter().rev().cloned().collect(),
+            CallOrder::Callee => names[i + 1..].to_vec(),
+        };
+        callchain::add_path(&mut entry.call_graph, &path, period);
+    }
+}
+
+pub fn run_report(options: ReportOptions) {
+    let data = match PerfData::open(&options.input) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", options.input.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let histograms = histograms(&data, &options);
+    let mut out = std::io::stdout();
+    // Text is the default, there being no interactive browser.
+    let result = if options.stdio || !options.json {
+        output::print_stdio(&mut out, &options, &histograms)
+    } else {
+        output::print_json(&mut out, &options, &histograms)
+    };
+    // A reader such as `head` may stop early.
+    if let Err(e) = result {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            eprintln!("Could not print the report: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn sort_keys_test() {
+    let keys: SortKeys = "dso, sym,dso".parse().unwrap();
+    assert_eq!(keys.0, vec![SortKey::Dso, SortKey::Sym]);
+    assert!("comm,size".parse::<SortKeys>().is_err());
+    assert!("".parse::<SortKeys>().is_err());
+}
+
+#[test]
+fn histograms_test() {
+    use crate::record::data::{DataWriter, FileAttr};
+    use crate::record::synth::task_records;
+    use std::io::Cursor;
+    let attr = perf_event_attr {
+        type_: perf_type_id_PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<perf_event_attr>() as u32,
+        config: perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK as u64,
+        sample_type: perf_event_sample_format_PERF_SAMPLE_IP
+            | perf_event_sample_format_PERF_SAMPLE_TID
+            | perf_event_sample_format_PERF_SAMPLE_PERIOD,
+        ..Default::default()
+    };
+    let format = crate::event::records::SampleFormat::new(&attr);
+    let pid = std::process::id();
+    let sample = |ip: u64, period: u64| {
+        let mut record = Vec::new();
+        record.extend_from_slice(&perf_event_type_PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&(PERF_RECORD_MISC_USER as u16).to_ne_bytes());
+        record.extend_from_slice(&32_u16.to_ne_bytes());
+        record.extend_from_slice(&ip.to_ne_bytes());
+        record.extend_from_slice(&pid.to_ne_bytes());
+        record.extend_from_slice(&pid.to_ne_bytes());
+        record.extend_from_slice(&period.to_ne_bytes());
+        record
+    };
+    let function = symbols::ruperf_report_test_function as extern "C" fn() -> u64 as usize as u64;
+    let mut writer = DataWriter::new(Cursor::new(Vec::new())).unwrap();
+    for record in task_records(pid as i32, &format, 0) {
+        writer.write_record(&record).unwrap();
+    }
+    writer.write_record(&sample(function, 3)).unwrap();
+    writer.write_record(&sample(function + 1, 1)).unwrap();
+    writer.write_record(&sample(0x10, 1)).unwrap();
+    let file = writer.finish(&[FileAttr { attr, ids: vec![] }]).unwrap();
+    let data = PerfData::parse(file.into_inner()).unwrap();
+
+    let histograms = histograms(&data, &ReportOptions::from_iter(&["report"]));
+    assert_eq!(histograms.len(), 1);
+    let histogram = &histograms[0];
+    assert_eq!(
+        (
+            histogram.event.as_str(),
+            histogram.samples,
+            histogram.period
+        ),
+        ("cpu-clock", 3, 5)
+    );
+    let top = &histogram.entries[0];
+    assert_eq!((top.samples, top.period), (2, 4));
+    assert_eq!(histogram.overhead(top), 80.0);
+    assert_eq!(
+        top.key.symbol,
+        Some((false, "ruperf_report_test_function".to_string()))
+    );
+    let exe = std::env::current_exe().unwrap();
+    assert_eq!(
+        top.key.dso,
+        Some(exe.file_name().unwrap().to_str().map(String::from))
+    );
+    assert_eq!(histogram.entries[1].key.dso, Some(None));
+    assert_eq!(
+        histogram.entries[1].key.symbol,
+        Some((false, "0x0000000000000010".to_string()))
+    );
+}
+
+#[test]
+fn add_sample_test() {
+    let options = ReportOptions::from_iter(&["report", "--sort", "sym"]);
+    let location = |name: &str| Location {
+        pid: 1,
+        comm: "prog".to_string(),
+        dso: Some("prog".to_string()),
+        symbol: Some(name.to_string()),
+        ip: 0,
+        kernel: false,
+    };
+    let chain = |names: &[&str]| -> Vec<Location> { names.iter().map(|n| location(n)).collect() };
+    let mut group = HashMap::new();
+    add_sample(
+        &mut group,
+        true,
+        &options,
+        &chain(&["leaf", "mid", "main"]),
+        3,
+    );
+    add_sample(&mut group, true, &options, &chain(&["main"]), 1);
+    // Recursion counts once.
+    add_sample(
+        &mut group,
+        true,
+        &options,
+        &chain(&["mid", "mid", "main"]),
+        2,
+    );
+
+    let entry = |name: &str| &group[&EntryKey::new(&options.sort, &location(name))];
+    assert_eq!((entry("main").period, entry("main").children), (1, 6));
+    assert_eq!((entry("mid").period, entry("mid").children), (2, 5));
+    assert_eq!((entry("leaf").period, entry("leaf").children), (3, 3));
+    // From main down to its callees.
+    let main = &entry("main").call_graph;
+    assert_eq!((main[0].name.as_str(), main[0].period), ("mid", 5));
+    assert_eq!(main[0].children[0].name, "leaf");
+
+    let options = ReportOptions::from_iter(&["report", "--sort", "sym", "--no-children"]);
+    let mut group = HashMap::new();
+    add_sample(
+        &mut group,
+        false,
+        &options,
+        &chain(&["leaf", "mid", "main"]),
+        3,
+    );
+    assert_eq!(group.len(), 1);
+    // From leaf up to its callers.
+    let leaf = &group[&EntryKey::new(&options.sort, &location("leaf"))];
+    assert_eq!(leaf.call_graph[0].name, "mid");
+    assert_eq!(leaf.call_graph[0].children[0].name, "main");
+}
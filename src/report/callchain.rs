@@ -0,0 +1,164 @@
+//! Call chains of samples, and the trees they add up to.
+//! A sample's chain comes from the kernel's walk of frame
+//! pointers, from the user stack copied with it, which is
+//! unwound here, or from its last branch records. The kernel
+//! marks where its own frames end and the user's begin with
+//! `PERF_CONTEXT_*` entries.
+
+use super::machine::Machine;
+use super::unwind;
+use crate::bindings::*;
+use crate::event::records::Sample;
+
+/// One function in a call chain.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// An address in the function. For callers this is just
+    /// before the return address, which may already be in
+    /// the next function.
+    pub ip: u64,
+    pub kernel: bool,
+}
+
+/// The frames of `sample`, the sampled function first. User
+/// stacks are unwound with the registers sampled with
+/// `regs_mask`. A sample without a chain has one frame.
+pub fn frames(machine: &mut Machine, sample: &Sample, regs_mask: u64) -> Vec<Frame> {
+    let cpumode = sample.misc as u32 & PERF_RECORD_MISC_CPUMODE_MASK;
+    let sampled = Frame {
+        ip: sample.ip.unwrap_or(0),
+        kernel: cpumode == PERF_RECORD_MISC_KERNEL,
+    };
+    let mut kernel = sampled.kernel;
+    let mut chain = Vec::new();
+    for ip in &sample.callchain {
+        if *ip >= perf_callchain_context_PERF_CONTEXT_MAX {
+            kernel = *ip == perf_callchain_context_PERF_CONTEXT_KERNEL;
+            continue;
+        }
+        chain.push(Frame { ip: *ip, kernel });
+    }
+
+    // Without user frames in the callchain, they are unwound
+    // from the stack copy or read from the branch records.
+    // Those hold the calls themselves, which are made to look
+    // like the return addresses of other chains.
+    let user: Vec<u64> = match &sample.regs_user {
+        Some(regs) if !sample.stack_user.is_empty() => {
+            let pid = sample.pid.unwrap_or(0);
+            unwind::unwind(machine, pid, regs, regs_mask, &sample.stack_user)
+        }
+        _ if !sample.branches.is_empty() => (!sampled.kernel)
+            .then_some(sampled.ip)
+            .into_iter()
+            .chain(sample.branches.iter().map(|branch| branch.from + 1))
+            .collect(),
+        _ => Vec::new(),
+    };
+    chain.extend(user.iter().map(|ip| Frame {
+        ip: *ip,
+        kernel: false,
+    }));
+    if chain.first() != Some(&sampled) {
+        chain.insert(0, sampled);
+    }
+    for frame in chain.iter_mut().skip(1) {
+        frame.ip = frame.ip.saturating_sub(1);
+    }
+    chain
+}
+
+/// A function in a call tree, and the period of the
+/// samples whose chains pass through it there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallNode {
+    pub name: String,
+    pub period: u64,
+    /// Sorted by period once the tree is complete.
+    pub children: Vec<CallNode>,
+}
+
+/// Add `period` along `path` to the trees `nodes`.
+pub fn add_path(nodes: &mut Vec<CallNode>, path: &[String], period: u64) {
+    let (name, rest) = match path.split_first() {
+        Some(first) => first,
+        None => return,
+    };
+    let at = match nodes.iter().position(|node| node.name == *name) {
+        Some(at) => at,
+        None => {
+            nodes.push(CallNode {
+                name: name.clone(),
+                ..Default::default()
+            });
+            nodes.len() - 1
+        }
+    };
+    nodes[at].period += period;
+    add_path(&mut nodes[at].children, rest, period);
+}
+
+/// Sort `nodes` and their children by period, largest first.
+pub fn sort(nodes: &mut Vec<CallNode>) {
+    nodes.sort_by(|a, b| b.period.cmp(&a.period).then_with(|| a.name.cmp(&b.name)));
+    for node in nodes {
+        sort(&mut node.children);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn frames_test() {
+    let mut machine = Machine::default();
+    let sample = Sample {
+        misc: PERF_RECORD_MISC_KERNEL as u16,
+        ip: Some(0xffff_0010),
+        callchain: vec![
+            perf_callchain_context_PERF_CONTEXT_KERNEL,
+            0xffff_0010,
+            0xffff_0100,
+            perf_callchain_context_PERF_CONTEXT_USER,
+            0x4000,
+        ],
+        ..Default::default()
+    };
+    let frame = |ip, kernel| Frame { ip, kernel };
+    assert_eq!(
+        frames(&mut machine, &sample, 0),
+        vec![
+            frame(0xffff_0010, true),
+            frame(0xffff_00ff, true),
+            frame(0x3fff, false)
+        ]
+    );
+
+    // Call stack branch records hold the calls, newest first.
+    let lbr = Sample {
+        misc: PERF_RECORD_MISC_USER as u16,
+        ip: Some(0x4100),
+        branches: vec![crate::event::records::BranchEntry {
+            from: 0x4010,
+            to: 0x4100,
+            flags: 0,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        frames(&mut machine, &lbr, 0),
+        vec![frame(0x4100, false), frame(0x4010, false)]
+    );
+}
+
+#[test]
+fn add_path_test() {
+    let path = |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+    let mut nodes = Vec::new();
+    add_path(&mut nodes, &path(&["a", "b"]), 1);
+    add_path(&mut nodes, &path(&["a", "c"]), 3);
+    add_path(&mut nodes, &path(&["d"]), 2);
+    sort(&mut nodes);
+    assert_eq!(nodes[0].name, "a");
+    assert_eq!(nodes[0].period, 4);
+    assert_eq!(nodes[0].children[0].name, "c");
+    assert_eq!(nodes[1].period, 2);
+}
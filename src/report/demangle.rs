@@ -0,0 +1,71 @@
+//! Readable names for mangled symbols.
+//! Rust symbols come mangled in the legacy scheme,
+//! `_ZN...17h<hash>E`, or in the v0 scheme, `_R...`, and
+//! C++ symbols in the Itanium scheme, `_Z...`. Legacy Rust
+//! names are also valid Itanium names, so Rust is tried first.
+//! Names in none of these schemes, as in C, are kept.
+
+use cpp_demangle::{DemangleOptions, Symbol};
+use std::fmt::Write;
+
+/// How symbol names are shown.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Demangle {
+    /// As they are in the symbol table.
+    Off,
+    /// Demangled.
+    #[default]
+    On,
+    /// Demangled, leaving out the hashes of Rust names and
+    /// the disambiguators of crates.
+    NoHash,
+}
+
+impl Demangle {
+    /// The name shown for the symbol `name`.
+    pub fn name(self, name: &str) -> String {
+        if self == Demangle::Off {
+            return name.to_string();
+        }
+        if let Ok(rust) = rustc_demangle::try_demangle(name) {
+            let mut demangled = String::new();
+            let written = if self == Demangle::NoHash {
+                write!(demangled, "{:#}", rust)
+            } else {
+                write!(demangled, "{}", rust)
+            };
+            if written.is_ok() {
+                return demangled;
+            }
+        }
+        if name.starts_with("_Z") {
+            if let Ok(symbol) = Symbol::new(name) {
+                if let Ok(demangled) = symbol.demangle(&DemangleOptions::default()) {
+                    return demangled;
+                }
+            }
+        }
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn demangle_test() {
+    let legacy = "_ZN4prog4leaf17h9ec7b37d53e3a6e0E";
+    assert_eq!(Demangle::On.name(legacy), "prog::leaf::h9ec7b37d53e3a6e0");
+    assert_eq!(Demangle::NoHash.name(legacy), "prog::leaf");
+    assert_eq!(Demangle::Off.name(legacy), legacy);
+
+    let v0 = "_RNvNtCsjrHSEGnQ3l9_3std2rt19lang_start_internal";
+    assert_eq!(Demangle::NoHash.name(v0), "std::rt::lang_start_internal");
+    assert!(Demangle::On.name(v0).starts_with("std["));
+
+    assert_eq!(
+        Demangle::On.name("_ZNSt6vectorIiSaIiEE9push_backERKi"),
+        "std::vector<int, std::allocator<int> >::push_back(int const&)"
+    );
+    assert_eq!(Demangle::On.name("_ZN3foo3barEv"), "foo::bar()");
+    assert_eq!(Demangle::NoHash.name("malloc"), "malloc");
+    assert_eq!(Demangle::On.name("_Zinvalid"), "_Zinvalid");
+}
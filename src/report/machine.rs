@@ -0,0 +1,351 @@
+//! Rebuilds the tasks of a recording from its records.
+//! `COMM`, `MMAP`/`MMAP2` and `FORK` records are
+//! replayed in time order, so that each sample can be
+//! placed in the name and address space its task had
+//! at the time it was taken. Function names are shown
+//! demangled, and functions inlined where a sample landed
+//! are found in the debugging information of its file.
+
+use super::callchain::Frame;
+use super::demangle::Demangle;
+use super::symbols::{Dso, KERNEL_NAME};
+use super::unwind::Step;
+use crate::event::records::{Record, Sample};
+use std::collections::{BTreeMap, HashMap};
+
+/// A file mapped into a process.
+#[derive(Debug, Clone)]
+struct Map {
+    end: u64,
+    pgoff: u64,
+    dso: usize,
+}
+
+/// Where a sample landed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub pid: u32,
+    pub comm: String,
+    /// The file, or `None` if no mapping holds the address.
+    pub dso: Option<String>,
+    pub symbol: Option<String>,
+    /// The sampled address, as an address of the file
+    /// when the file is known.
+    pub ip: u64,
+    /// Whether the sample was taken in the kernel.
+    pub kernel: bool,
+}
+
+impl Location {
+    /// The function, or the address if it is not known.
+    pub fn name(&self) -> String {
+        match &self.symbol {
+            Some(name) => name.clone(),
+            None => format!("{:#018x}", self.ip),
+        }
+    }
+}
+
+/// The tasks of a recording, as of the records replayed so far.
+#[derive(Default)]
+pub struct Machine {
+    /// How function names are shown.
+    demangle: Demangle,
+    /// Whether to look for inlined functions.
+    inline: bool,
+    /// The mappings of each process, by start address.
+    maps: HashMap<u32, BTreeMap<u64, Map>>,
+    comms: HashMap<u32, String>,
+    /// Files by path, loaded when a sample first lands in them.
+    dsos: Vec<(String, Option<Dso>)>,
+    dso_index: HashMap<String, usize>,
+    kernel: Option<Dso>,
+}
+
+impl Machine {
+    /// A machine showing function names as `demangle` says,
+    /// and with `inline` the functions inlined into them.
+    pub fn new(demangle: Demangle, inline: bool) -> Self {
+        Machine {
+            demangle,
+            inline,
+            ..Default::default()
+        }
+    }
+
+    /// Replay a record that changes a task.
+    pub fn apply(&mut self, record: &Record) {
+        match record {
+            Record::Comm(comm) => {
+                // A new program brings a new address space.
+                if comm.exec {
+                    self.maps.remove(&comm.pid);
+                }
+                self.comms.insert(comm.tid, comm.comm.clone());
+            }
+            Record::Mmap(mmap) => {
+                self.map(mmap.pid, mmap.addr, mmap.len, mmap.pgoff, &mmap.filename)
+            }
+            Record::Mmap2(mmap) => {
+                self.map(mmap.pid, mmap.addr, mmap.len, mmap.pgoff, &mmap.filename)
+            }
+            Record::Fork(fork) => {
+                if let Some(comm) = self.comms.get(&fork.ptid).cloned() {
+                    self.comms.entry(fork.tid).or_insert(comm);
+                }
+                // A new process starts as a copy of its parent.
+                if fork.pid != fork.ppid {
+                    if let Some(maps) = self.maps.get(&fork.ppid).cloned() {
+                        self.maps.insert(fork.pid, maps);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Map `len` bytes of `filename` at `addr` into `pid`,
+    /// replacing whatever was mapped there before.
+    fn map(&mut self, pid: u32, addr: u64, len: u64, pgoff: u64, filename: &str) {
+        // The kernel's own map is reported for pid -1.
+        if pid == u32::MAX || filename.starts_with(KERNEL_NAME) {
+            return;
+        }
+        let dso = match self.dso_index.get(filename) {
+            Some(dso) => *dso,
+            None => {
+                self.dsos.push((filename.to_string(), None));
+                self.dso_index
+                    .insert(filename.to_string(), self.dsos.len() - 1);
+                self.dsos.len() - 1
+            }
+        };
+        let maps = self.maps.entry(pid).or_default();
+        let end = addr.saturating_add(len);
+        let overlapping: Vec<u64> = maps
+            .range(..end)
+            .filter(|(_, map)| map.end > addr)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            maps.remove(&start);
+        }
+        maps.insert(addr, Map { end, pgoff, dso });
+    }
+
+    /// The name of task `tid`, or `:TID` if it was never seen.
+    pub fn comm(&self, tid: u32) -> String {
+        match self.comms.get(&tid) {
+            Some(comm) => comm.clone(),
+            None => format!(":{}", tid),
+        }
+    }
+
+    /// Find where `frame` of the call chain of `sample` is:
+    /// in the functions inlined there, innermost first, and
+    /// last in the function they were inlined into.
+    pub fn locate(&mut self, sample: &Sample, frame: &Frame) -> Vec<Location> {
+        let pid = sample.pid.unwrap_or(0);
+        let tid = sample.tid.unwrap_or(pid);
+        let (mut ip, kernel) = (frame.ip, frame.kernel);
+        let (dso, symbol, inlined) = if kernel {
+            let demangle = self.demangle;
+            let symbol = self
+                .kernel
+                .get_or_insert_with(Dso::kernel)
+                .find(ip)
+                .map(|sym| demangle.name(&sym.name));
+            (Some(KERNEL_NAME.to_string()), symbol, Vec::new())
+        } else {
+            let inlined = self.inlined_at(pid, ip);
+            let (dso, symbol, address) = self.locate_address(pid, ip);
+            ip = address.unwrap_or(ip);
+            (dso, symbol, inlined)
+        };
+        let location = Location {
+            pid,
+            comm: self.comm(tid),
+            dso,
+            symbol,
+            ip,
+            kernel,
+        };
+        let mut locations: Vec<Location> = inlined
+            .into_iter()
+            .map(|name| Location {
+                symbol: Some(name),
+                ..location.clone()
+            })
+            .collect();
+        locations.push(location);
+        locations
+    }
+
+    /// The file mapped at `ip` in process `pid`, and the
+    /// address of `ip` in it. Files that cannot be read are
+    /// placed by file offset.
+    pub fn dso_at(&mut self, pid: u32, ip: u64) -> Option<(&mut Dso, u64)> {
+        let (offset, index) = self
+            .maps
+            .get(&pid)
+            .and_then(|maps| maps.range(..=ip).next_back())
+            .filter(|(_, map)| ip < map.end)
+            .map(|(start, map)| (ip - start + map.pgoff, map.dso))?;
+        let (path, dso) = &mut self.dsos[index];
+        let dso = dso.get_or_insert_with(|| Dso::load(path));
+        let address = dso.address_of(offset).unwrap_or(offset);
+        Some((dso, address))
+    }
+
+    /// The file and function of `ip` in process `pid`,
+    /// and the address of `ip` in the file.
+    pub fn locate_address(
+        &mut self,
+        pid: u32,
+        ip: u64,
+    ) -> (Option<String>, Option<String>, Option<u64>) {
+        let demangle = self.demangle;
+        match self.dso_at(pid, ip) {
+            Some((dso, address)) => {
+                let symbol = dso.find(address).map(|sym| demangle.name(&sym.name));
+                (Some(dso.short_name().to_string()), symbol, Some(address))
+            }
+            None => (None, None, None),
+        }
+    }
+
+    /// The names of the functions inlined at `ip` in process
+    /// `pid`, innermost first, if inlined functions are shown.
+    fn inlined_at(&mut self, pid: u32, ip: u64) -> Vec<String> {
+        if !self.inline {
+            return Vec::new();
+        }
+        let demangle = self.demangle;
+        match self.dso_at(pid, ip) {
+            Some((dso, address)) => dso
+                .inlined(address)
+                .iter()
+                .map(|name| demangle.name(name))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// How to unwind the user stack of `pid` from `ip`.
+    pub fn unwind_step(&mut self, pid: u32, ip: u64) -> Option<Step> {
+        let (dso, address) = self.dso_at(pid, ip)?;
+        dso.unwind.as_mut()?.step(address)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn machine_test() {
+    use crate::event::records::{Comm, Mmap2, SampleId, Task};
+    use object::{Object, ObjectSegment};
+    let mut machine = Machine::default();
+    let exe = std::env::current_exe().unwrap();
+    let exe = exe.to_str().unwrap();
+    let data = std::fs::read(exe).unwrap();
+    let file = object::File::parse(&*data).unwrap();
+    let address =
+        crate::event::probe::symbol_address(&file, "ruperf_report_test_function").unwrap();
+    // Map the whole file at an arbitrary base, as a PIE would be.
+    let base = 0x5555_0000_0000;
+    machine.apply(&Record::Comm(Comm {
+        pid: 10,
+        tid: 10,
+        comm: "ruperf".to_string(),
+        exec: true,
+        sample_id: SampleId::default(),
+    }));
+    machine.apply(&Record::Mmap2(Mmap2 {
+        pid: 10,
+        tid: 10,
+        addr: base,
+        len: data.len() as u64 + 0x10_0000,
+        pgoff: 0,
+        maj: 0,
+        min: 0,
+        ino: 0,
+        ino_generation: 0,
+        build_id: None,
+        prot: 5,
+        flags: 2,
+        filename: exe.to_string(),
+        sample_id: SampleId::default(),
+    }));
+    machine.apply(&Record::Fork(Task {
+        pid: 11,
+        ppid: 10,
+        tid: 11,
+        ptid: 10,
+        time: 0,
+        sample_id: SampleId::default(),
+    }));
+    // Find the file offset of the function through its segment.
+    let offset = file
+        .segments()
+        .find(|seg| address >= seg.address() && address < seg.address() + seg.size())
+        .map(|seg| address - seg.address() + seg.file_range().0)
+        .unwrap();
+    let sample = Sample {
+        pid: Some(11),
+        tid: Some(11),
+        ..Default::default()
+    };
+    let frame = Frame {
+        ip: base + offset,
+        kernel: false,
+    };
+    let location = &machine.locate(&sample, &frame)[0];
+    assert_eq!((location.comm.as_str(), location.ip), ("ruperf", address));
+    assert_eq!(
+        location.symbol.as_deref(),
+        Some("ruperf_report_test_function")
+    );
+    assert_eq!(
+        location.dso.as_deref(),
+        std::path::Path::new(exe).file_name().unwrap().to_str()
+    );
+
+    // Unoptimized code still inlines `#[inline(always)]`,
+    // and the test function's segment holds this one too.
+    // Inlined frames need debug info, as in `inlined_test`.
+    machine.inline = true;
+    let outer = crate::event::probe::symbol_address(&file, "ruperf_report_test_outer").unwrap();
+    let ip = |at: u64| base + at - address + offset;
+    let end = match machine.dso_at(11, ip(outer)) {
+        Some((dso, at)) if dso.debug().is_some() => dso.find(at).unwrap().end,
+        _ => outer,
+    };
+    let chains: Vec<Vec<Location>> = (outer..end)
+        .map(|at| Frame {
+            ip: ip(at),
+            kernel: false,
+        })
+        .map(|frame| machine.locate(&sample, &frame))
+        .collect();
+    if end > outer {
+        let chain = chains
+            .iter()
+            .find(|chain| chain.len() > 1)
+            .expect("no inlined frame in ruperf_report_test_outer");
+        assert!(chain[0].name().contains("ruperf_report_test_inlined"));
+        assert_eq!(chain[1].name(), "ruperf_report_test_outer");
+        assert_eq!(chain[0].ip, chain[1].ip);
+        assert_eq!(chains[0].len(), 1);
+    }
+
+    let unknown = Sample {
+        pid: Some(12),
+        tid: Some(12),
+        ..sample
+    };
+    let frame = Frame {
+        ip: 0x1234,
+        kernel: false,
+    };
+    let location = &machine.locate(&unknown, &frame)[0];
+    assert_eq!((location.comm.as_str(), &location.dso), (":12", &None));
+}
@@ -0,0 +1,364 @@
+//! Report output for `ruperf report`.
+//! Samples are printed as a table of overheads, one per
+//! event, in the layout of `perf report --stdio`, or as
+//! JSON with `--json`. Call trees leave out the calls
+//! that took less than `CALL_GRAPH_LIMIT` of the samples.
+
+use super::*;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// What a file column shows for samples outside any mapping.
+const UNKNOWN_DSO: &str = "[unknown]";
+
+/// The smallest share of an event's count, in percent,
+/// that a call in a call tree is shown for.
+const CALL_GRAPH_LIMIT: f64 = 0.5;
+
+/// The calls of `nodes` that are shown.
+fn shown<'a>(histogram: &Histogram, nodes: &'a [CallNode]) -> Vec<&'a CallNode> {
+    nodes
+        .iter()
+        .filter(|node| histogram.percent(node.period) >= CALL_GRAPH_LIMIT)
+        .collect()
+}
+
+/// Print the call tree `nodes`, each line starting with `prefix`.
+fn print_call_graph(
+    out: &mut dyn Write,
+    histogram: &Histogram,
+    nodes: &[CallNode],
+    prefix: &str,
+) -> io::Result<()> {
+    let nodes = shown(histogram, nodes);
+    for (i, node) in nodes.iter().enumerate() {
+        let last = i + 1 == nodes.len();
+        let branch = if last { " " } else { "|" };
+        let share = format!("{:.2}%", histogram.percent(node.period));
+        writeln!(out, "{}{}--{}--{}", prefix, branch, share, node.name)?;
+        let prefix = format!("{}{}{}", prefix, branch, " ".repeat(share.len() + 4));
+        print_call_graph(out, histogram, &node.children, &prefix)?;
+    }
+    Ok(())
+}
+
+impl SortKey {
+    /// The heading of the column.
+    pub(crate) fn heading(&self) -> &'static str {
+        match self {
+            SortKey::Comm => "Command",
+            SortKey::Pid => "Pid",
+            SortKey::Dso => "Shared Object",
+            SortKey::Sym => "Symbol",
+        }
+    }
+
+    /// The name the key is given on the command line.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SortKey::Comm => "comm",
+            SortKey::Pid => "pid",
+            SortKey::Dso => "dso",
+            SortKey::Sym => "sym",
+        }
+    }
+}
+
+impl EntryKey {
+    /// The value of `column`, as printed in a table.
+    pub(crate) fn column(&self, column: SortKey) -> String {
+        match column {
+            SortKey::Comm => self.comm.clone().unwrap_or_default(),
+            SortKey::Pid => self.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+            SortKey::Dso => match &self.dso {
+                Some(Some(dso)) => dso.clone(),
+                _ => UNKNOWN_DSO.to_string(),
+            },
+            SortKey::Sym => match &self.symbol {
+                Some((true, name)) => format!("[k] {}", name),
+                Some((false, name)) => format!("[.] {}", name),
+                None => String::new(),
+            },
+        }
+    }
+}
+
+/// Print `histograms` as tables, like `perf report --stdio`.
+pub fn print_stdio(
+    out: &mut dyn Write,
+    options: &ReportOptions,
+    histograms: &[Histogram],
+) -> io::Result<()> {
+    let keys = &options.sort.0;
+    let lost: u64 = histograms.iter().map(|h| h.lost).sum();
+    writeln!(out, "# Total Lost Samples: {}", lost)?;
+    for histogram in histograms {
+        writeln!(out, "#")?;
+        writeln!(
+            out,
+            "# Samples: {} of event '{}'",
+            histogram.samples, histogram.event
+        )?;
+        writeln!(out, "# Event count (approx.): {}", histogram.period)?;
+        writeln!(out, "#")?;
+
+        let rows: Vec<Vec<String>> = histogram
+            .entries
+            .iter()
+            .map(|entry| keys.iter().map(|key| entry.key.column(*key)).collect())
+            .collect();
+        let widths: Vec<usize> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(key.heading().len()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        // The last column is not padded.
+        let line = |cells: Vec<String>| -> String {
+            let mut line = String::new();
+            for (i, cell) in cells.iter().enumerate() {
+                if i + 1 == cells.len() {
+                    line.push_str(&format!("  {}", cell));
+                } else {
+                    line.push_str(&format!("  {:<width$}", cell, width = widths[i]));
+                }
+            }
+            line
+        };
+        let headings = keys.iter().map(|key| key.heading().to_string()).collect();
+        let dots = widths.iter().map(|width| ".".repeat(*width)).collect();
+        if histogram.children {
+            writeln!(out, "# Children      Self{}", line(headings))?;
+            writeln!(out, "# ........  ........{}", line(dots))?;
+        } else {
+            writeln!(out, "# Overhead{}", line(headings))?;
+            writeln!(out, "# ........{}", line(dots))?;
+        }
+        writeln!(out, "#")?;
+        for (entry, row) in histogram.entries.iter().zip(rows) {
+            if histogram.children {
+                write!(out, "{:>9.2}% ", histogram.percent(entry.children))?;
+            }
+            writeln!(out, "{:>9.2}%{}", histogram.overhead(entry), line(row))?;
+            if !shown(histogram, &entry.call_graph).is_empty() {
+                let prefix = " ".repeat(12);
+                writeln!(out, "{}|", prefix)?;
+                print_call_graph(out, histogram, &entry.call_graph, &prefix)?;
+                writeln!(out)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// A call in a call tree as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonCall {
+    name: String,
+    overhead: f64,
+    period: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    calls: Vec<JsonCall>,
+}
+
+impl JsonCall {
+    /// The shown calls of `nodes`.
+    fn tree(histogram: &Histogram, nodes: &[CallNode]) -> Vec<Self> {
+        shown(histogram, nodes)
+            .into_iter()
+            .map(|node| JsonCall {
+                name: node.name.clone(),
+                overhead: histogram.percent(node.period),
+                period: node.period,
+                calls: JsonCall::tree(histogram, &node.children),
+            })
+            .collect()
+    }
+}
+
+/// One group of samples as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonEntry {
+    overhead: f64,
+    samples: u64,
+    period: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kernel: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    call_graph: Vec<JsonCall>,
+}
+
+/// The samples of one event as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonEvent<'a> {
+    event: &'a str,
+    samples: u64,
+    event_count: u64,
+    lost: u64,
+    entries: Vec<JsonEntry>,
+}
+
+/// The whole report as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    input: String,
+    sort: Vec<&'static str>,
+    events: Vec<JsonEvent<'a>>,
+}
+
+/// Print `histograms` as JSON.
+pub fn print_json(
+    out: &mut dyn Write,
+    options: &ReportOptions,
+    histograms: &[Histogram],
+) -> io::Result<()> {
+    let report = JsonReport {
+        input: options.input.display().to_string(),
+        sort: options.sort.0.iter().map(SortKey::name).collect(),
+        events: histograms
+            .iter()
+            .map(|histogram| JsonEvent {
+                event: &histogram.event,
+                samples: histogram.samples,
+                event_count: histogram.period,
+                lost: histogram.lost,
+                entries: histogram
+                    .entries
+                    .iter()
+                    .map(|entry| JsonEntry {
+                        overhead: histogram.overhead(entry),
+                        samples: entry.samples,
+                        period: entry.period,
+                        children: if histogram.children {
+                            Some(histogram.percent(entry.children))
+                        } else {
+                            None
+                        },
+                        comm: entry.key.comm.clone(),
+                        pid: entry.key.pid,
+                        dso: entry
+                            .key
+                            .dso
+                            .as_ref()
+                            .map(|dso| dso.clone().unwrap_or_else(|| UNKNOWN_DSO.to_string())),
+                        symbol: entry.key.symbol.as_ref().map(|(_, name)| name.clone()),
+                        kernel: entry.key.symbol.as_ref().map(|(kernel, _)| *kernel),
+                        call_graph: JsonCall::tree(histogram, &entry.call_graph),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    writeln!(out, "{}", serde_json::to_string_pretty(&report).unwrap())
+}
+
+#[cfg(test)]
+fn test_histogram() -> Histogram {
+    let entry = |comm: &str, dso: Option<&str>, symbol: (bool, &str), period| Entry {
+        key: EntryKey {
+            comm: Some(comm.to_string()),
+            pid: None,
+            dso: Some(dso.map(String::from)),
+            symbol: Some((symbol.0, symbol.1.to_string())),
+        },
+        samples: period,
+        period,
+        children: period,
+        call_graph: Vec::new(),
+    };
+    Histogram {
+        event: "cpu-clock".to_string(),
+        samples: 4,
+        period: 4,
+        lost: 0,
+        children: false,
+        entries: vec![
+            entry("fp-calc", Some("fp-calc"), (false, "main"), 3),
+            entry("fp-calc", None, (true, "0x0000000000000010"), 1),
+        ],
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn print_stdio_test() {
+    let options = ReportOptions::from_iter(&["report"]);
+    let mut out = Vec::new();
+    print_stdio(&mut out, &options, &[test_histogram()]).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[2], "# Samples: 4 of event 'cpu-clock'");
+    assert_eq!(lines[5], "# Overhead  Command  Shared Object  Symbol");
+    assert_eq!(
+        lines[6],
+        "# ........  .......  .............  ......................"
+    );
+    assert_eq!(lines[8], "    75.00%  fp-calc  fp-calc        [.] main");
+    assert_eq!(
+        lines[9],
+        "    25.00%  fp-calc  [unknown]      [k] 0x0000000000000010"
+    );
+}
+
+#[test]
+fn print_json_test() {
+    let options = ReportOptions::from_iter(&["report", "--json", "--sort", "dso,sym"]);
+    let mut histogram = test_histogram();
+    for entry in &mut histogram.entries {
+        entry.key.comm = None;
+    }
+    let mut out = Vec::new();
+    print_json(&mut out, &options, &[histogram]).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["sort"], serde_json::json!(["dso", "sym"]));
+    let entry = &json["events"][0]["entries"][1];
+    assert_eq!(entry["dso"], "[unknown]");
+    assert_eq!(entry["kernel"], true);
+    assert!(entry.get("comm").is_none());
+}
+
+#[test]
+fn print_call_graph_test() {
+    let node = |name: &str, period, children| CallNode {
+        name: name.to_string(),
+        period,
+        children,
+    };
+    let mut histogram = test_histogram();
+    histogram.period = 1000;
+    let tree = vec![
+        node(
+            "a",
+            600,
+            vec![node("b", 400, vec![]), node("c", 200, vec![])],
+        ),
+        node("d", 300, vec![]),
+        // Too small to show.
+        node("e", 4, vec![]),
+    ];
+    let mut out = Vec::new();
+    print_call_graph(&mut out, &histogram, &tree, "  ").unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "  |--60.00%--a\n\
+         \x20 |          |--40.00%--b\n\
+         \x20 |           --20.00%--c\n\
+         \x20  --30.00%--d\n"
+    );
+}
@@ -0,0 +1,287 @@
+//! Function symbols of the files samples land in.
+//! A `Dso` ("dynamic shared object", as `perf` calls any
+//! mapped ELF file) holds the functions of `.symtab`, or of
+//! `.dynsym` for stripped files, along with the loaded
+//! segments that turn a file offset into a symbol address.
+//! Files built with debugging information also tell, from
+//! `.debug_info`, which functions were inlined at an address
+//! and which line of source it was compiled from.
+//! The kernel's functions come from `/proc/kallsyms`.
+
+use super::unwind::UnwindInfo;
+use addr2line::ObjectContext;
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use std::cell::OnceCell;
+use std::fs;
+use std::path::Path;
+
+/// The name samples in the kernel are reported under.
+pub const KERNEL_NAME: &str = "[kernel.kallsyms]";
+
+/// A function, over `[start, end)` in its file's addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub start: u64,
+    pub end: u64,
+    pub name: String,
+}
+
+/// A loaded segment of an ELF file.
+#[derive(Debug, Copy, Clone)]
+struct Segment {
+    offset: u64,
+    size: u64,
+    address: u64,
+}
+
+/// The symbols of one file, sorted by address.
+#[derive(Default)]
+pub struct Dso {
+    /// The path the file was mapped from.
+    pub path: String,
+    symbols: Vec<Symbol>,
+    segments: Vec<Segment>,
+    /// The call frame information, for unwinding user stacks.
+    pub unwind: Option<UnwindInfo>,
+    /// The DWARF debugging information, for inlined functions
+    /// and source lines, read when first asked for.
+    debug: OnceCell<Option<ObjectContext>>,
+}
+
+impl Dso {
+    /// Load the symbols of the ELF file at `path`. A file that
+    /// cannot be read, such as one deleted since it was mapped,
+    /// has no symbols, and samples in it stay unresolved.
+    pub fn load(path: &str) -> Self {
+        let mut dso = Dso {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return dso,
+        };
+        let file = match object::File::parse(&*data) {
+            Ok(file) => file,
+            Err(_) => return dso,
+        };
+        dso.segments = file
+            .segments()
+            .map(|seg| Segment {
+                offset: seg.file_range().0,
+                size: seg.file_range().1,
+                address: seg.address(),
+            })
+            .collect();
+        let symbols = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|sym| sym.kind() == SymbolKind::Text && sym.is_definition())
+            .filter(|sym| sym.address() != 0)
+            .filter_map(|sym| Some((sym.address(), sym.size(), sym.name().ok()?.to_string())));
+        dso.symbols = sorted_symbols(symbols);
+        dso.unwind = UnwindInfo::load(&file);
+        dso
+    }
+
+    /// The kernel's functions, from `/proc/kallsyms`. Addresses
+    /// read as zero without the privilege to see them, and are
+    /// then left out.
+    pub fn kernel() -> Self {
+        let kallsyms = fs::read_to_string("/proc/kallsyms").unwrap_or_default();
+        let symbols = kallsyms.lines().filter_map(|line| {
+            // address type name [module]
+            let mut fields = line.split_whitespace();
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            match (address, kind) {
+                (0, _) => None,
+                (_, "t") | (_, "T") | (_, "w") | (_, "W") => Some((address, 0, name.to_string())),
+                _ => None,
+            }
+        });
+        Dso {
+            path: KERNEL_NAME.to_string(),
+            symbols: sorted_symbols(symbols),
+            // Kernel samples are already in symbol addresses.
+            segments: vec![Segment {
+                offset: 0,
+                size: u64::MAX,
+                address: 0,
+            }],
+            unwind: None,
+            debug: OnceCell::from(None),
+        }
+    }
+
+    /// The name reports show for the file, without its directory.
+    pub fn short_name(&self) -> &str {
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+
+    /// The symbol address of `offset` into the file.
+    pub fn address_of(&self, offset: u64) -> Option<u64> {
+        self.segments
+            .iter()
+            .find(|seg| offset >= seg.offset && offset - seg.offset < seg.size)
+            .map(|seg| offset - seg.offset + seg.address)
+    }
+
+    /// The function holding `address`.
+    pub fn find(&self, address: u64) -> Option<&Symbol> {
+        let at = self.symbols.partition_point(|sym| sym.start <= address);
+        self.symbols[..at].last().filter(|sym| address < sym.end)
+    }
+
+    /// The DWARF debugging information of the file, if it has
+    /// any. Reading it takes a while, so it is only read here.
+    pub fn debug(&self) -> Option<&ObjectContext> {
+        self.debug
+            .get_or_init(|| {
+                let data = fs::read(&self.path).ok()?;
+                let file = object::File::parse(&*data).ok()?;
+                file.section_by_name(".debug_info")?;
+                ObjectContext::new(&file).ok()
+            })
+            .as_ref()
+    }
+
+    /// The source file and line `address` was compiled from,
+    /// from `.debug_line`.
+    pub fn line(&self, address: u64) -> Option<(String, u32)> {
+        let location = self.debug()?.find_location(address).ok()??;
+        Some((location.file?.to_string(), location.line?))
+    }
+
+    /// The mangled names of the functions inlined at `address`,
+    /// innermost first, leaving out the function they were
+    /// inlined into. Empty without debugging information.
+    pub fn inlined(&self, address: u64) -> Vec<String> {
+        let mut frames = match self.debug().map(|debug| debug.find_frames(address)) {
+            Some(Ok(frames)) => frames,
+            _ => return Vec::new(),
+        };
+        let mut names = Vec::new();
+        while let Ok(Some(frame)) = frames.next() {
+            let name = frame
+                .function
+                .and_then(|function| Some(function.raw_name().ok()?.into_owned()));
+            names.push(name);
+        }
+        names.pop();
+        names.into_iter().flatten().collect()
+    }
+}
+
+/// Sort `(address, size, name)` triples into symbols. Aliases
+/// at the same address are dropped, keeping the first, and a
+/// symbol without a size ends where the next one starts.
+fn sorted_symbols(symbols: impl Iterator<Item = (u64, u64, String)>) -> Vec<Symbol> {
+    let mut symbols: Vec<(u64, u64, String)> = symbols.collect();
+    symbols.sort_by_key(|(address, _, _)| *address);
+    symbols.dedup_by_key(|(address, _, _)| *address);
+    let next: Vec<u64> = symbols
+        .iter()
+        .skip(1)
+        .map(|(address, _, _)| *address)
+        .chain(std::iter::once(u64::MAX))
+        .collect();
+    symbols
+        .into_iter()
+        .zip(next)
+        .map(|((start, size, name), next)| Symbol {
+            start,
+            end: if size > 0 { start + size } else { next },
+            name,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[test]
+fn sorted_symbols_test() {
+    let symbols = sorted_symbols(
+        vec![
+            (0x2000, 0, "b".to_string()),
+            (0x1000, 0x10, "a".to_string()),
+            (0x1000, 0x10, "a_alias".to_string()),
+        ]
+        .into_iter(),
+    );
+    assert_eq!(symbols.len(), 2);
+    let dso = Dso {
+        symbols,
+        ..Default::default()
+    };
+    assert_eq!(dso.find(0x1008).unwrap().name, "a");
+    assert_eq!(dso.find(0x1010), None);
+    assert_eq!(dso.find(0xfff), None);
+    assert_eq!(dso.find(0x9000).unwrap().name, "b");
+}
+
+/// A function for `load_test` to find by name.
+#[cfg(test)]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn ruperf_report_test_function() -> u64 {
+    std::hint::black_box(42)
+}
+
+#[test]
+fn load_test() {
+    let exe = std::env::current_exe().unwrap();
+    let dso = Dso::load(exe.to_str().unwrap());
+    assert_eq!(dso.short_name(), exe.file_name().unwrap().to_str().unwrap());
+    let data = fs::read(&exe).unwrap();
+    let file = object::File::parse(&*data).unwrap();
+    let address = crate::event::probe::symbol_address(&file, "ruperf_report_test_function");
+    let symbol = dso.find(address.unwrap() + 1).unwrap();
+    assert_eq!(symbol.name, "ruperf_report_test_function");
+    assert_eq!(ruperf_report_test_function(), 42);
+
+    let missing = Dso::load("/no/such/file");
+    assert_eq!(missing.find(address.unwrap()), None);
+    assert!(missing.inlined(address.unwrap()).is_empty());
+}
+
+/// Inlined into `ruperf_report_test_outer`, even unoptimized.
+#[cfg(test)]
+#[inline(always)]
+fn ruperf_report_test_inlined(x: u64) -> u64 {
+    std::hint::black_box(x) * 3
+}
+
+/// A function for `inlined_test` to look into.
+#[cfg(test)]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn ruperf_report_test_outer(x: u64) -> u64 {
+    ruperf_report_test_inlined(x) + 1
+}
+
+#[test]
+fn inlined_test() {
+    let exe = std::env::current_exe().unwrap();
+    let dso = Dso::load(exe.to_str().unwrap());
+    if dso.debug().is_none() {
+        return;
+    }
+    let data = fs::read(&exe).unwrap();
+    let file = object::File::parse(&*data).unwrap();
+    let start = crate::event::probe::symbol_address(&file, "ruperf_report_test_outer").unwrap();
+    let outer = dso.find(start).unwrap();
+    let inlined: Vec<String> = (outer.start..outer.end)
+        .flat_map(|address| dso.inlined(address))
+        .collect();
+    assert!(inlined
+        .iter()
+        .any(|name| name.contains("ruperf_report_test_inlined")));
+    assert!(dso.inlined(start).is_empty());
+    let (file, line) = dso.line(start).unwrap();
+    assert!(file.ends_with("symbols.rs") && line > 0);
+    assert_eq!(ruperf_report_test_outer(2), 7);
+}
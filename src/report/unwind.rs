@@ -0,0 +1,333 @@
+//! Unwinding of copied user stacks.
+//! With `--call-graph dwarf` each sample holds the user
+//! registers and a copy of the top of the user stack. The
+//! call frame information in each file's `.eh_frame` tells,
+//! for any address, where the canonical frame address (the
+//! stack pointer before the call) and the saved return
+//! address and frame pointer are, relative to the registers.
+//! Following it frame by frame rebuilds the chain of return
+//! addresses, for as long as the frames lie in the copy.
+
+use super::machine::Machine;
+use crate::event::records::Registers;
+use crate::event::regs;
+use gimli::{
+    BaseAddresses, CfaRule, EhFrame, EhFrameHdr, NativeEndian, RegisterRule,
+    UninitializedUnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection};
+use std::collections::HashMap;
+
+/// The most frames followed, against loops in corrupt stacks.
+const MAX_FRAMES: usize = 256;
+
+/// DWARF numbers of the registers unwinding follows.
+#[cfg(target_arch = "aarch64")]
+mod dwarf {
+    pub const FP: u16 = 29;
+    pub const LR: Option<u16> = Some(30);
+    pub const SP: u16 = 31;
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+mod dwarf {
+    pub const FP: u16 = 6;
+    pub const LR: Option<u16> = None;
+    pub const SP: u16 = 7;
+}
+
+/// Where a register of the caller is found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Saved {
+    /// Unchanged from the callee.
+    Same,
+    /// In memory at the given offset from the frame address.
+    At(i64),
+    /// The frame address plus the given offset.
+    Value(i64),
+    /// In another register.
+    In(u16),
+    /// Not recoverable.
+    Unknown,
+}
+
+impl Saved {
+    fn new<R: gimli::Reader>(rule: RegisterRule<R>) -> Self {
+        match rule {
+            RegisterRule::Undefined | RegisterRule::SameValue => Saved::Same,
+            RegisterRule::Offset(offset) => Saved::At(offset),
+            RegisterRule::ValOffset(offset) => Saved::Value(offset),
+            RegisterRule::Register(reg) => Saved::In(reg.0),
+            _ => Saved::Unknown,
+        }
+    }
+}
+
+/// How to get from a frame to its caller's.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// The frame address is this register plus the offset.
+    pub cfa: (u16, i64),
+    pub fp: Saved,
+    pub ra: Saved,
+    /// Whether the return address has no rule of its own,
+    /// as in leaf functions that keep it in the link register.
+    pub ra_undefined: bool,
+}
+
+/// The call frame information of a file.
+#[derive(Debug)]
+pub struct UnwindInfo {
+    eh_frame: Vec<u8>,
+    eh_frame_hdr: Option<Vec<u8>>,
+    bases: BaseAddresses,
+    /// Steps already found, by address.
+    steps: HashMap<u64, Option<Step>>,
+}
+
+impl UnwindInfo {
+    /// Read the `.eh_frame` of `file`, if it has one.
+    pub fn load(file: &object::File) -> Option<Self> {
+        let eh_frame = file.section_by_name(".eh_frame")?;
+        let mut bases = BaseAddresses::default().set_eh_frame(eh_frame.address());
+        if let Some(text) = file.section_by_name(".text") {
+            bases = bases.set_text(text.address());
+        }
+        if let Some(got) = file.section_by_name(".got") {
+            bases = bases.set_got(got.address());
+        }
+        let hdr = file.section_by_name(".eh_frame_hdr");
+        if let Some(hdr) = &hdr {
+            bases = bases.set_eh_frame_hdr(hdr.address());
+        }
+        Some(Self {
+            eh_frame: eh_frame.data().ok()?.to_vec(),
+            eh_frame_hdr: hdr.and_then(|hdr| Some(hdr.data().ok()?.to_vec())),
+            bases,
+            steps: HashMap::new(),
+        })
+    }
+
+    /// How to unwind from `address`, in the file's addresses.
+    pub fn step(&mut self, address: u64) -> Option<Step> {
+        if let Some(step) = self.steps.get(&address) {
+            return *step;
+        }
+        let step = self.find_step(address);
+        self.steps.insert(address, step);
+        step
+    }
+
+    fn find_step(&self, address: u64) -> Option<Step> {
+        let mut eh_frame = EhFrame::new(&self.eh_frame, NativeEndian);
+        eh_frame.set_address_size(8);
+        // The sorted table of `.eh_frame_hdr` saves a linear search.
+        let table = self.eh_frame_hdr.as_ref().and_then(|hdr| {
+            EhFrameHdr::new(hdr, NativeEndian)
+                .parse(&self.bases, 8)
+                .ok()
+        });
+        let fde = match table.as_ref().and_then(|hdr| hdr.table()) {
+            Some(table) => {
+                table.fde_for_address(&eh_frame, &self.bases, address, EhFrame::cie_from_offset)
+            }
+            None => eh_frame.fde_for_address(&self.bases, address, EhFrame::cie_from_offset),
+        }
+        .ok()?;
+        let mut ctx = UninitializedUnwindContext::new();
+        let row = fde
+            .unwind_info_for_address(&eh_frame, &self.bases, &mut ctx, address)
+            .ok()?;
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => (register.0, *offset),
+            CfaRule::Expression(_) => return None,
+        };
+        let ra = row.register(fde.cie().return_address_register());
+        Some(Step {
+            cfa,
+            fp: Saved::new(row.register(gimli::Register(dwarf::FP))),
+            ra_undefined: matches!(ra, RegisterRule::Undefined),
+            ra: Saved::new(ra),
+        })
+    }
+}
+
+/// The registers of a frame being unwound.
+#[derive(Debug, Copy, Clone)]
+struct Frame {
+    pc: u64,
+    sp: u64,
+    fp: Option<u64>,
+    lr: Option<u64>,
+}
+
+impl Frame {
+    fn register(&self, reg: u16) -> Option<u64> {
+        match reg {
+            dwarf::SP => Some(self.sp),
+            dwarf::FP => self.fp,
+            reg if Some(reg) == dwarf::LR => self.lr,
+            _ => None,
+        }
+    }
+}
+
+/// The return addresses of the user stack of process `pid`,
+/// from the sampled registers `regs`, taken with `mask`, and
+/// the copy of the stack `stack`. The sampled address comes
+/// first. Stops at the first frame it cannot unwind.
+pub fn unwind(
+    machine: &mut Machine,
+    pid: u32,
+    regs: &Registers,
+    mask: u64,
+    stack: &[u8],
+) -> Vec<u64> {
+    let get = |reg| regs::value(regs, mask, reg);
+    let mut frame = match (get(regs::IP), get(regs::SP)) {
+        (Some(pc), Some(sp)) => Frame {
+            pc,
+            sp,
+            fp: get(regs::FP),
+            lr: regs::LR.and_then(get),
+        },
+        _ => return Vec::new(),
+    };
+    let base = frame.sp;
+    let read = |address: u64| -> Option<u64> {
+        let at = address.checked_sub(base)? as usize;
+        let mut word = [0; 8];
+        word.copy_from_slice(stack.get(at..at.checked_add(8)?)?);
+        Some(u64::from_ne_bytes(word))
+    };
+    let offset = |value: u64, offset: i64| -> Option<u64> {
+        if offset < 0 {
+            value.checked_sub(offset.unsigned_abs())
+        } else {
+            value.checked_add(offset as u64)
+        }
+    };
+
+    let mut chain = vec![frame.pc];
+    for depth in 0..MAX_FRAMES {
+        // A return address is past its call, which may be the
+        // last instruction of a function.
+        let address = if depth == 0 { frame.pc } else { frame.pc - 1 };
+        let step = match machine.unwind_step(pid, address) {
+            Some(step) => step,
+            None => break,
+        };
+        let cfa = match frame
+            .register(step.cfa.0)
+            .and_then(|reg| offset(reg, step.cfa.1))
+        {
+            Some(cfa) => cfa,
+            None => break,
+        };
+        let restore = |saved: Saved, current: Option<u64>| -> Option<u64> {
+            match saved {
+                Saved::Same => current,
+                Saved::At(n) => read(offset(cfa, n)?),
+                Saved::Value(n) => offset(cfa, n),
+                Saved::In(reg) => frame.register(reg),
+                Saved::Unknown => None,
+            }
+        };
+        // Only a leaf frame can still hold its return
+        // address in the link register.
+        let ra = if step.ra_undefined {
+            if depth == 0 {
+                frame.lr
+            } else {
+                None
+            }
+        } else {
+            restore(step.ra, None)
+        };
+        let ra = match ra {
+            Some(ra) if ra != 0 => ra,
+            _ => break,
+        };
+        if ra == frame.pc && cfa == frame.sp {
+            break;
+        }
+        frame = Frame {
+            pc: ra,
+            sp: cfa,
+            fp: restore(step.fp, frame.fp),
+            lr: None,
+        };
+        chain.push(ra);
+    }
+    chain
+}
+
+/// Sample the registers and a copy of the stack, as
+/// the kernel does for an interrupted task.
+#[cfg(all(test, target_arch = "x86_64"))]
+#[inline(never)]
+fn sample_stack() -> (Registers, Vec<u8>) {
+    let mut stack = vec![0; 4096];
+    let (sp, fp, pc): (u64, u64, u64);
+    unsafe {
+        std::arch::asm!(
+            "mov {sp}, rsp",
+            "mov {fp}, rbp",
+            "lea {pc}, [rip]",
+            sp = out(reg) sp,
+            fp = out(reg) fp,
+            pc = out(reg) pc,
+        );
+        // Only frames above the stack pointer are copied,
+        // and they stay put while this function runs.
+        for (i, byte) in stack.iter_mut().enumerate() {
+            *byte = std::ptr::read_volatile((sp as usize + i) as *const u8);
+        }
+    }
+    let regs = Registers {
+        abi: 2,
+        values: vec![fp, sp, pc],
+    };
+    (regs, stack)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+#[test]
+fn unwind_test() {
+    use crate::event::records::{Mmap2, Record, SampleId};
+    // Map this executable as the kernel reported it.
+    let mut machine = Machine::default();
+    let pid = std::process::id();
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    for line in maps.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || !fields[1].contains('x') {
+            continue;
+        }
+        let (start, end) = fields[0].split_once('-').unwrap();
+        let start = u64::from_str_radix(start, 16).unwrap();
+        machine.apply(&Record::Mmap2(Mmap2 {
+            pid,
+            tid: pid,
+            addr: start,
+            len: u64::from_str_radix(end, 16).unwrap() - start,
+            pgoff: u64::from_str_radix(fields[2], 16).unwrap(),
+            maj: 0,
+            min: 0,
+            ino: 0,
+            ino_generation: 0,
+            build_id: None,
+            prot: 5,
+            flags: 2,
+            filename: fields[5].to_string(),
+            sample_id: SampleId::default(),
+        }));
+    }
+
+    let (regs, stack) = sample_stack();
+    let chain = unwind(&mut machine, pid, &regs, regs::unwind_mask(), &stack);
+    assert!(chain.len() > 2, "{:x?}", chain);
+    // The caller of `sample_stack` is this test.
+    let (_, symbol, _) = machine.locate_address(pid, chain[1] - 1);
+    assert!(symbol.unwrap().contains("unwind_test"));
+}
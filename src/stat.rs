@@ -1,86 +1,323 @@
 //! # Stat driver.
 //! <p> Usage: <em> ruperf stat [COMMAND] [ARGS] </em>
 //! Where COMMAND and ARGS are a shell command and it's arguments. </p>
+//! <p> Usage: <em> ruperf stat -p PID[,PID] | -t TID[,TID] </em>
+//! Attaches to running tasks and counts until interrupted. </p>
+//! <p> Usage: <em> ruperf stat -a | -C CPUS [COMMAND] [ARGS] </em>
+//! Counts every task on the given CPUs. </p>
+//! <p> Add <em> -I MSEC </em> to print counts every MSEC milliseconds,
+//! or <em> -r N </em> to report the mean of N runs of COMMAND.
+//! Reports are written as CSV with <em> -x SEP </em> or as JSON
+//! with <em> --json </em>. </p>
+
+mod interval;
+mod metrics;
+mod output;
+mod repeat;
+pub(crate) mod target;
+pub(crate) mod topology;
 
 extern crate structopt;
+use crate::event::breakpoint::{self, Breakpoint};
+use crate::event::catalog::{self, CacheId, CacheOp, CacheResult};
+use crate::event::modifiers::{self, Modifiers};
 use crate::event::open::*;
-use crate::utils::ParseError;
+use crate::event::pmu::{self, PmuEvent};
+use crate::event::probe::{self, ProbeEvent};
+use crate::event::tracepoint::{self, Tracepoint};
+use crate::utils::{parse_seconds, ParseError};
 use os_pipe::pipe;
+use std::collections::BTreeMap;
 use std::io::prelude::*;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Command;
 use std::str::{self, FromStr};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use target::IdList;
+use topology::CpuList;
 
-/// Supported events
-#[derive(Debug, Copy, Clone)]
+/// Supported events: every generic hardware, software
+/// and hardware cache event, see `ruperf list` for their
+/// names, and raw or PMU-specific events.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StatEvent {
+    // Generic hardware events.
     Cycles,
     Instructions,
+    CacheReferences,
+    CacheMisses,
+    BranchInstructions,
+    BranchMisses,
+    BusCycles,
+    StalledCyclesFrontend,
+    StalledCyclesBackend,
+    RefCycles,
+    // Software events.
+    CpuClock,
     TaskClock,
+    PageFaults,
     ContextSwitches,
-    L1DCacheRead,
-    L1DCacheWrite,
-    L1DCacheReadMiss,
-    L1ICacheReadMiss,
+    CpuMigrations,
+    MinorFaults,
+    MajorFaults,
+    AlignmentFaults,
+    EmulationFaults,
+    Dummy,
+    BpfOutput,
+    /// A hardware cache event, e.g. `L1-dcache-load-misses`.
+    HwCache(CacheId, CacheOp, CacheResult),
+    /// A raw config for the CPU's PMU, e.g. `r1a8`.
+    Raw(u64),
+    /// An event on a named PMU, e.g. `cpu/event=0x3c,umask=0x0/`.
+    Pmu(PmuEvent),
+    /// A tracepoint, e.g. `sched:sched_switch`.
+    Tracepoint(Tracepoint),
+    /// Every tracepoint matching a glob, e.g. `syscalls:sys_enter_*`.
+    /// Expanded into single tracepoints before counting.
+    Tracepoints(Vec<Tracepoint>),
+    /// A dynamic probe, e.g. `kprobe:do_sys_open` or `uprobe:/bin/app:main`.
+    Probe(ProbeEvent),
+    /// A hardware breakpoint, e.g. `mem:0x1000:rw/8` or `mem:my_static:w`.
+    Breakpoint(Breakpoint),
+    /// An event with modifiers, e.g. `cycles:u`.
+    Modified(Box<StatEvent>, Modifiers),
 }
 
-/// Match on each supported event to parse from command line
+/// Parse an event from the command line by any of its
+/// names or aliases, or in raw or PMU syntax, optionally
+/// followed by modifiers, as `perf` accepts them.
 impl FromStr for StatEvent {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "cycles" => Ok(StatEvent::Cycles),
-            "instructions" => Ok(StatEvent::Instructions),
-            "task-clock" => Ok(StatEvent::TaskClock),
-            "context-switches" => Ok(StatEvent::ContextSwitches),
-            "L1D-cache-reads" => Ok(StatEvent::L1DCacheRead),
-            "L1D-cache-writes" => Ok(StatEvent::L1DCacheWrite),
-            "L1D-cache-read-misses" => Ok(StatEvent::L1DCacheReadMiss),
-            "L1I-cache-read-misses" => Ok(StatEvent::L1ICacheReadMiss),
-            _ => Err(ParseError::InvalidEvent),
+        // `mem:u` watches a symbol `u` rather than modifying `mem`.
+        if let Some((base, modifiers)) = modifiers::split(s).filter(|(base, _)| *base != "mem") {
+            return match base.parse::<StatEvent>()? {
+                StatEvent::Modified(_, _) => Err(ParseError::InvalidEvent),
+                base => Ok(StatEvent::Modified(Box::new(base), modifiers)),
+            };
+        }
+        if let Some(event) = catalog::parse(s) {
+            Ok(event)
+        } else if let Some(config) = pmu::parse_raw(s) {
+            Ok(StatEvent::Raw(config))
+        } else if breakpoint::is_breakpoint(s) {
+            breakpoint::parse(s).map(StatEvent::Breakpoint)
+        } else if probe::is_probe(s) {
+            // A probe created with `perf probe` is already a tracepoint.
+            if s.starts_with("probe_") {
+                if let Ok(mut tracepoints) = tracepoint::parse(s) {
+                    return Ok(StatEvent::Tracepoint(tracepoints.remove(0)));
+                }
+            }
+            probe::parse(s).map(StatEvent::Probe)
+        } else if s.contains('/') {
+            pmu::parse(s).map(StatEvent::Pmu)
+        } else if s.contains(':') {
+            let mut tracepoints = tracepoint::parse(s)?;
+            if tracepoints.len() == 1 {
+                Ok(StatEvent::Tracepoint(tracepoints.remove(0)))
+            } else {
+                Ok(StatEvent::Tracepoints(tracepoints))
+            }
+        } else {
+            Err(ParseError::InvalidEvent)
         }
     }
 }
 
-/// Match on each supported event to parse from command line.
+/// The canonical name of an event.
 /// Note that the context-switches event runs in kernel mode and requires a perf_event_paranoid setting < 1.
 impl ToString for StatEvent {
     fn to_string(&self) -> String {
+        catalog::name(self)
+    }
+}
+
+impl StatEvent {
+    /// The event without its modifiers.
+    pub fn base(&self) -> &StatEvent {
         match self {
-            StatEvent::Cycles => "cycles".to_string(),
-            StatEvent::Instructions => "instructions".to_string(),
-            StatEvent::TaskClock => "task clock".to_string(),
-            StatEvent::ContextSwitches => "context switches".to_string(),
-            StatEvent::L1DCacheRead => "L1D-cache-reads".to_string(),
-            StatEvent::L1DCacheWrite => "L1D-cache-writes".to_string(),
-            StatEvent::L1DCacheReadMiss => "L1D-cache-read-misses".to_string(),
-            StatEvent::L1ICacheReadMiss => "L1I-cache-read-misses".to_string(),
+            StatEvent::Modified(base, _) => base,
+            _ => self,
+        }
+    }
+
+    /// Resolve a breakpoint on a symbol to its address in `pid`.
+    pub fn resolve(&self, pid: i32) -> Result<StatEvent, ParseError> {
+        match self {
+            StatEvent::Breakpoint(bp) => bp.resolve(pid).map(StatEvent::Breakpoint),
+            StatEvent::Modified(base, modifiers) => Ok(StatEvent::Modified(
+                Box::new(base.resolve(pid)?),
+                *modifiers,
+            )),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    /// Whether this is a breakpoint on a symbol, which can
+    /// only be resolved once the traced program is running.
+    pub fn needs_task(&self) -> bool {
+        matches!(self.base(), StatEvent::Breakpoint(bp) if bp.address().is_none())
+    }
+
+    /// Expand a glob of tracepoints into one event for each.
+    pub fn expand(self) -> Vec<StatEvent> {
+        match self {
+            StatEvent::Tracepoints(tracepoints) => {
+                tracepoints.into_iter().map(StatEvent::Tracepoint).collect()
+            }
+            StatEvent::Modified(base, modifiers) => base
+                .expand()
+                .into_iter()
+                .map(|event| StatEvent::Modified(Box::new(event), modifiers))
+                .collect(),
+            event => vec![event],
         }
     }
 }
 
 /// Configuration settings for running stat. A program to profile is a required
-/// argument. Default events will run on that program if no events are
-/// specified. Specify events using the flag `-e or --event`. See `./ruperf stat
-/// --help' for more information.
+/// argument, unless attaching to running tasks with `-p` or `-t` or counting
+/// system-wide with `-a` or `-C`. Default events will run on that program if
+/// no events are specified. Specify events using
+/// the flag `-e or --event`. See `./ruperf stat --help' for more information.
 
 #[derive(Debug, StructOpt)]
 pub struct StatOptions {
     #[structopt(short, long, help = "Event to collect", number_of_values = 1)]
     pub event: Vec<StatEvent>,
 
+    #[structopt(long, help = "Count each event independently instead of as one group")]
+    pub no_group: bool,
+
+    #[structopt(
+        short,
+        long,
+        help = "Attach to running processes, counting all their threads",
+        conflicts_with = "tid"
+    )]
+    pub pid: Option<IdList>,
+
+    #[structopt(short, long, help = "Attach to running threads")]
+    pub tid: Option<IdList>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Count every task on all CPUs",
+        conflicts_with_all = &["pid", "tid"]
+    )]
+    pub all_cpus: bool,
+
+    #[structopt(
+        short = "C",
+        long,
+        help = "Count every task on the listed CPUs, e.g. 0-3,7",
+        conflicts_with_all = &["pid", "tid", "all-cpus"]
+    )]
+    pub cpu: Option<CpuList>,
+
+    #[structopt(
+        long,
+        help = "Report counts for each CPU",
+        conflicts_with_all = &["per-core", "per-socket"]
+    )]
+    pub per_cpu: bool,
+
+    #[structopt(
+        long,
+        help = "Report counts for each physical core",
+        conflicts_with = "per-socket"
+    )]
+    pub per_core: bool,
+
+    #[structopt(long, help = "Report counts for each socket")]
+    pub per_socket: bool,
+
+    #[structopt(
+        long,
+        help = "Stop counting attached tasks after this many seconds",
+        value_name = "SECS",
+        parse(try_from_str = parse_seconds)
+    )]
+    pub duration: Option<Duration>,
+
+    #[structopt(
+        short = "I",
+        long,
+        help = "Print counts every MSEC milliseconds while counting",
+        value_name = "MSEC"
+    )]
+    pub interval_print: Option<u64>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Run the command N times and report the mean and deviation",
+        value_name = "N",
+        default_value = "1"
+    )]
+    pub repeat: usize,
+
+    #[structopt(
+        long,
+        help = "Discard the first K runs when repeating",
+        value_name = "K",
+        default_value = "0"
+    )]
+    pub warmup: usize,
+
+    #[structopt(
+        short = "x",
+        long,
+        help = "Print counts as fields separated by SEP",
+        value_name = "SEP",
+        conflicts_with = "json"
+    )]
+    pub field_separator: Option<String>,
+
+    #[structopt(long, help = "Print counts as JSON")]
+    pub json: bool,
+
+    #[structopt(
+        short,
+        long,
+        help = "Write the report to FILE instead of stdout",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    pub output: Option<PathBuf>,
+
     // Allows multiple arguments to be passed, collects everything remaining on
     // the command line
-    #[structopt(required = true, help = "Command to run")]
+    #[structopt(
+        required_unless_one = &["pid", "tid", "all-cpus", "cpu"],
+        conflicts_with_all = &["pid", "tid"],
+        help = "Command to run"
+    )]
     pub command: Vec<String>,
 }
 
 pub fn launch_command_process(
+    command: Vec<String>,
+    child_reader: os_pipe::PipeReader,
+    child_writer: os_pipe::PipeWriter,
+) -> i32 {
+    spawn_command(command, child_reader, child_writer, false)
+}
+
+/// Fork a child as `launch_command_process` does. When `traced`,
+/// the child also stops right after it executes the command,
+/// until the parent detaches from it with `PTRACE_DETACH`.
+pub(crate) fn spawn_command(
     command: Vec<String>,
     mut child_reader: os_pipe::PipeReader,
     mut child_writer: os_pipe::PipeWriter,
+    traced: bool,
 ) -> i32 {
     match unsafe { libc::fork() as i32 } {
         0 => {
@@ -97,6 +334,16 @@ pub fn launch_command_process(
             let nread = child_reader.read(&mut buf).unwrap();
             assert_eq!(nread, 1);
 
+            if traced {
+                unsafe {
+                    libc::ptrace(
+                        libc::PTRACE_TRACEME,
+                        0,
+                        std::ptr::null_mut::<libc::c_void>(),
+                        std::ptr::null_mut::<libc::c_void>(),
+                    );
+                }
+            }
             let e = comm.exec();
             panic!("child command failed: {}", e);
         }
@@ -104,88 +351,907 @@ pub fn launch_command_process(
     }
 }
 
-/// Run perf stat on the given command and event combinations.
-/// Currently starts and stops a cycles timer in serial for each event specified.
-pub fn run_stat(options: StatOptions) {
-    // In future rather than starting and stopping counter
-    // in series for each event, events will have the ability
-    // to be added in groups that will coordinate their timing.
-    let mut options = options;
+/// The counters opened for a stat run. Events are grouped
+/// under one leader when the kernel can schedule them together,
+/// and otherwise fall back to being counted independently.
+enum Counters {
+    Group(EventGroup),
+    Independent(Vec<Event>),
+}
+
+/// The counters opened on one target, along with which
+/// events could be opened at all. Events the kernel or CPU
+/// does not support are left out, and reported as such.
+struct TargetCounters {
+    target: Target,
+    counters: Counters,
+    supported: Vec<bool>,
+    /// The last counts read, kept once a task has exited.
+    last: Vec<CounterValue>,
+}
 
-    struct EventCounter {
-        event: Event,
-        start: isize,
-        stop: isize,
+impl Counters {
+    /// Open counters for `events` on `target`, grouping them
+    /// unless `no_group` is set. Fails on the first event that
+    /// could not be opened, which is returned with the error.
+    fn new(
+        events: &[StatEvent],
+        target: Target,
+        no_group: bool,
+    ) -> Result<Self, (StatEvent, OpenError)> {
+        if !no_group {
+            if let Ok(group) = EventGroup::new(events, Some(target.pid), target.cpu) {
+                return Ok(Counters::Group(group));
+            }
+        }
+        Self::independent(events, target)
     }
 
-    let mut event_list: Vec<EventCounter> = Vec::new();
+    /// Open each of `events` on `target` on its own.
+    fn independent(events: &[StatEvent], target: Target) -> Result<Self, (StatEvent, OpenError)> {
+        let events = events
+            .iter()
+            .map(|e| {
+                Event::on_cpu(e.clone(), Some(target.pid), target.cpu)
+                    .map_err(|err| (e.clone(), err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Counters::Independent(events))
+    }
 
-    let (reader, mut writer) = pipe().unwrap();
-    let (mut parent_reader, parent_writer) = pipe().unwrap();
+    /// Start all counters, returning their initial counts.
+    fn start(&self) -> Result<Vec<CounterValue>, SysErr> {
+        match self {
+            Counters::Group(group) => group.start_counters(),
+            Counters::Independent(events) => events.iter().map(Event::start_counter).collect(),
+        }
+    }
 
-    let child_reader = reader.try_clone().unwrap();
-    let child_writer = parent_writer.try_clone().unwrap();
-    let pid_child = launch_command_process(options.command.clone(), child_reader, child_writer);
+    /// Read all counters without stopping them.
+    fn read(&self) -> Result<Vec<CounterValue>, SysErr> {
+        match self {
+            Counters::Group(group) => group.read_counters(),
+            Counters::Independent(events) => events.iter().map(Event::read_counter).collect(),
+        }
+    }
+
+    /// Stop all counters, returning their final counts.
+    fn stop(&self) -> Result<Vec<CounterValue>, SysErr> {
+        match self {
+            Counters::Group(group) => group.stop_counters(),
+            Counters::Independent(events) => events.iter().map(Event::stop_counter).collect(),
+        }
+    }
+}
+
+impl TargetCounters {
+    /// Open counters for `events` on `target`. Events that fail
+    /// to open as not supported are left out and the rest opened
+    /// again; any other failure, such as missing permissions, is
+    /// returned along with the event that caused it.
+    fn new(
+        events: &[StatEvent],
+        target: Target,
+        no_group: bool,
+    ) -> Result<Self, (StatEvent, OpenError)> {
+        let mut supported = vec![true; events.len()];
+        let counters = loop {
+            let open: Vec<StatEvent> = events
+                .iter()
+                .zip(&supported)
+                .filter(|(_, ok)| **ok)
+                .map(|(event, _)| event.clone())
+                .collect();
+            let (event, e) = match Counters::new(&open, target, no_group || open.is_empty()) {
+                Ok(counters) => break counters,
+                Err(failed) => failed,
+            };
+            let failed = events
+                .iter()
+                .zip(&supported)
+                .position(|(opened, ok)| *ok && *opened == event);
+            match failed {
+                Some(i) if e.is_not_supported() => supported[i] = false,
+                _ => return Err((event, e)),
+            }
+        };
+        Ok(Self {
+            target,
+            counters,
+            last: vec![CounterValue::default(); supported.len()],
+            supported,
+        })
+    }
+
+    /// Counts read from these counters, along with which
+    /// events they cover and how they were opened.
+    fn counts(&self, counts: Vec<CounterValue>) -> TargetCounts {
+        TargetCounts {
+            target: self.target,
+            counts,
+            supported: self.supported.clone(),
+            grouped: matches!(self.counters, Counters::Group(_)),
+        }
+    }
+
+    /// Spread the counts of the opened events back over
+    /// every event, with zero for those not supported.
+    fn fill(&self, counts: Vec<CounterValue>) -> Vec<CounterValue> {
+        let mut counts = counts.into_iter();
+        self.supported
+            .iter()
+            .map(|ok| match ok {
+                true => counts.next().unwrap_or_default(),
+                false => CounterValue::default(),
+            })
+            .collect()
+    }
+
+    /// Apply `read` to the opened counters and spread the counts
+    /// over every event. When a task has exited and its counters
+    /// can no longer be read, the last counts read are kept.
+    fn read_with(
+        &mut self,
+        read: impl Fn(&Counters) -> Result<Vec<CounterValue>, SysErr>,
+    ) -> Result<Vec<CounterValue>, SysErr> {
+        match read(&self.counters) {
+            Ok(counts) => {
+                self.last = self.fill(counts);
+                Ok(self.last.clone())
+            }
+            Err(_) if self.target.pid > 0 && !target::is_alive(self.target.pid) => {
+                Ok(self.last.clone())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Start all counters, returning their initial counts.
+    fn start(&mut self) -> Result<Vec<CounterValue>, SysErr> {
+        self.read_with(Counters::start)
+    }
+
+    /// Read all counters without stopping them.
+    fn read(&mut self) -> Result<Vec<CounterValue>, SysErr> {
+        self.read_with(Counters::read)
+    }
+
+    /// Stop all counters, returning their final counts.
+    fn stop(&mut self) -> Result<Vec<CounterValue>, SysErr> {
+        self.read_with(Counters::stop)
+    }
+}
+
+/// A task and CPU pair that counters are opened on.
+/// A `pid` of -1 counts every task on `cpu`, and a
+/// `cpu` of -1 follows `pid` onto any CPU.
+#[derive(Debug, Copy, Clone)]
+struct Target {
+    pid: i32,
+    cpu: i32,
+}
+
+/// The counts read from one `Target` over a stat run,
+/// and which of its events could be counted at all.
+struct TargetCounts {
+    target: Target,
+    counts: Vec<CounterValue>,
+    supported: Vec<bool>,
+    /// Whether the events were counted as one group.
+    grouped: bool,
+}
+
+impl TargetCounts {
+    /// The change in these counts since `earlier` was read.
+    fn since(&self, earlier: &[CounterValue]) -> TargetCounts {
+        TargetCounts {
+            target: self.target,
+            counts: self
+                .counts
+                .iter()
+                .zip(earlier)
+                .map(|(now, then)| now.delta(then))
+                .collect(),
+            supported: self.supported.clone(),
+            grouped: self.grouped,
+        }
+    }
+
+    /// Whether several events had to be counted independently.
+    fn ungrouped(&self) -> bool {
+        !self.grouped && self.supported.iter().filter(|ok| **ok).count() > 1
+    }
+
+    /// Whether the events were counted as a group that was
+    /// enabled but never got onto the PMU, as happens when it
+    /// needs more counters than the PMU has free. Every member
+    /// then reads as not counted.
+    fn unscheduled(&self) -> bool {
+        self.grouped
+            && self
+                .counts
+                .iter()
+                .zip(&self.supported)
+                .any(|(count, ok)| *ok && count.enabled > 0 && count.running == 0)
+    }
+}
+
+/// Everything measured over one stat run.
+struct StatRun {
+    counts: Vec<TargetCounts>,
+    elapsed: Duration,
+    exit_status: Option<i32>,
+}
+
+/// The count collected for one event over a stat run.
+struct EventCounter {
+    event: StatEvent,
+    count: CounterValue,
+    supported: bool,
+}
+
+impl EventCounter {
+    /// Whether the event was counted at all: it is supported,
+    /// and it was on the PMU for some of the time it was enabled.
+    fn counted(&self) -> bool {
+        self.supported && !(self.count.enabled > 0 && self.count.running == 0)
+    }
+}
+
+/// How counts from several CPUs are combined in the report.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Aggregation {
+    Global,
+    Cpu,
+    Core,
+    Socket,
+}
+
+/// Set by the SIGINT handler to end counting on attached tasks.
+pub(crate) static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Run perf stat on the given command and event combinations,
+/// on already running tasks when `-p` or `-t` is given, or on
+/// every task of some CPUs when `-a` or `-C` is given.
+/// Events are counted as a single group by default so they
+/// all cover the same window; pass `--no-group` to count
+/// them independently.
+pub fn run_stat(options: StatOptions) {
+    let mut options = options;
+    options.event = options
+        .event
+        .into_iter()
+        .flat_map(StatEvent::expand)
+        .collect();
 
     if options.event.is_empty() {
         options.event.push(StatEvent::Cycles);
         options.event.push(StatEvent::Instructions);
         options.event.push(StatEvent::TaskClock);
         options.event.push(StatEvent::ContextSwitches);
-        options.event.push(StatEvent::L1DCacheRead);
-        options.event.push(StatEvent::L1DCacheWrite);
-        options.event.push(StatEvent::L1DCacheReadMiss);
-        options.event.push(StatEvent::L1ICacheReadMiss);
+        options.event.push(StatEvent::HwCache(
+            CacheId::L1D,
+            CacheOp::Read,
+            CacheResult::Access,
+        ));
+        options.event.push(StatEvent::HwCache(
+            CacheId::L1D,
+            CacheOp::Write,
+            CacheResult::Access,
+        ));
+        options.event.push(StatEvent::HwCache(
+            CacheId::L1D,
+            CacheOp::Read,
+            CacheResult::Miss,
+        ));
+        options.event.push(StatEvent::HwCache(
+            CacheId::L1I,
+            CacheOp::Read,
+            CacheResult::Miss,
+        ));
     }
 
-    for event in &options.event {
-        event_list.push(EventCounter {
-            event: Event::new(*event, Some(pid_child)),
-            start: 0,
-            stop: 0,
-        });
+    let aggregation = aggregation(&options);
+    let cpus = match &options.cpu {
+        Some(cpus) => Some(cpus.0.clone()),
+        None if options.all_cpus => Some(topology::online_cpus()),
+        None => None,
+    };
+    if aggregation != Aggregation::Global && cpus.is_none() {
+        eprintln!("--per-cpu, --per-core and --per-socket need -a or -C");
+        std::process::exit(1);
+    }
+    let system_wide = |cpus: &[i32]| -> Vec<Target> {
+        cpus.iter()
+            .map(|cpu| Target { pid: -1, cpu: *cpu })
+            .collect()
+    };
+    let per_task = |tids: &[i32]| -> Vec<Target> {
+        tids.iter()
+            .map(|tid| Target { pid: *tid, cpu: -1 })
+            .collect()
+    };
+
+    if options.repeat < 1 {
+        eprintln!("--repeat needs at least one run");
+        std::process::exit(1);
+    }
+
+    let mut out = output::open(&options);
+
+    if options.repeat > 1 || options.warmup > 0 {
+        if options.command.is_empty() {
+            eprintln!("--repeat and --warmup need a command to run");
+            std::process::exit(1);
+        }
+        // A default value always counts as given, so this
+        // cannot be left to `conflicts_with`.
+        if aggregation != Aggregation::Global {
+            eprintln!(
+                "--repeat and --warmup report totals, not --per-cpu, --per-core or --per-socket"
+            );
+            std::process::exit(1);
+        }
+        let result = repeat::run_repeated(&mut *out, &options, cpus.map(|cpus| system_wide(&cpus)));
+        exit_unwritten(result);
+        return;
+    }
+
+    let (label, run) = if let Some(pids) = &options.pid {
+        let tids: Vec<i32> = pids
+            .0
+            .iter()
+            .flat_map(|pid| target::threads_of(*pid))
+            .collect();
+        let run = count_until_done(&mut *out, &options, &per_task(&tids), &tids);
+        (format!("process id '{}'", join_ids(&pids.0)), run)
+    } else if let Some(tids) = &options.tid {
+        let run = count_until_done(&mut *out, &options, &per_task(&tids.0), &tids.0);
+        (format!("thread id '{}'", join_ids(&tids.0)), run)
+    } else if options.command.is_empty() {
+        let cpus = cpus.unwrap_or_else(topology::online_cpus);
+        let run = count_until_done(&mut *out, &options, &system_wide(&cpus), &[]);
+        ("system wide".to_string(), run)
+    } else {
+        let targets = cpus.map(|cpus| system_wide(&cpus));
+        let run = count_command(&mut *out, &options, targets);
+        (format!("'{}'", options.command[0]), run)
+    };
+
+    let sections = aggregate(&options.event, &run.counts, aggregation);
+    let result = output::print_report(&mut *out, &options, &label, &sections, &run);
+    exit_unwritten(result);
+    for note in grouping_notes(options.no_group, &run.counts) {
+        eprintln!("{}", note);
+    }
+}
+
+/// Exit if the report could not be written, unless a
+/// reader such as `head` stopped early.
+fn exit_unwritten(result: std::io::Result<()>) {
+    if let Err(e) = result {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            eprintln!("Could not write the report: {}", e);
+            std::process::exit(1);
+        }
     }
+}
+
+/// Exit if interval counts could not be written. Once a reader
+/// such as `head` has stopped there is no one to print for, so
+/// counting ends quietly.
+fn exit_interval_unwritten(result: std::io::Result<()>) {
+    if result.is_err() {
+        exit_unwritten(result);
+        std::process::exit(0);
+    }
+}
+
+/// The aggregation requested on the command line.
+fn aggregation(options: &StatOptions) -> Aggregation {
+    if options.per_cpu {
+        Aggregation::Cpu
+    } else if options.per_core {
+        Aggregation::Core
+    } else if options.per_socket {
+        Aggregation::Socket
+    } else {
+        Aggregation::Global
+    }
+}
+
+/// Launch the command in `options` and count it until it exits.
+/// When `targets` is given those are counted instead of the command,
+/// so that it bounds a system-wide measurement.
+fn count_command(
+    out: &mut dyn Write,
+    options: &StatOptions,
+    targets: Option<Vec<Target>>,
+) -> StatRun {
+    let (reader, mut writer) = pipe().unwrap();
+    let (mut parent_reader, parent_writer) = pipe().unwrap();
+
+    let child_reader = reader.try_clone().unwrap();
+    let child_writer = parent_writer.try_clone().unwrap();
+    // Symbols can only be found once the command is loaded, so
+    // stop it after exec and open the counters at that point.
+    let traced = options.event.iter().any(StatEvent::needs_task);
+    let pid_child = spawn_command(options.command.clone(), child_reader, child_writer, traced);
 
     // Wait for child to say it is set up to execute.
     let mut buf = [0];
     let nread = parent_reader.read(&mut buf).unwrap();
     assert_eq!(nread, 1);
 
-    for e in event_list.iter_mut() {
-        e.start = e.event.start_counter().unwrap();
-    }
+    let events = if traced {
+        writer.write_all(&[1]).unwrap();
+        let mut status: libc::c_int = 0;
+        unsafe { libc::waitpid(pid_child, &mut status, 0) };
+        if !libc::WIFSTOPPED(status) {
+            eprintln!("Could not run '{}'", options.command[0]);
+            std::process::exit(1);
+        }
+        resolve_events(&options.event, pid_child).unwrap_or_else(|e| {
+            unsafe { libc::kill(pid_child, libc::SIGKILL) };
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    } else {
+        options.event.clone()
+    };
+    let targets = targets.unwrap_or_else(|| {
+        vec![Target {
+            pid: pid_child,
+            cpu: -1,
+        }]
+    });
+    let mut counters = open_counters(&events, options.no_group, &targets);
+
+    let start = read_all(&mut counters, TargetCounters::start);
     let now = Instant::now();
     // Notify child counters are set up.
-    writer.write_all(&[1]).unwrap();
+    if traced {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_DETACH,
+                pid_child,
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            );
+        }
+    } else {
+        writer.write_all(&[1]).unwrap();
+    }
     drop(writer);
 
     // Wait for process to exit.
     let mut status: libc::c_int = 0;
-    let result = unsafe { libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, 0) };
-    assert_eq!(result, pid_child);
+    if options.interval_print.is_some() {
+        let exited = || {
+            let result = unsafe { libc::waitpid(pid_child, &mut status, libc::WNOHANG) };
+            result == pid_child
+        };
+        poll_until(out, options, &mut counters, &start, now, exited);
+    } else {
+        let result = unsafe { libc::waitpid(pid_child, (&mut status) as *mut libc::c_int, 0) };
+        assert_eq!(result, pid_child);
+    }
     let t = now.elapsed();
-    for e in event_list.iter_mut() {
-        e.stop = e.event.stop_counter().unwrap();
+    let stop = read_all(&mut counters, TargetCounters::stop);
+
+    // Report signals the way shells do, as 128 + signal number.
+    let exit_status = if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    };
+    StatRun {
+        counts: target_counts(&counters, start, stop),
+        elapsed: t,
+        exit_status: Some(exit_status),
     }
+}
 
-    println!(
-        "Performance counter stats for '{}:'\n",
-        options.command.get(0).unwrap()
-    );
+/// Count `targets` until SIGINT, until `--duration` has passed,
+/// or until every thread in `tids` has exited. With no `tids`,
+/// as when counting system-wide, only the first two apply.
+fn count_until_done(
+    out: &mut dyn Write,
+    options: &StatOptions,
+    targets: &[Target],
+    tids: &[i32],
+) -> StatRun {
+    let mut counters = open_counters(&options.event, options.no_group, targets);
 
-    for event in event_list {
-        if matches!(event.event.event, StatEvent::TaskClock) {
-            println!(
-                " {:.2} msec task-clock\n CPU utilized: {:.3}",
-                (event.stop - event.start) as f64 / 1_000_000.0,
-                (event.stop - event.start) as f64 / t.as_nanos() as f64
-            );
-        } else {
-            println!(
-                " Number of {}: {}",
-                event.event.event.to_string(),
-                event.stop - event.start
-            );
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let deadline = options.duration;
+
+    let start = read_all(&mut counters, TargetCounters::start);
+    let now = Instant::now();
+    let timed_out = || matches!(deadline, Some(d) if now.elapsed() >= d);
+    let exited = || !tids.is_empty() && !tids.iter().any(|tid| target::is_alive(*tid));
+    let done = || INTERRUPTED.load(Ordering::SeqCst) || timed_out() || exited();
+    poll_until(out, options, &mut counters, &start, now, done);
+    let t = now.elapsed();
+    let stop = read_all(&mut counters, TargetCounters::stop);
+
+    StatRun {
+        counts: target_counts(&counters, start, stop),
+        elapsed: t,
+        exit_status: None,
+    }
+}
+
+/// Wait until `done` returns true, printing the counts of each
+/// interval along the way when `-I` is given. `now` marks when
+/// counting began and `start` holds the counts read at that time.
+fn poll_until(
+    out: &mut dyn Write,
+    options: &StatOptions,
+    counters: &mut [TargetCounters],
+    start: &[Vec<CounterValue>],
+    now: Instant,
+    mut done: impl FnMut() -> bool,
+) {
+    let mut printer = options
+        .interval_print
+        .map(|msec| interval::IntervalPrinter::new(options, msec, start.to_vec()));
+    if let Some(printer) = &printer {
+        exit_interval_unwritten(printer.print_header(out));
+    }
+    // Poll often enough to keep interval timestamps accurate.
+    let step = match printer {
+        Some(_) => Duration::from_millis(10),
+        None => Duration::from_millis(100),
+    };
+    while !done() {
+        if let Some(printer) = printer.as_mut() {
+            if printer.is_due(now.elapsed()) {
+                let current = read_all(counters, TargetCounters::read);
+                let current = counters
+                    .iter()
+                    .zip(current)
+                    .map(|(counters, counts)| counters.counts(counts))
+                    .collect();
+                let result = printer.print(out, &options.event, current, now.elapsed());
+                exit_interval_unwritten(result);
+            }
         }
+        thread::sleep(step);
     }
 }
+
+/// Apply `read` to the counters of every target. Exits with an
+/// explanation if any cannot be read, other than those of a
+/// task that has exited.
+fn read_all(
+    counters: &mut [TargetCounters],
+    read: impl Fn(&mut TargetCounters) -> Result<Vec<CounterValue>, SysErr>,
+) -> Vec<Vec<CounterValue>> {
+    counters
+        .iter_mut()
+        .map(|c| {
+            read(c).unwrap_or_else(|e| {
+                eprintln!("Error: could not read counters: {}", e);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+/// Open one set of counters for each target, resolving
+/// breakpoint symbols in each target's task. Exits with
+/// an explanation if an event cannot be opened for any
+/// reason other than not being supported.
+fn open_counters(events: &[StatEvent], no_group: bool, targets: &[Target]) -> Vec<TargetCounters> {
+    targets
+        .iter()
+        .map(|target| {
+            let events = resolve_events(events, target.pid).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            TargetCounters::new(&events, *target, no_group).unwrap_or_else(|(event, e)| {
+                eprintln!("Error: could not open {}: {}", event.to_string(), e);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+/// Notes on how events were grouped over a run, printed
+/// after its report: that some could not be opened as a
+/// group, or that a group never got onto the PMU.
+fn grouping_notes(no_group: bool, runs: &[TargetCounts]) -> Vec<&'static str> {
+    let mut notes = Vec::new();
+    if !no_group && runs.iter().any(TargetCounts::ungrouped) {
+        notes.push("Could not open events as a group, counted them independently");
+    }
+    if runs.iter().any(TargetCounts::unscheduled) {
+        notes.push(
+            "Some events were not counted as their group never got onto the PMU, try --no-group",
+        );
+    }
+    notes
+}
+
+/// Resolve every breakpoint symbol in `events` in the task `pid`.
+pub(crate) fn resolve_events(events: &[StatEvent], pid: i32) -> Result<Vec<StatEvent>, ParseError> {
+    events.iter().map(|event| event.resolve(pid)).collect()
+}
+
+/// Pair each target with the counts it accumulated
+/// between `start` and `stop`.
+fn target_counts(
+    counters: &[TargetCounters],
+    start: Vec<Vec<CounterValue>>,
+    stop: Vec<Vec<CounterValue>>,
+) -> Vec<TargetCounts> {
+    counters
+        .iter()
+        .zip(start.into_iter().zip(stop))
+        .map(|(counters, (start, stop))| counters.counts(stop).since(&start))
+        .collect()
+}
+
+/// The label of a report section, the summed count of each
+/// event, and whether each event was supported on any target.
+type Section = (String, Vec<CounterValue>, Vec<bool>);
+
+/// Sum the counts of every target into one `EventCounter`
+/// per event, for each label of the chosen aggregation.
+/// Labels are empty for `Aggregation::Global`.
+fn aggregate(
+    events: &[StatEvent],
+    runs: &[TargetCounts],
+    aggregation: Aggregation,
+) -> Vec<(String, Vec<EventCounter>)> {
+    aggregate_on(events, runs, aggregation, topology::topology)
+}
+
+/// Aggregate as `aggregate` does, placing each CPU in
+/// the machine by `topology`.
+fn aggregate_on(
+    events: &[StatEvent],
+    runs: &[TargetCounts],
+    aggregation: Aggregation,
+    topology: impl Fn(i32) -> topology::CpuTopology,
+) -> Vec<(String, Vec<EventCounter>)> {
+    // Sort on the numeric key so CPU10 follows CPU9.
+    let mut sections: BTreeMap<(i32, i32), Section> = BTreeMap::new();
+    for run in runs {
+        let cpu = topology(run.target.cpu);
+        let (key, label) = match aggregation {
+            Aggregation::Global => ((0, 0), String::new()),
+            Aggregation::Cpu => ((cpu.cpu, 0), format!("CPU{}", cpu.cpu)),
+            Aggregation::Core => (
+                (cpu.socket, cpu.core),
+                format!("S{}-C{}", cpu.socket, cpu.core),
+            ),
+            Aggregation::Socket => ((cpu.socket, 0), format!("S{}", cpu.socket)),
+        };
+        let section = sections.entry(key).or_insert_with(|| {
+            (
+                label,
+                vec![CounterValue::default(); events.len()],
+                vec![false; events.len()],
+            )
+        });
+        for (total, count) in section.1.iter_mut().zip(&run.counts) {
+            *total += *count;
+        }
+        // Supported on any CPU of the section is enough to report it.
+        for (any, supported) in section.2.iter_mut().zip(&run.supported) {
+            *any |= *supported;
+        }
+    }
+    sections
+        .into_iter()
+        .map(|(_, (label, counts, supported))| {
+            let counters = events
+                .iter()
+                .zip(counts.into_iter().zip(supported))
+                .map(|(event, (count, supported))| EventCounter {
+                    event: event.clone(),
+                    count,
+                    supported,
+                })
+                .collect();
+            (label, counters)
+        })
+        .collect()
+}
+
+/// Format task IDs as they were given on the command line.
+fn join_ids(ids: &[i32]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Counts of a target on `cpu`, with `None` for events
+/// that were not supported.
+#[cfg(test)]
+fn test_counts(cpu: i32, counts: &[Option<CounterValue>], grouped: bool) -> TargetCounts {
+    TargetCounts {
+        target: Target { pid: -1, cpu },
+        counts: counts.iter().map(|c| c.unwrap_or_default()).collect(),
+        supported: counts.iter().map(Option::is_some).collect(),
+        grouped,
+    }
+}
+
+/// A count that was on the PMU for `running` of `enabled`.
+#[cfg(test)]
+fn test_value(raw: u64, enabled: u64, running: u64) -> Option<CounterValue> {
+    Some(CounterValue {
+        raw,
+        enabled,
+        running,
+    })
+}
+
+/// Two sockets of two cores of two hyperthreads each: CPUs
+/// 0-3 on socket 0 and 4-7 on socket 1, with CPU n and
+/// n + 2 sharing a core.
+#[cfg(test)]
+fn test_topology(cpu: i32) -> topology::CpuTopology {
+    topology::CpuTopology {
+        cpu,
+        core: cpu % 2,
+        socket: cpu / 4,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn aggregate_test() {
+    let events = [StatEvent::PageFaults, StatEvent::Cycles];
+    // Cycles is only supported on CPUs 2 and 6.
+    let runs: Vec<TargetCounts> = (0..8)
+        .map(|cpu| {
+            let cycles = match cpu {
+                2 | 6 => test_value(1000, 10, 10),
+                _ => None,
+            };
+            test_counts(cpu, &[test_value(cpu as u64 + 1, 10, 10), cycles], true)
+        })
+        .collect();
+    let totals = |aggregation| -> Vec<(String, Vec<u64>, Vec<bool>)> {
+        aggregate_on(&events, &runs, aggregation, test_topology)
+            .into_iter()
+            .map(|(label, counters)| {
+                let raw = counters.iter().map(|c| c.count.raw).collect();
+                let supported = counters.iter().map(|c| c.supported).collect();
+                (label, raw, supported)
+            })
+            .collect()
+    };
+
+    let global = totals(Aggregation::Global);
+    assert_eq!(global.len(), 1);
+    assert_eq!(global[0], (String::new(), vec![36, 2000], vec![true, true]));
+
+    let cpus = totals(Aggregation::Cpu);
+    assert_eq!(cpus.len(), 8);
+    assert_eq!(cpus[0], ("CPU0".to_string(), vec![1, 0], vec![true, false]));
+    assert_eq!(
+        cpus[2],
+        ("CPU2".to_string(), vec![3, 1000], vec![true, true])
+    );
+
+    let cores = totals(Aggregation::Core);
+    let labels: Vec<&str> = cores.iter().map(|c| c.0.as_str()).collect();
+    assert_eq!(labels, ["S0-C0", "S0-C1", "S1-C0", "S1-C1"]);
+    assert_eq!(cores[0].1, vec![1 + 3, 1000]);
+    assert_eq!(
+        cores[1],
+        ("S0-C1".to_string(), vec![2 + 4, 0], vec![true, false])
+    );
+    assert_eq!(cores[3].1, vec![6 + 8, 0]);
+
+    let sockets = totals(Aggregation::Socket);
+    assert_eq!(sockets.len(), 2);
+    assert_eq!(
+        sockets[0],
+        ("S0".to_string(), vec![10, 1000], vec![true, true])
+    );
+    assert_eq!(
+        sockets[1],
+        ("S1".to_string(), vec![26, 1000], vec![true, true])
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn aggregate_cpu_order_test() {
+    let events = [StatEvent::PageFaults];
+    let runs: Vec<TargetCounts> = [10, 9, 1]
+        .iter()
+        .map(|cpu| test_counts(*cpu, &[test_value(1, 1, 1)], true))
+        .collect();
+    let labels: Vec<String> = aggregate_on(&events, &runs, Aggregation::Cpu, test_topology)
+        .into_iter()
+        .map(|(label, _)| label)
+        .collect();
+    assert_eq!(labels, ["CPU1", "CPU9", "CPU10"]);
+}
+
+#[cfg(test)]
+#[test]
+fn target_counters_fill_test() {
+    let mut counters = TargetCounters {
+        target: Target {
+            pid: i32::MAX,
+            cpu: -1,
+        },
+        counters: Counters::Independent(Vec::new()),
+        supported: vec![true, false, true],
+        last: vec![CounterValue::default(); 3],
+    };
+    let read = test_value(5, 2, 1).unwrap();
+    let filled = counters.fill(vec![read, read]);
+    assert_eq!(filled, vec![read, CounterValue::default(), read]);
+    // Missing counts read as zero rather than shifting the rest.
+    assert_eq!(counters.fill(vec![read])[2], CounterValue::default());
+
+    assert_eq!(
+        counters.read_with(|_| Ok(vec![read, read])).unwrap(),
+        filled
+    );
+    // The task is gone, so the last counts are kept.
+    let kept = counters.read_with(|_| Err(SysErr::ReadFail)).unwrap();
+    assert_eq!(kept, filled);
+    counters.target.pid = -1;
+    assert!(counters.read_with(|_| Err(SysErr::ReadFail)).is_err());
+
+    let counts = counters.counts(filled.clone());
+    assert!(!counts.grouped);
+    assert_eq!(
+        counts.since(&filled).counts,
+        vec![CounterValue::default(); 3]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn grouping_notes_test() {
+    let counted = test_counts(0, &[test_value(5, 10, 10), test_value(7, 10, 10)], true);
+    assert!(!counted.unscheduled());
+    assert!(!counted.ungrouped());
+    assert!(grouping_notes(false, std::slice::from_ref(&counted)).is_empty());
+
+    // Multiplexed groups still ran for part of the time.
+    let multiplexed = test_counts(0, &[test_value(5, 10, 4), test_value(7, 10, 4)], true);
+    assert!(!multiplexed.unscheduled());
+
+    let unscheduled = test_counts(1, &[test_value(0, 10, 0), test_value(0, 10, 0)], true);
+    assert!(unscheduled.unscheduled());
+    let notes = grouping_notes(false, &[counted, unscheduled]);
+    assert_eq!(notes.len(), 1);
+    assert!(notes[0].contains("--no-group"));
+
+    // Independent counters are never reported as an unscheduled group.
+    let independent = test_counts(0, &[test_value(0, 10, 0), test_value(7, 10, 10)], false);
+    assert!(!independent.unscheduled());
+    assert!(independent.ungrouped());
+    assert_eq!(
+        grouping_notes(false, std::slice::from_ref(&independent)).len(),
+        1
+    );
+    assert!(grouping_notes(true, &[independent]).is_empty());
+
+    // One event counted alone was never grouped to begin with.
+    let single = test_counts(0, &[test_value(7, 10, 10), None], false);
+    assert!(!single.ungrouped());
+}
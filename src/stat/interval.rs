@@ -0,0 +1,234 @@
+//! Interval printing for `ruperf stat -I MSEC`.
+//! While the workload runs, every counter is read
+//! each interval and the change since the previous
+//! interval is printed as one timestamped row per event,
+//! either as an aligned table or, with `-x SEP` or `--json`,
+//! as records for other tools to consume.
+
+use super::metrics::*;
+use super::output::*;
+use super::*;
+use std::io;
+
+/// Tracks the counts printed at the last interval
+/// so that each row shows only what changed since.
+pub struct IntervalPrinter {
+    period: Duration,
+    next: Duration,
+    last_time: Duration,
+    last: Vec<Vec<CounterValue>>,
+    format: Format,
+    aggregation: Aggregation,
+}
+
+impl IntervalPrinter {
+    /// Start printing every `msec` milliseconds, with
+    /// `start` holding the counts read when counting began.
+    pub fn new(options: &StatOptions, msec: u64, start: Vec<Vec<CounterValue>>) -> Self {
+        let period = Duration::from_millis(msec.max(1));
+        Self {
+            period,
+            next: period,
+            last_time: Duration::from_secs(0),
+            last: start,
+            format: Format::new(options),
+            aggregation: aggregation(options),
+        }
+    }
+
+    /// Whether an interval has passed since the last print.
+    pub fn is_due(&self, elapsed: Duration) -> bool {
+        elapsed >= self.next
+    }
+
+    /// Print the change in `current`, the counts read from each
+    /// target, since the last interval, timestamped with the
+    /// `elapsed` time since counting began.
+    pub fn print(
+        &mut self,
+        out: &mut dyn Write,
+        events: &[StatEvent],
+        current: Vec<TargetCounts>,
+        elapsed: Duration,
+    ) -> io::Result<()> {
+        let runs: Vec<TargetCounts> = current
+            .iter()
+            .zip(&self.last)
+            .map(|(now, last)| now.since(last))
+            .collect();
+        self.last = current.into_iter().map(|now| now.counts).collect();
+        let wall = elapsed - self.last_time;
+        for (prefix, event_list) in aggregate(events, &runs, self.aggregation) {
+            for event in &event_list {
+                let metric = section_metric(event, &event_list, wall);
+                self.print_row(out, elapsed, &prefix, event, metric)?;
+            }
+        }
+        self.last_time = elapsed;
+        while self.next <= elapsed {
+            self.next += self.period;
+        }
+        out.flush()
+    }
+
+    /// Describe the columns of the human-readable table.
+    pub fn print_header(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.format != Format::Human {
+            return Ok(());
+        }
+        if self.aggregation == Aggregation::Global {
+            writeln!(out, "#{:>14} {:>18} {:<5}events", "time", "counts", "unit")
+        } else {
+            writeln!(
+                out,
+                "#{:>14} {:<8} {:>18} {:<5}events",
+                "time", "aggr", "counts", "unit"
+            )
+        }
+    }
+
+    /// Print a single event's change over the last interval.
+    fn print_row(
+        &self,
+        out: &mut dyn Write,
+        elapsed: Duration,
+        prefix: &str,
+        event: &EventCounter,
+        metric: Option<MetricValue>,
+    ) -> io::Result<()> {
+        let time = format!("{:.9}", elapsed.as_secs_f64());
+        let (count, unit) = display_count(event);
+        let note = metric_note(metric.clone());
+        let mut record = CounterRecord::new(prefix, event, metric);
+        record.interval = Some(elapsed.as_secs_f64());
+        let line = match &self.format {
+            Format::Separated(sep) => record.to_separated(sep),
+            // One object per line, so rows can be streamed.
+            Format::Json => serde_json::to_string(&record).unwrap(),
+            Format::Human if prefix.is_empty() => format!(
+                "{:>15} {:>18} {:<5}{}{}{}",
+                time,
+                count,
+                unit,
+                event.event.to_string(),
+                note,
+                running_note(&event.count)
+            ),
+            Format::Human => format!(
+                "{:>15} {:<8} {:>18} {:<5}{}{}{}",
+                time,
+                prefix,
+                count,
+                unit,
+                event.event.to_string(),
+                note,
+                running_note(&event.count)
+            ),
+        };
+        writeln!(out, "{}", line)
+    }
+}
+
+/// Counts of a target on `cpu` that were on the PMU
+/// for as long as they were enabled.
+#[cfg(test)]
+fn running_counts(cpu: i32, raw: &[u64]) -> TargetCounts {
+    let counts: Vec<_> = raw.iter().map(|raw| test_value(*raw, *raw, *raw)).collect();
+    test_counts(cpu, &counts, true)
+}
+
+#[cfg(test)]
+#[test]
+fn interval_printer_test() {
+    let events = [StatEvent::PageFaults, StatEvent::ContextSwitches];
+    let options = StatOptions::from_iter(&["stat", "-I", "100", "-x", ",", "true"]);
+    let start = vec![running_counts(-1, &[100, 10]).counts];
+    let mut printer = IntervalPrinter::new(&options, 100, start);
+    assert!(!printer.is_due(Duration::from_millis(99)));
+    assert!(printer.is_due(Duration::from_millis(100)));
+
+    // Each row shows the change since the interval before.
+    let mut out = Vec::new();
+    let current = vec![running_counts(-1, &[150, 12])];
+    printer
+        .print(&mut out, &events, current, Duration::from_millis(100))
+        .unwrap();
+    let current = vec![running_counts(-1, &[180, 12])];
+    printer
+        .print(&mut out, &events, current, Duration::from_millis(200))
+        .unwrap();
+    let rows: Vec<&str> = str::from_utf8(&out).unwrap().lines().collect();
+    assert_eq!(rows.len(), 4);
+    assert!(rows[0].starts_with("0.100000000,50,,page-faults,50,"));
+    assert!(rows[1].starts_with("0.100000000,2,,context-switches,2,"));
+    assert!(rows[2].starts_with("0.200000000,30,,page-faults,30,"));
+    assert!(rows[3].starts_with("0.200000000,0,,context-switches,0,"));
+
+    // A late poll skips the intervals it missed.
+    let current = vec![running_counts(-1, &[200, 12])];
+    printer
+        .print(
+            &mut Vec::new(),
+            &events,
+            current,
+            Duration::from_millis(450),
+        )
+        .unwrap();
+    assert!(!printer.is_due(Duration::from_millis(499)));
+    assert!(printer.is_due(Duration::from_millis(500)));
+}
+
+#[cfg(test)]
+#[test]
+fn interval_aggr_test() {
+    let events = [StatEvent::PageFaults];
+    let options = StatOptions::from_iter(&["stat", "-I", "100", "-a", "--per-cpu"]);
+    let start = vec![vec![CounterValue::default()]; 2];
+    let mut printer = IntervalPrinter::new(&options, 100, start);
+    let mut out = Vec::new();
+    printer.print_header(&mut out).unwrap();
+    let header = String::from_utf8(out).unwrap();
+    assert!(header.contains(" aggr "));
+    assert!(header.trim_end().ends_with("events"));
+
+    let mut out = Vec::new();
+    let current = vec![running_counts(1, &[7]), running_counts(0, &[5])];
+    printer
+        .print(&mut out, &events, current, Duration::from_millis(100))
+        .unwrap();
+    let rows: Vec<Vec<String>> = str::from_utf8(&out)
+        .unwrap()
+        .lines()
+        .map(|row| row.split_whitespace().map(String::from).collect())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0][..4], ["0.100000000", "CPU0", "5", "page-faults"]);
+    assert_eq!(rows[1][..4], ["0.100000000", "CPU1", "7", "page-faults"]);
+}
+
+#[cfg(test)]
+#[test]
+fn interval_json_test() {
+    let events = [StatEvent::PageFaults, StatEvent::ContextSwitches];
+    let options = StatOptions::from_iter(&["stat", "-I", "100", "--json", "true"]);
+    let start = vec![vec![CounterValue::default(); 2]];
+    let mut printer = IntervalPrinter::new(&options, 100, start);
+    let mut out = Vec::new();
+    printer.print_header(&mut out).unwrap();
+    assert!(out.is_empty());
+    let current = vec![running_counts(-1, &[3, 1])];
+    printer
+        .print(&mut out, &events, current, Duration::from_millis(100))
+        .unwrap();
+    // One object per line, so the rows can be streamed.
+    let rows: Vec<serde_json::Value> = str::from_utf8(&out)
+        .unwrap()
+        .lines()
+        .map(|row| serde_json::from_str(row).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["event"], "page-faults");
+    assert_eq!(rows[0]["interval"], 0.1);
+    assert_eq!(rows[1]["raw"], 1);
+    assert!(rows[1].get("aggr").is_none());
+}
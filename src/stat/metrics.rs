@@ -0,0 +1,202 @@
+//! Derived metrics for `ruperf stat`.
+//! Each metric is computed from the counts of one or
+//! more events and printed as a `#` annotation next to
+//! the event it describes. A metric whose inputs were
+//! not all counted is left out of the report.
+
+use super::*;
+
+const L1D_LOADS: StatEvent = StatEvent::HwCache(CacheId::L1D, CacheOp::Read, CacheResult::Access);
+const L1D_LOAD_MISSES: StatEvent =
+    StatEvent::HwCache(CacheId::L1D, CacheOp::Read, CacheResult::Miss);
+
+/// Looks up the scaled count of an event, if it was counted.
+pub type Counts<'a> = &'a dyn Fn(StatEvent) -> Option<f64>;
+
+/// A metric derived from other counts.
+pub struct Metric {
+    /// The event whose line the metric is printed on.
+    pub annotates: StatEvent,
+    /// Describes the value, e.g. "insn per cycle".
+    pub unit: &'static str,
+    /// Decimal places to print the value with.
+    pub precision: usize,
+    /// Compute the value from the count of each event
+    /// and the wall time in seconds, or `None` when an
+    /// input is missing or zero.
+    pub compute: fn(Counts, f64) -> Option<f64>,
+}
+
+/// A computed metric, ready to print.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricValue {
+    pub value: f64,
+    pub unit: &'static str,
+    pub precision: usize,
+}
+
+impl std::fmt::Display for MetricValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Percentages read better without a space before the sign.
+        let space = if self.unit.starts_with('%') { "" } else { " " };
+        write!(f, "{:.*}{}{}", self.precision, self.value, space, self.unit)
+    }
+}
+
+/// Divide, treating a zero or missing denominator as no result.
+fn ratio(numerator: Option<f64>, denominator: Option<f64>) -> Option<f64> {
+    match (numerator, denominator) {
+        (Some(n), Some(d)) if d != 0.0 => Some(n / d),
+        _ => None,
+    }
+}
+
+/// Every metric the engine knows about.
+pub const METRICS: &[Metric] = &[
+    Metric {
+        annotates: StatEvent::TaskClock,
+        unit: "CPUs utilized",
+        precision: 3,
+        // task-clock is in nanoseconds.
+        compute: |count, wall| ratio(count(StatEvent::TaskClock), Some(wall * 1e9)),
+    },
+    Metric {
+        annotates: StatEvent::CpuClock,
+        unit: "CPUs utilized",
+        precision: 3,
+        compute: |count, wall| ratio(count(StatEvent::CpuClock), Some(wall * 1e9)),
+    },
+    Metric {
+        annotates: StatEvent::ContextSwitches,
+        unit: "K/sec",
+        precision: 3,
+        // Switches per millisecond of task-clock is thousands per second.
+        compute: |count, _| {
+            ratio(
+                count(StatEvent::ContextSwitches),
+                count(StatEvent::TaskClock).map(|ns| ns / 1e6),
+            )
+        },
+    },
+    Metric {
+        annotates: StatEvent::Cycles,
+        unit: "GHz",
+        precision: 3,
+        // Cycles per nanosecond of task-clock is GHz.
+        compute: |count, _| ratio(count(StatEvent::Cycles), count(StatEvent::TaskClock)),
+    },
+    Metric {
+        annotates: StatEvent::Instructions,
+        unit: "insn per cycle",
+        precision: 2,
+        compute: |count, _| ratio(count(StatEvent::Instructions), count(StatEvent::Cycles)),
+    },
+    Metric {
+        annotates: L1D_LOAD_MISSES,
+        unit: "% of all L1-dcache accesses",
+        precision: 2,
+        compute: |count, _| ratio(count(L1D_LOAD_MISSES).map(|x| x * 100.0), count(L1D_LOADS)),
+    },
+];
+
+/// The metric annotating `event`, computed from the counts
+/// given by `count` over `wall` seconds, if all its inputs
+/// were collected. A modified event, such as `instructions:u`,
+/// takes its inputs from events with the same modifiers.
+pub fn annotate(event: &StatEvent, count: Counts, wall: f64) -> Option<MetricValue> {
+    let modifiers = match event {
+        StatEvent::Modified(_, modifiers) => Some(*modifiers),
+        _ => None,
+    };
+    let metric = METRICS.iter().find(|m| m.annotates == *event.base())?;
+    let modified_count = |e: StatEvent| match modifiers {
+        Some(m) => count(StatEvent::Modified(Box::new(e), m)),
+        None => count(e),
+    };
+    (metric.compute)(&modified_count, wall).map(|value| MetricValue {
+        value,
+        unit: metric.unit,
+        precision: metric.precision,
+    })
+}
+
+/// Look up counts in one section of a report. Events that
+/// were not collected, or never ran, have no count.
+pub fn section_counts(section: &[EventCounter]) -> impl Fn(StatEvent) -> Option<f64> + '_ {
+    move |event| {
+        section
+            .iter()
+            .find(|e| e.event == event)
+            .filter(|e| e.counted())
+            .map(|e| e.count.scaled() as f64)
+    }
+}
+
+/// The metric annotating `event` in a report section
+/// counted over `wall` time. An event that was not
+/// counted has no metric.
+pub fn section_metric(
+    event: &EventCounter,
+    section: &[EventCounter],
+    wall: Duration,
+) -> Option<MetricValue> {
+    if !event.counted() {
+        return None;
+    }
+    annotate(&event.event, &section_counts(section), wall.as_secs_f64())
+}
+
+#[cfg(test)]
+#[test]
+fn annotate_test() {
+    let counts = |event| match event {
+        StatEvent::Cycles => Some(3_000_000.0),
+        StatEvent::Instructions => Some(6_000_000.0),
+        StatEvent::TaskClock => Some(1_000_000.0),
+        L1D_LOADS => Some(200.0),
+        L1D_LOAD_MISSES => Some(5.0),
+        _ => None,
+    };
+    let ipc = annotate(&StatEvent::Instructions, &counts, 1.0).unwrap();
+    assert_eq!(ipc.to_string(), "2.00 insn per cycle");
+    let ghz = annotate(&StatEvent::Cycles, &counts, 1.0).unwrap();
+    assert_eq!(ghz.to_string(), "3.000 GHz");
+    let miss = annotate(&L1D_LOAD_MISSES, &counts, 1.0).unwrap();
+    assert_eq!(miss.to_string(), "2.50% of all L1-dcache accesses");
+    let cpus = annotate(&StatEvent::TaskClock, &counts, 0.002).unwrap();
+    assert_eq!(cpus.to_string(), "0.500 CPUs utilized");
+    // Context switches were not collected.
+    assert!(annotate(&StatEvent::ContextSwitches, &counts, 1.0).is_none());
+    // No metric annotates L1D loads.
+    assert!(annotate(&L1D_LOADS, &counts, 1.0).is_none());
+}
+
+#[test]
+fn missing_input_test() {
+    let counts = |event| match event {
+        StatEvent::Instructions => Some(6_000_000.0),
+        StatEvent::Cycles => Some(0.0),
+        _ => None,
+    };
+    assert!(annotate(&StatEvent::Instructions, &counts, 1.0).is_none());
+    assert!(annotate(&StatEvent::Cycles, &counts, 1.0).is_none());
+}
+
+#[test]
+fn modified_inputs_test() {
+    let user = |e: StatEvent| StatEvent::Modified(Box::new(e), "u".parse().unwrap());
+    let counts = |event: StatEvent| {
+        if event == user(StatEvent::Instructions) {
+            Some(4_000.0)
+        } else if event == user(StatEvent::Cycles) {
+            Some(2_000.0)
+        } else if event == StatEvent::Cycles {
+            Some(8_000.0)
+        } else {
+            None
+        }
+    };
+    let ipc = annotate(&user(StatEvent::Instructions), &counts, 1.0).unwrap();
+    assert_eq!(ipc.to_string(), "2.00 insn per cycle");
+    assert!(annotate(&StatEvent::Instructions, &counts, 1.0).is_none());
+}
@@ -0,0 +1,371 @@
+//! Report output for `ruperf stat`.
+//! Counts are printed as a human-readable report by
+//! default, as separated fields with `-x SEP`, or as
+//! JSON with `--json`, to stdout or the file given with `-o`.
+
+use super::metrics::*;
+use super::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+
+/// How the report is formatted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    Human,
+    Separated(String),
+    Json,
+}
+
+impl Format {
+    /// The format requested on the command line.
+    pub fn new(options: &StatOptions) -> Self {
+        if options.json {
+            Format::Json
+        } else if let Some(sep) = &options.field_separator {
+            Format::Separated(sep.clone())
+        } else {
+            Format::Human
+        }
+    }
+}
+
+/// Open where the report is written: the file
+/// given with `-o`, or stdout.
+pub fn open(options: &StatOptions) -> Box<dyn Write> {
+    match &options.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Could not create {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(io::stdout()),
+    }
+}
+
+/// One counter as written in machine-readable output.
+#[derive(Debug, Serialize)]
+pub struct CounterRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggr: Option<String>,
+    pub event: String,
+    pub scaled: Option<f64>,
+    pub unit: String,
+    pub raw: u64,
+    pub enabled: u64,
+    pub running: u64,
+    pub running_percent: f64,
+    pub supported: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metric: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metric_unit: Option<String>,
+}
+
+impl CounterRecord {
+    /// Describe `event`, counted on the CPUs labelled `prefix`,
+    /// along with the metric derived for it, if any.
+    pub fn new(prefix: &str, event: &EventCounter, metric: Option<MetricValue>) -> Self {
+        Self {
+            interval: None,
+            aggr: if prefix.is_empty() {
+                None
+            } else {
+                Some(prefix.to_string())
+            },
+            event: event.event.to_string(),
+            scaled: if event.counted() {
+                Some(display_value(event))
+            } else {
+                None
+            },
+            unit: unit(&event.event).to_string(),
+            raw: event.count.raw,
+            enabled: event.count.enabled,
+            running: event.count.running,
+            running_percent: event.count.running_percent(),
+            supported: event.supported,
+            metric: metric.as_ref().map(|m| m.value),
+            metric_unit: metric.map(|m| m.unit.to_string()),
+        }
+    }
+
+    /// The record as fields joined by `sep`.
+    pub fn to_separated(&self, sep: &str) -> String {
+        let mut fields = Vec::new();
+        if let Some(interval) = self.interval {
+            fields.push(format!("{:.9}", interval));
+        }
+        if let Some(aggr) = &self.aggr {
+            fields.push(aggr.clone());
+        }
+        fields.push(match self.scaled {
+            Some(x) => format_value(x, &self.unit),
+            None if !self.supported => "<not supported>".to_string(),
+            None => "<not counted>".to_string(),
+        });
+        fields.push(self.unit.clone());
+        fields.push(self.event.clone());
+        fields.push(self.raw.to_string());
+        fields.push(self.enabled.to_string());
+        fields.push(self.running.to_string());
+        fields.push(format!("{:.2}", self.running_percent));
+        // Always present so every row has the same columns.
+        fields.push(match self.metric {
+            Some(x) => format!("{:.3}", x),
+            None => String::new(),
+        });
+        fields.push(self.metric_unit.clone().unwrap_or_default());
+        fields.join(sep)
+    }
+}
+
+/// The whole report as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    command: &'a [String],
+    target: &'a str,
+    exit_status: Option<i32>,
+    wall_time: f64,
+    counters: Vec<CounterRecord>,
+}
+
+/// Write the report for one stat run on `label`.
+pub fn print_report(
+    out: &mut dyn Write,
+    options: &StatOptions,
+    label: &str,
+    sections: &[(String, Vec<EventCounter>)],
+    run: &StatRun,
+) -> io::Result<()> {
+    match Format::new(options) {
+        Format::Human => print_human(out, label, sections, run.elapsed),
+        Format::Separated(sep) => {
+            writeln!(
+                out,
+                "# {}: exit status {}, {:.9} seconds time elapsed",
+                label,
+                exit_status_string(run.exit_status),
+                run.elapsed.as_secs_f64()
+            )?;
+            for (prefix, event_list) in sections {
+                for event in event_list {
+                    let metric = section_metric(event, event_list, run.elapsed);
+                    writeln!(
+                        out,
+                        "{}",
+                        CounterRecord::new(prefix, event, metric).to_separated(&sep)
+                    )?;
+                }
+            }
+            Ok(())
+        }
+        Format::Json => {
+            let report = JsonReport {
+                command: &options.command,
+                target: label,
+                exit_status: run.exit_status,
+                wall_time: run.elapsed.as_secs_f64(),
+                counters: sections
+                    .iter()
+                    .flat_map(|(prefix, event_list)| {
+                        event_list.iter().map(move |event| {
+                            let metric = section_metric(event, event_list, run.elapsed);
+                            CounterRecord::new(prefix, event, metric)
+                        })
+                    })
+                    .collect(),
+            };
+            writeln!(out, "{}", to_json(options, &report))
+        }
+    }
+}
+
+/// A JSON report, pretty-printed, or on one line after
+/// the one-line records of `-I`, so the whole output
+/// can be read as JSON Lines.
+pub fn to_json<T: Serialize>(options: &StatOptions, report: &T) -> String {
+    match options.interval_print {
+        Some(_) => serde_json::to_string(report).unwrap(),
+        None => serde_json::to_string_pretty(report).unwrap(),
+    }
+}
+
+/// Print the counts collected for `label` over wall time `t`.
+/// Each section is prefixed with its CPU, core or socket
+/// label when counts are broken down.
+fn print_human(
+    out: &mut dyn Write,
+    label: &str,
+    sections: &[(String, Vec<EventCounter>)],
+    t: Duration,
+) -> io::Result<()> {
+    writeln!(out, "Performance counter stats for {}:\n", label)?;
+
+    for (prefix, event_list) in sections {
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!(" {:<8}", prefix)
+        };
+        for event in event_list {
+            if !event.counted() {
+                let (count, _) = display_count(event);
+                writeln!(out, "{} {} {}", prefix, count, event.event.to_string())?;
+            } else if !unit(&event.event).is_empty() {
+                writeln!(
+                    out,
+                    "{} {:.2} {} {}{}{}",
+                    prefix,
+                    display_value(event),
+                    unit(&event.event),
+                    event.event.to_string(),
+                    metric_note(section_metric(event, event_list, t)),
+                    running_note(&event.count)
+                )?;
+            } else {
+                writeln!(
+                    out,
+                    "{} Number of {}: {}{}{}",
+                    prefix,
+                    event.event.to_string(),
+                    event.count.scaled(),
+                    metric_note(section_metric(event, event_list, t)),
+                    running_note(&event.count)
+                )?;
+            }
+        }
+    }
+    writeln!(out, "\n {:.9} seconds time elapsed", t.as_secs_f64())
+}
+
+/// Describe how the command ended, for the separated
+/// output header.
+fn exit_status_string(status: Option<i32>) -> String {
+    match status {
+        Some(x) => x.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// The unit counts of `event` are reported in.
+pub fn unit(event: &StatEvent) -> &'static str {
+    match event.base() {
+        StatEvent::TaskClock | StatEvent::CpuClock => "msec",
+        _ => "",
+    }
+}
+
+/// The scaled count of `event` in its reported unit.
+/// Clocks are reported in milliseconds, as `perf stat` does.
+pub fn display_value(event: &EventCounter) -> f64 {
+    match unit(&event.event) {
+        "msec" => event.count.scaled() as f64 / 1_000_000.0,
+        _ => event.count.scaled() as f64,
+    }
+}
+
+/// Format a value in `unit` the way it is displayed:
+/// milliseconds to two places, counts as integers.
+pub fn format_value(value: f64, unit: &str) -> String {
+    match unit {
+        "" => format!("{:.0}", value),
+        _ => format!("{:.2}", value),
+    }
+}
+
+/// Format the scaled count of `event` along with its unit.
+pub fn display_count(event: &EventCounter) -> (String, &'static str) {
+    if !event.supported {
+        return ("<not supported>".to_string(), "");
+    }
+    if !event.counted() {
+        return ("<not counted>".to_string(), "");
+    }
+    let unit = unit(&event.event);
+    (format_value(display_value(event), unit), unit)
+}
+
+/// Annotate a count with the metric derived from it, if any.
+pub fn metric_note(metric: Option<MetricValue>) -> String {
+    match metric {
+        Some(m) => format!("  # {}", m),
+        None => String::new(),
+    }
+}
+
+/// When a counter was multiplexed, its count is scaled
+/// up from a sample. Note the percentage of time it was
+/// actually running, as `perf stat` does.
+pub fn running_note(count: &CounterValue) -> String {
+    if count.is_multiplexed() {
+        format!("  ({:.2}%)", count.running_percent())
+    } else {
+        String::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn counter_record_test() {
+    let event = EventCounter {
+        event: StatEvent::TaskClock,
+        count: CounterValue {
+            raw: 2_000_000,
+            enabled: 10,
+            running: 5,
+        },
+        supported: true,
+    };
+    let metric = section_metric(
+        &event,
+        std::slice::from_ref(&event),
+        Duration::from_millis(8),
+    );
+    let record = CounterRecord::new("CPU1", &event, metric);
+    assert_eq!(record.scaled, Some(4.0));
+    assert_eq!(
+        record.to_separated(","),
+        "CPU1,4.00,msec,task-clock,2000000,10,5,50.00,0.500,CPUs utilized"
+    );
+    let json = serde_json::to_value(&record).unwrap();
+    assert_eq!(json["aggr"], "CPU1");
+    assert_eq!(json["metric_unit"], "CPUs utilized");
+    assert!(json.get("interval").is_none());
+
+    let idle = EventCounter {
+        event: StatEvent::Cycles,
+        count: CounterValue {
+            raw: 0,
+            enabled: 10,
+            running: 0,
+        },
+        supported: true,
+    };
+    let record = CounterRecord::new("", &idle, None);
+    assert_eq!(record.scaled, None);
+    assert!(record
+        .to_separated(";")
+        .starts_with("<not counted>;;cycles"));
+    assert!(record.to_separated(";").ends_with(";;"));
+    assert!(serde_json::to_value(&record)
+        .unwrap()
+        .get("metric")
+        .is_none());
+
+    let unsupported = EventCounter {
+        event: StatEvent::Cycles,
+        count: CounterValue::default(),
+        supported: false,
+    };
+    let record = CounterRecord::new("", &unsupported, None);
+    assert_eq!(record.scaled, None);
+    assert!(record
+        .to_separated(";")
+        .starts_with("<not supported>;;cycles"));
+    assert_eq!(display_count(&unsupported).0, "<not supported>");
+}
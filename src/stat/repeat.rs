@@ -0,0 +1,270 @@
+//! Repeated runs for `ruperf stat -r N`.
+//! The command is launched N times, after K discarded
+//! warmup runs, and each event is reported as the mean
+//! over all runs along with its standard deviation.
+
+use super::metrics::*;
+use super::output::*;
+use super::*;
+use serde::Serialize;
+use std::io;
+
+/// Summary statistics over the runs of one event.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RunStats {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl RunStats {
+    /// Compute the mean and sample standard deviation of `values`.
+    pub fn new(values: &[f64]) -> Self {
+        let n = values.len() as f64;
+        if values.is_empty() {
+            return Self {
+                mean: 0.0,
+                stddev: 0.0,
+            };
+        }
+        let mean = values.iter().sum::<f64>() / n;
+        let stddev = if values.len() > 1 {
+            let sum_sq: f64 = values.iter().map(|x| (x - mean) * (x - mean)).sum();
+            (sum_sq / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        Self { mean, stddev }
+    }
+
+    /// Standard deviation as a percentage of the mean.
+    pub fn variance_percent(&self) -> f64 {
+        if self.mean == 0.0 {
+            return 0.0;
+        }
+        self.stddev * 100.0 / self.mean
+    }
+}
+
+/// One event's statistics as written in machine-readable output.
+#[derive(Debug, Serialize)]
+struct RepeatRecord {
+    event: String,
+    mean: f64,
+    unit: String,
+    stddev: f64,
+    variance_percent: f64,
+    supported: bool,
+    /// The runs the event was counted in, which the
+    /// statistics are taken over.
+    counted_runs: usize,
+}
+
+/// The whole repeated report as written with `--json`.
+#[derive(Debug, Serialize)]
+struct JsonRepeatReport<'a> {
+    command: &'a [String],
+    runs: usize,
+    exit_status: Vec<Option<i32>>,
+    wall_time: f64,
+    wall_time_stddev: f64,
+    counters: Vec<RepeatRecord>,
+}
+
+/// Run the command in `options` `--warmup` + `--repeat` times,
+/// and report statistics over every run after the warmup.
+/// When `targets` is given those are counted instead of the command.
+pub fn run_repeated(
+    out: &mut dyn Write,
+    options: &StatOptions,
+    targets: Option<Vec<Target>>,
+) -> io::Result<()> {
+    let mut runs: Vec<Vec<EventCounter>> = Vec::new();
+    let mut times: Vec<f64> = Vec::new();
+    let mut exit_status: Vec<Option<i32>> = Vec::new();
+    let mut notes: Vec<&str> = Vec::new();
+    for run in 0..options.warmup + options.repeat {
+        let result = count_command(out, options, targets.clone());
+        for note in grouping_notes(options.no_group, &result.counts) {
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+        if run < options.warmup {
+            continue;
+        }
+        let totals = aggregate(&options.event, &result.counts, Aggregation::Global)
+            .into_iter()
+            .next()
+            .map(|(_, event_list)| event_list)
+            .unwrap_or_default();
+        runs.push(totals);
+        times.push(result.elapsed.as_secs_f64());
+        exit_status.push(result.exit_status);
+    }
+
+    let records: Vec<RepeatRecord> = options
+        .event
+        .iter()
+        .enumerate()
+        .map(|(i, event)| {
+            let values: Vec<f64> = counted(&runs, i).map(display_value).collect();
+            let stats = RunStats::new(&values);
+            RepeatRecord {
+                event: event.to_string(),
+                mean: stats.mean,
+                unit: unit(event).to_string(),
+                stddev: stats.stddev,
+                variance_percent: stats.variance_percent(),
+                supported: runs.iter().any(|run| run[i].supported),
+                counted_runs: values.len(),
+            }
+        })
+        .collect();
+    let wall = RunStats::new(&times);
+    // Metrics are derived from the mean count of each event.
+    let means: Vec<f64> = (0..options.event.len())
+        .map(|i| {
+            let values: Vec<f64> = counted(&runs, i)
+                .map(|event| event.count.scaled() as f64)
+                .collect();
+            RunStats::new(&values).mean
+        })
+        .collect();
+    let mean_of = |event: StatEvent| {
+        options
+            .event
+            .iter()
+            .position(|e| *e == event)
+            .map(|i| means[i])
+    };
+    let failed = exit_status.iter().filter(|s| !matches!(s, Some(0))).count();
+    for note in notes {
+        eprintln!("{}", note);
+    }
+
+    match Format::new(options) {
+        Format::Human => {
+            writeln!(
+                out,
+                "Performance counter stats for '{}' ({} runs):\n",
+                options.command[0],
+                runs.len()
+            )?;
+            for (event, record) in options.event.iter().zip(&records) {
+                if !record.supported {
+                    writeln!(out, " <not supported> {}", record.event)?;
+                    continue;
+                }
+                if record.counted_runs == 0 {
+                    writeln!(out, " <not counted> {}", record.event)?;
+                    continue;
+                }
+                let unit = if record.unit.is_empty() {
+                    String::new()
+                } else {
+                    format!(" {}", record.unit)
+                };
+                writeln!(
+                    out,
+                    " Number of {}: {:.2}{}{}  ( +- {:.2}, {:.2}% )",
+                    record.event,
+                    record.mean,
+                    unit,
+                    metric_note(annotate(event, &mean_of, wall.mean)),
+                    record.stddev,
+                    record.variance_percent
+                )?;
+                if record.counted_runs < runs.len() {
+                    writeln!(
+                        out,
+                        "   (counted in {} of {} runs)",
+                        record.counted_runs,
+                        runs.len()
+                    )?;
+                }
+            }
+            writeln!(
+                out,
+                "\n {:.9} +- {:.9} seconds time elapsed  ( +- {:.2}% )",
+                wall.mean,
+                wall.stddev,
+                wall.variance_percent()
+            )?;
+            if failed > 0 {
+                writeln!(
+                    out,
+                    "\n Note: {} of {} runs exited with a non-zero status",
+                    failed,
+                    runs.len()
+                )?;
+            }
+            Ok(())
+        }
+        Format::Separated(sep) => {
+            writeln!(
+                out,
+                "# '{}' ({} runs): {:.9} +- {:.9} seconds time elapsed",
+                options.command[0],
+                runs.len(),
+                wall.mean,
+                wall.stddev
+            )?;
+            if failed > 0 {
+                writeln!(
+                    out,
+                    "# {} of {} runs exited with a non-zero status",
+                    failed,
+                    runs.len()
+                )?;
+            }
+            for record in &records {
+                let mean = if !record.supported {
+                    "<not supported>".to_string()
+                } else if record.counted_runs == 0 {
+                    "<not counted>".to_string()
+                } else {
+                    format!("{:.2}", record.mean)
+                };
+                let fields = [
+                    mean,
+                    record.unit.clone(),
+                    record.event.clone(),
+                    format!("{:.2}", record.stddev),
+                    format!("{:.2}", record.variance_percent),
+                ];
+                writeln!(out, "{}", fields.join(&sep))?;
+            }
+            Ok(())
+        }
+        Format::Json => {
+            let report = JsonRepeatReport {
+                command: &options.command,
+                runs: runs.len(),
+                exit_status,
+                wall_time: wall.mean,
+                wall_time_stddev: wall.stddev,
+                counters: records,
+            };
+            writeln!(out, "{}", to_json(options, &report))
+        }
+    }
+}
+
+/// The counts of event `i` over the runs it was counted in.
+fn counted(runs: &[Vec<EventCounter>], i: usize) -> impl Iterator<Item = &EventCounter> {
+    runs.iter()
+        .map(move |run| &run[i])
+        .filter(|event| event.counted())
+}
+
+#[cfg(test)]
+#[test]
+fn run_stats_test() {
+    let stats = RunStats::new(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+    assert!((stats.mean - 5.0).abs() < 1e-9);
+    assert!((stats.stddev - 2.138_089_935).abs() < 1e-6);
+    assert!((stats.variance_percent() - 42.761_798_7).abs() < 1e-6);
+    let single = RunStats::new(&[3.0]);
+    assert_eq!(single.stddev, 0.0);
+    assert_eq!(RunStats::new(&[]).variance_percent(), 0.0);
+}
@@ -0,0 +1,68 @@
+//! Resolves the tasks `ruperf stat` attaches to
+//! with `-p` and `-t`. A process is expanded into
+//! every thread listed under `/proc/PID/task`, since
+//! counters opened on a PID only follow its main thread.
+
+use crate::utils::ParseError;
+use std::fs;
+use std::str::FromStr;
+
+/// A comma-separated list of process or thread IDs,
+/// as given on the command line, e.g. `1234,5678`.
+#[derive(Debug, Clone)]
+pub struct IdList(pub Vec<i32>);
+
+impl FromStr for IdList {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ids = s
+            .split(',')
+            .map(|id| match id.trim().parse::<i32>() {
+                Ok(x) if x > 0 => Ok(x),
+                _ => Err(ParseError::InvalidTaskList),
+            })
+            .collect::<Result<Vec<i32>, ParseError>>()?;
+        Ok(IdList(ids))
+    }
+}
+
+/// List every thread of process `pid`.
+/// Falls back to the process itself if
+/// `/proc/PID/task` cannot be read.
+pub fn threads_of(pid: i32) -> Vec<i32> {
+    let mut tids: Vec<i32> = match fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if tids.is_empty() {
+        tids.push(pid);
+    }
+    tids.sort_unstable();
+    tids
+}
+
+/// Whether task `tid` still exists.
+pub fn is_alive(tid: i32) -> bool {
+    unsafe { libc::kill(tid, 0) == 0 || *libc::__errno_location() == libc::EPERM }
+}
+
+#[cfg(test)]
+#[test]
+fn id_list_test() {
+    let ids: IdList = "1,22, 333".parse().unwrap();
+    assert_eq!(ids.0, vec![1, 22, 333]);
+    assert!("1,,2".parse::<IdList>().is_err());
+    assert!("-4".parse::<IdList>().is_err());
+    assert!("abc".parse::<IdList>().is_err());
+}
+
+#[test]
+fn threads_of_self_test() {
+    let pid = std::process::id() as i32;
+    let tids = threads_of(pid);
+    assert!(tids.contains(&pid));
+    assert!(is_alive(pid));
+}
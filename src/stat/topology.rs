@@ -0,0 +1,98 @@
+//! CPU topology for system-wide counting.
+//! Online CPUs and the core and socket each one
+//! belongs to are read from `/sys/devices/system/cpu`.
+
+use crate::utils::ParseError;
+use std::fs;
+use std::str::FromStr;
+
+const SYSFS_CPU: &str = "/sys/devices/system/cpu";
+
+/// A list of CPUs in the kernel's cpulist
+/// format, e.g. `0-3,7`.
+#[derive(Debug, Clone)]
+pub struct CpuList(pub Vec<i32>);
+
+impl FromStr for CpuList {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for range in s.trim().split(',') {
+            let mut bounds = range.splitn(2, '-').map(|x| x.trim().parse::<i32>());
+            let first = match bounds.next() {
+                Some(Ok(x)) if x >= 0 => x,
+                _ => return Err(ParseError::InvalidCpuList),
+            };
+            let last = match bounds.next() {
+                Some(Ok(x)) if x >= first => x,
+                Some(_) => return Err(ParseError::InvalidCpuList),
+                None => first,
+            };
+            cpus.extend(first..=last);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuList(cpus))
+    }
+}
+
+/// Where a CPU sits in the machine.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CpuTopology {
+    pub cpu: i32,
+    pub core: i32,
+    pub socket: i32,
+}
+
+/// Every CPU the kernel currently has online.
+pub fn online_cpus() -> Vec<i32> {
+    match fs::read_to_string(format!("{}/online", SYSFS_CPU)) {
+        Ok(list) => match list.parse::<CpuList>() {
+            Ok(cpus) => cpus.0,
+            Err(_) => fallback_cpus(),
+        },
+        Err(_) => fallback_cpus(),
+    }
+}
+
+/// Used when sysfs is unavailable: assume CPUs
+/// `0..n` where `n` is the configured CPU count.
+fn fallback_cpus() -> Vec<i32> {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    (0..n.max(1) as i32).collect()
+}
+
+/// Look up the core and socket of `cpu`. A CPU
+/// without topology information is treated as
+/// its own core on socket 0.
+pub fn topology(cpu: i32) -> CpuTopology {
+    let read_id = |name: &str| {
+        fs::read_to_string(format!("{}/cpu{}/topology/{}", SYSFS_CPU, cpu, name))
+            .ok()
+            .and_then(|x| x.trim().parse::<i32>().ok())
+    };
+    CpuTopology {
+        cpu,
+        core: read_id("core_id").unwrap_or(cpu),
+        socket: read_id("physical_package_id").unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn cpu_list_test() {
+    let cpus: CpuList = "0-3,7".parse().unwrap();
+    assert_eq!(cpus.0, vec![0, 1, 2, 3, 7]);
+    let cpus: CpuList = "5,1-2,2\n".parse().unwrap();
+    assert_eq!(cpus.0, vec![1, 2, 5]);
+    assert!("3-1".parse::<CpuList>().is_err());
+    assert!("a".parse::<CpuList>().is_err());
+    assert!("1,".parse::<CpuList>().is_err());
+}
+
+#[test]
+fn online_cpus_test() {
+    let cpus = online_cpus();
+    assert!(!cpus.is_empty());
+    assert_eq!(topology(cpus[0]).cpu, cpus[0]);
+}
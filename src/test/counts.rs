@@ -1,7 +1,8 @@
 use std::io::Read;
 use std::io::Write;
 
-use crate::event::open::Event;
+use crate::event::catalog::{CacheId, CacheOp, CacheResult};
+use crate::event::open::{CounterValue, Event};
 use crate::stat::launch_command_process;
 use crate::stat::StatEvent;
 use crate::test::RunSettings;
@@ -24,7 +25,7 @@ pub fn test_counts() -> Test {
     }
     fn event_counter(
         event_to_run: StatEvent,
-        sane_number: isize,
+        sane_number: u64,
         settings: &RunSettings,
     ) -> TestResult {
         let command_to_count = "cat".to_string();
@@ -38,9 +39,12 @@ pub fn test_counts() -> Test {
             child_reader,
             child_writer,
         );
-        let start: isize;
-        let stop: isize;
-        let event = Event::new(event_to_run, Some(pid_child));
+        let start: CounterValue;
+        let stop: CounterValue;
+        let event = match Event::new(event_to_run, Some(pid_child)) {
+            Ok(event) => event,
+            Err(e) => return fail(format!("\nINFO:\t{}", e), settings),
+        };
         let mut buf = [0];
         let nread = parent_reader.read(&mut buf).unwrap();
         if nread != 1 {
@@ -70,7 +74,7 @@ pub fn test_counts() -> Test {
             );
         }
         stop = event.stop_counter().unwrap();
-        let count = stop - start;
+        let count = stop.delta(&start).scaled();
         if count < sane_number {
             return fail(
                 format!(
@@ -122,7 +126,11 @@ pub fn test_counts() -> Test {
     }
     fn test_l1d_cache_read() -> Test {
         fn l1d_cache_read(settings: &RunSettings) -> TestResult {
-            event_counter(StatEvent::L1DCacheRead, 1000, settings)
+            event_counter(
+                StatEvent::HwCache(CacheId::L1D, CacheOp::Read, CacheResult::Access),
+                1000,
+                settings,
+            )
         }
         Test {
             name: "L1D_cache_read_test".to_string(),
@@ -134,7 +142,11 @@ pub fn test_counts() -> Test {
     }
     fn test_l1d_cache_write() -> Test {
         fn l1d_cache_write(settings: &RunSettings) -> TestResult {
-            event_counter(StatEvent::L1DCacheWrite, 0, settings)
+            event_counter(
+                StatEvent::HwCache(CacheId::L1D, CacheOp::Write, CacheResult::Access),
+                0,
+                settings,
+            )
         }
         Test {
             name: "L1D_cache_write_test".to_string(),
@@ -146,7 +158,11 @@ pub fn test_counts() -> Test {
     }
     fn test_l1d_cache_read_misses() -> Test {
         fn l1d_cache_read_misses(settings: &RunSettings) -> TestResult {
-            event_counter(StatEvent::L1DCacheReadMiss, 0, settings)
+            event_counter(
+                StatEvent::HwCache(CacheId::L1D, CacheOp::Read, CacheResult::Miss),
+                0,
+                settings,
+            )
         }
         Test {
             name: "L1D_cache_read_miss_test".to_string(),
@@ -158,7 +174,11 @@ pub fn test_counts() -> Test {
     }
     fn test_l1i_cache_read_misses() -> Test {
         fn l1i_cache_read_misses(settings: &RunSettings) -> TestResult {
-            event_counter(StatEvent::L1ICacheReadMiss, 0, settings)
+            event_counter(
+                StatEvent::HwCache(CacheId::L1I, CacheOp::Read, CacheResult::Miss),
+                0,
+                settings,
+            )
         }
         Test {
             name: "L1I_cache_read_miss_test".to_string(),
@@ -0,0 +1,346 @@
+//! # Top driver.
+//! <p> Usage: <em> ruperf top [-e EVENT] [-F FREQ] [-p PID[,PID]] [-d SECS] [-s KEYS] </em>
+//! Samples every task on all CPUs, or the threads of the given
+//! processes, and keeps a table of the functions taking the
+//! most samples, refreshed every SECS seconds. </p>
+//! <p> At each refresh earlier samples count for seven eighths
+//! of what they did, so the table follows what runs now. Keys:
+//! <em> s </em> steps through groupings, <em> e </em> through
+//! the events, <em> d </em> and <em> t </em> zoom into the file
+//! or the thread of the line selected with the arrows,
+//! <em> p </em> pauses and <em> q </em> quits. </p>
+//! <p> Names are shown as <em> ruperf report </em> shows them,
+//! and take the same <em> --no-demangle </em>,
+//! <em> --strip-hashes </em> and <em> --no-inline </em>. </p>
+//! <p> With <em> --stdio </em> the table is printed at each
+//! refresh instead, until interrupted or, with
+//! <em> -n COUNT </em>, after COUNT refreshes. </p>
+
+mod sampler;
+mod view;
+
+extern crate structopt;
+use crate::bindings::*;
+use crate::event::records::Record;
+use crate::record::sampling::{self, Sampling, Target};
+use crate::record::{synth, DEFAULT_FREQ};
+use crate::report::callchain::Frame;
+use crate::report::machine::Machine;
+use crate::report::{SortKeys, SymbolOptions};
+use crate::stat::target::{self, IdList};
+use crate::stat::topology;
+use crate::stat::{self, StatEvent, INTERRUPTED};
+use crate::utils::parse_seconds;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+use sampler::Sampler;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use view::View;
+
+/// Rows of the table printed with `--stdio`.
+const STDIO_ROWS: usize = 40;
+
+/// Show the functions taking the most samples, live.
+/// See `./ruperf top --help' for more information.
+#[derive(Debug, StructOpt)]
+pub struct TopOptions {
+    #[structopt(short, long, help = "Event to sample", number_of_values = 1)]
+    pub event: Vec<StatEvent>,
+
+    #[structopt(
+        short = "F",
+        long,
+        help = "Sample this many times a second",
+        value_name = "FREQ"
+    )]
+    pub freq: Option<u64>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Sample running processes, all their threads, instead of all CPUs"
+    )]
+    pub pid: Option<IdList>,
+
+    #[structopt(
+        short,
+        long,
+        help = "Refresh every SECS seconds",
+        value_name = "SECS",
+        default_value = "2",
+        parse(try_from_str = parse_seconds)
+    )]
+    pub delay: Duration,
+
+    #[structopt(
+        short,
+        long,
+        help = "Group samples by these keys: comm, pid, dso, sym",
+        value_name = "KEYS",
+        default_value = "dso,sym"
+    )]
+    pub sort: SortKeys,
+
+    #[structopt(
+        short,
+        long,
+        help = "Pages of ring buffer for each CPU or thread, a power of two",
+        value_name = "PAGES",
+        default_value = "128"
+    )]
+    pub mmap_pages: usize,
+
+    #[structopt(flatten)]
+    pub symbols: SymbolOptions,
+
+    #[structopt(long, help = "Print the table at each refresh, without keys")]
+    pub stdio: bool,
+
+    #[structopt(
+        short = "n",
+        long,
+        help = "Stop after COUNT refreshes",
+        value_name = "COUNT"
+    )]
+    pub iterations: Option<u64>,
+}
+
+/// Count the samples waiting in `sampler` into `view`,
+/// replaying the other records into `machine`.
+fn take_records(sampler: &mut Sampler, machine: &mut Machine, view: &mut View) {
+    for record in sampler.records() {
+        match &record {
+            Record::Sample(sample) => {
+                if view.paused {
+                    continue;
+                }
+                let cpumode = sample.misc as u32 & PERF_RECORD_MISC_CPUMODE_MASK;
+                let frame = Frame {
+                    ip: sample.ip.unwrap_or(0),
+                    kernel: cpumode == PERF_RECORD_MISC_KERNEL,
+                };
+                let location = &machine.locate(sample, &frame)[0];
+                view.add(
+                    sampler.event_of(sample),
+                    location,
+                    sample.period.unwrap_or(1),
+                );
+            }
+            Record::Lost(lost) => view.lost += lost.lost,
+            _ => machine.apply(&record),
+        }
+    }
+}
+
+/// Samples and the state they are counted in.
+struct Top {
+    sampler: Sampler,
+    machine: Machine,
+    view: View,
+    delay: Duration,
+    iterations: Option<u64>,
+}
+
+impl Top {
+    /// Sample for one refresh, waiting in `wait`, or until
+    /// `wait` returns true to quit. Returns the samples a
+    /// second, or `None` to quit.
+    fn refresh(
+        &mut self,
+        mut wait: impl FnMut(&mut Sampler, &mut View) -> io::Result<bool>,
+    ) -> io::Result<Option<f64>> {
+        let start = Instant::now();
+        while start.elapsed() < self.delay {
+            if wait(&mut self.sampler, &mut self.view)? || INTERRUPTED.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            take_records(&mut self.sampler, &mut self.machine, &mut self.view);
+        }
+        let rate = self.view.recent as f64 / start.elapsed().as_secs_f64();
+        self.view.recent = 0;
+        Ok(Some(rate))
+    }
+
+    /// Whether `refreshes` is as many as were asked for.
+    fn done(&self, refreshes: u64) -> bool {
+        self.iterations.is_some_and(|count| refreshes >= count)
+    }
+}
+
+/// Print the table at each refresh, until every
+/// attached task has exited.
+fn run_stdio(top: &mut Top) -> io::Result<()> {
+    let mut out = io::stdout();
+    let mut refreshes = 0;
+    while !top.done(refreshes) {
+        let rate = match top.refresh(|sampler, _| {
+            sampler.poll(100);
+            Ok(sampler.hung_up())
+        })? {
+            Some(rate) => rate,
+            None => break,
+        };
+        for line in top.view.lines(usize::MAX, STDIO_ROWS + 3, rate, false) {
+            writeln!(out, "{}", line)?;
+        }
+        writeln!(out)?;
+        top.view.decay();
+        refreshes += 1;
+    }
+    if top.sampler.hung_up() {
+        eprintln!("Every sampled task has exited");
+    }
+    Ok(())
+}
+
+/// Draw the table across the terminal.
+fn draw(out: &mut impl Write, view: &View, rate: f64) -> io::Result<()> {
+    // Some terminals, as under `script`, report no size.
+    let (width, height) = match terminal::size()? {
+        (0, _) | (_, 0) => (80, 24),
+        size => size,
+    };
+    queue!(out, terminal::Clear(ClearType::All))?;
+    for (i, line) in view
+        .lines(width as usize, height as usize, rate, true)
+        .iter()
+        .enumerate()
+    {
+        queue!(out, cursor::MoveTo(0, i as u16), Print(line))?;
+    }
+    out.flush()
+}
+
+/// Change `view` by a key, if one was pressed within
+/// `timeout`. Returns whether the key quits.
+fn read_key(view: &mut View, timeout: Duration) -> io::Result<Option<bool>> {
+    if !event::poll(timeout)? {
+        return Ok(None);
+    }
+    let key = match event::read()? {
+        Event::Key(key) if key.kind != KeyEventKind::Release => key,
+        Event::Resize(_, _) => return Ok(Some(false)),
+        _ => return Ok(None),
+    };
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => return Ok(Some(true)),
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            return Ok(Some(true))
+        }
+        KeyCode::Char('s') => view.next_sort(),
+        KeyCode::Char('e') => view.next_event(),
+        KeyCode::Char('d') => view.zoom_dso(),
+        KeyCode::Char('t') => view.zoom_thread(),
+        KeyCode::Char('p') | KeyCode::Char(' ') => view.paused = !view.paused,
+        KeyCode::Up => view.select(-1),
+        KeyCode::Down => view.select(1),
+        _ => return Ok(None),
+    }
+    Ok(Some(false))
+}
+
+/// Show the table on the terminal, redrawn at each refresh
+/// and key, until `q`.
+fn run_screen(top: &mut Top) -> io::Result<()> {
+    let mut out = io::stdout();
+    let mut refreshes = 0;
+    let mut rate = 0.0;
+    draw(&mut out, &top.view, rate)?;
+    while !top.done(refreshes) {
+        let refreshed =
+            top.refresh(
+                |_, view| match read_key(view, Duration::from_millis(100))? {
+                    Some(quit) => {
+                        draw(&mut io::stdout(), view, rate)?;
+                        Ok(quit)
+                    }
+                    None => Ok(false),
+                },
+            )?;
+        rate = match refreshed {
+            Some(rate) => rate,
+            None => break,
+        };
+        draw(&mut out, &top.view, rate)?;
+        if !top.view.paused {
+            top.view.decay();
+        }
+        refreshes += 1;
+    }
+    Ok(())
+}
+
+/// Run perf top on all CPUs, or on the processes of `-p`.
+pub fn run_top(options: TopOptions) {
+    let (events, default_event) = sampling::events_or_default(&options.event);
+    let sampling = Sampling {
+        period: None,
+        freq: options.freq.unwrap_or(DEFAULT_FREQ),
+        call_graph: None,
+        pages: options.mmap_pages,
+        follow_exec: false,
+    };
+    sampling.check_or_exit();
+
+    let (targets, pids): (Vec<Target>, Vec<i32>) = match &options.pid {
+        Some(pids) => (
+            pids.0
+                .iter()
+                .flat_map(|pid| target::threads_of(*pid))
+                .map(|tid| Target { pid: tid, cpu: -1 })
+                .collect(),
+            pids.0.clone(),
+        ),
+        None => (
+            topology::online_cpus()
+                .iter()
+                .map(|cpu| Target { pid: -1, cpu: *cpu })
+                .collect(),
+            synth::all_processes(),
+        ),
+    };
+    let sampler = Sampler::open(&sampling, &events, default_event, &targets);
+    let mut machine = options.symbols.machine();
+    for record in sampler.task_records(&pids) {
+        machine.apply(&record);
+    }
+
+    let names = sampler.names.clone();
+    let mut top = Top {
+        sampler,
+        machine,
+        view: View::new(names, options.sort.clone()),
+        delay: options.delay,
+        iterations: options.iterations,
+    };
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            stat::on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let result = if options.stdio {
+        run_stdio(&mut top)
+    } else {
+        let mut out = io::stdout();
+        let result = terminal::enable_raw_mode()
+            .and_then(|_| execute!(out, EnterAlternateScreen, cursor::Hide))
+            .and_then(|_| run_screen(&mut top));
+        // Give the terminal back even when drawing failed.
+        execute!(out, cursor::Show, LeaveAlternateScreen).ok();
+        terminal::disable_raw_mode().ok();
+        result
+    };
+    // A reader such as `head` may stop early.
+    if let Err(e) = result {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("Could not show the table: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
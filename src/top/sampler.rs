@@ -0,0 +1,117 @@
+//! Sampling for `ruperf top`.
+//! Events are opened as `ruperf record` opens them, with a
+//! ring buffer for each CPU, or for each thread with `-p`,
+//! and their records are read as they come in rather than
+//! written out.
+
+use crate::event::records::{self, Record, Sample, SampleFormat};
+use crate::record::sampling::{Sampling, Target, TargetRing};
+use crate::record::synth;
+use crate::stat::StatEvent;
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// Events sampled on a set of CPUs or threads.
+pub struct Sampler {
+    rings: Vec<TargetRing>,
+    format: SampleFormat,
+    /// The index of each event, by counter id.
+    ids: HashMap<u64, usize>,
+    /// The names of the events, once opened.
+    pub names: Vec<String>,
+}
+
+impl Sampler {
+    /// Open and start `events` on `targets` with `sampling`,
+    /// falling back from the default cycles event to cpu-clock
+    /// as `ruperf record` does. Exits on errors.
+    pub fn open(
+        sampling: &Sampling,
+        events: &[StatEvent],
+        default_event: bool,
+        targets: &[Target],
+    ) -> Self {
+        let (rings, attrs) = sampling.open_or_exit(events, default_event, targets);
+        let mut ids = HashMap::new();
+        for ring in &rings {
+            for (i, event) in ring.events.iter().enumerate() {
+                if let Ok(id) = event.fd.id() {
+                    ids.insert(id as u64, i);
+                }
+            }
+        }
+        let names = match rings.first() {
+            Some(ring) => ring.events.iter().map(|e| e.event.to_string()).collect(),
+            None => events.iter().map(|event| event.to_string()).collect(),
+        };
+        Self {
+            rings,
+            format: SampleFormat::new(&attrs[0]),
+            ids,
+            names,
+        }
+    }
+
+    /// Wait up to `timeout` milliseconds for records. Rings
+    /// whose target has gone are no longer polled, as they
+    /// would report a hang-up at once, every time.
+    pub fn poll(&mut self, timeout: i32) {
+        let mut polled: Vec<(usize, libc::pollfd)> = self
+            .rings
+            .iter()
+            .enumerate()
+            .filter(|(_, ring)| !ring.hung_up)
+            .map(|(i, ring)| {
+                let fd = libc::pollfd {
+                    fd: ring.events[0].fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                (i, fd)
+            })
+            .collect();
+        let mut fds: Vec<libc::pollfd> = polled.iter().map(|(_, fd)| *fd).collect();
+        if fds.is_empty() {
+            std::thread::sleep(Duration::from_millis(timeout.max(0) as u64));
+            return;
+        }
+        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        for ((i, _), fd) in polled.iter_mut().zip(&fds) {
+            if fd.revents & libc::POLLHUP != 0 {
+                self.rings[*i].hung_up = true;
+            }
+        }
+    }
+
+    /// Whether every target sampled has gone.
+    pub fn hung_up(&self) -> bool {
+        self.rings.iter().all(|ring| ring.hung_up)
+    }
+
+    /// The records waiting in the ring buffers.
+    pub fn records(&mut self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for ring in &mut self.rings {
+            records.extend(&mut ring.ring);
+        }
+        records
+    }
+
+    /// The index of the event `sample` was taken for.
+    pub fn event_of(&self, sample: &Sample) -> usize {
+        sample
+            .id
+            .and_then(|id| self.ids.get(&id).copied())
+            .unwrap_or(0)
+    }
+
+    /// Records describing the running processes `pids`, whose
+    /// names and mappings the kernel will not report.
+    pub fn task_records(&self, pids: &[i32]) -> Vec<Record> {
+        pids.iter()
+            .flat_map(|pid| synth::task_records(*pid, &self.format, 0))
+            .filter_map(|record| records::parse(&record, &self.format))
+            .collect()
+    }
+}
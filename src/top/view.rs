@@ -0,0 +1,383 @@
+//! What `ruperf top` shows.
+//! Samples are counted by command, process, file and function,
+//! and shown grouped by the current sort keys, which can change
+//! at any time. At each refresh the counts decay by an eighth,
+//! so that functions that stop running fade out of the table.
+
+use crate::report::machine::Location;
+use crate::report::{EntryKey, SortKey, SortKeys};
+use std::collections::HashMap;
+
+/// The share of its period an entry keeps at each refresh.
+const DECAY: (u64, u64) = (7, 8);
+
+/// The groupings `s` steps through.
+const SORTS: &[&[SortKey]] = &[
+    &[SortKey::Dso, SortKey::Sym],
+    &[SortKey::Sym],
+    &[SortKey::Comm, SortKey::Dso, SortKey::Sym],
+    &[SortKey::Comm, SortKey::Pid],
+    &[SortKey::Dso],
+];
+
+/// The keys samples are counted by, so that they can be
+/// grouped by any of them later.
+fn all_keys() -> SortKeys {
+    SortKeys(vec![
+        SortKey::Comm,
+        SortKey::Pid,
+        SortKey::Dso,
+        SortKey::Sym,
+    ])
+}
+
+/// `key` with only the columns of `keys`.
+fn project(key: &EntryKey, keys: &SortKeys) -> EntryKey {
+    let mut projected = EntryKey::default();
+    for column in &keys.0 {
+        match column {
+            SortKey::Comm => projected.comm = key.comm.clone(),
+            SortKey::Pid => projected.pid = key.pid,
+            SortKey::Dso => projected.dso = key.dso.clone(),
+            SortKey::Sym => projected.symbol = key.symbol.clone(),
+        }
+    }
+    projected
+}
+
+/// A line of the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub key: EntryKey,
+    /// The decayed sum of the sample periods.
+    pub period: u64,
+    /// The heaviest of the counts in the row, whose file
+    /// or thread zooming in picks.
+    heaviest: (EntryKey, u64),
+}
+
+/// The counts of each event, and how they are shown.
+#[derive(Debug, Clone)]
+pub struct View {
+    events: Vec<String>,
+    /// Decayed periods by event, then by every key.
+    counts: Vec<HashMap<EntryKey, u64>>,
+    /// The event shown.
+    pub event: usize,
+    pub sort: SortKeys,
+    /// The file zoomed into, `Some(None)` for samples
+    /// outside any mapping.
+    pub dso: Option<Option<String>>,
+    /// The thread zoomed into, by process and name.
+    pub thread: Option<(u32, String)>,
+    /// The row picked for zooming.
+    pub selected: usize,
+    /// Whether samples are left out, so the table holds still.
+    pub paused: bool,
+    /// Samples since the last refresh.
+    pub recent: u64,
+    /// Samples the kernel dropped for lack of ring buffer space.
+    pub lost: u64,
+}
+
+impl View {
+    /// A view of the samples of `events`, grouped by `sort`.
+    pub fn new(events: Vec<String>, sort: SortKeys) -> Self {
+        View {
+            counts: vec![HashMap::new(); events.len()],
+            events,
+            event: 0,
+            sort,
+            dso: None,
+            thread: None,
+            selected: 0,
+            paused: false,
+            recent: 0,
+            lost: 0,
+        }
+    }
+
+    /// Count a sample of `event` at `location`.
+    pub fn add(&mut self, event: usize, location: &Location, period: u64) {
+        if self.paused {
+            return;
+        }
+        let key = EntryKey::new(&all_keys(), location);
+        *self.counts[event].entry(key).or_default() += period;
+        self.recent += 1;
+    }
+
+    /// Let every count decay, dropping those that reach zero.
+    pub fn decay(&mut self) {
+        for counts in &mut self.counts {
+            counts.retain(|_, period| {
+                *period = *period * DECAY.0 / DECAY.1;
+                *period > 0
+            });
+        }
+    }
+
+    /// The decayed count of the event shown.
+    fn total(&self) -> u64 {
+        self.counts[self.event].values().sum()
+    }
+
+    /// The rows of the event shown, within the zoom,
+    /// largest first.
+    pub fn rows(&self) -> Vec<Row> {
+        let mut rows: HashMap<EntryKey, Row> = HashMap::new();
+        for (key, period) in &self.counts[self.event] {
+            if matches!(&self.dso, Some(dso) if key.dso.as_ref() != Some(dso)) {
+                continue;
+            }
+            if let Some((pid, comm)) = &self.thread {
+                if key.pid != Some(*pid) || key.comm.as_ref() != Some(comm) {
+                    continue;
+                }
+            }
+            let projected = project(key, &self.sort);
+            let row = rows.entry(projected.clone()).or_insert_with(|| Row {
+                key: projected,
+                period: 0,
+                heaviest: (key.clone(), 0),
+            });
+            row.period += period;
+            if (*period, &row.heaviest.0) > (row.heaviest.1, key) {
+                row.heaviest = (key.clone(), *period);
+            }
+        }
+        let mut rows: Vec<Row> = rows.into_values().collect();
+        rows.sort_by(|a, b| b.period.cmp(&a.period).then_with(|| a.key.cmp(&b.key)));
+        rows
+    }
+
+    /// Group by the next of the preset sort keys.
+    pub fn next_sort(&mut self) {
+        let at = SORTS
+            .iter()
+            .position(|keys| *keys == self.sort.0.as_slice());
+        let next = at.map_or(0, |at| (at + 1) % SORTS.len());
+        self.sort = SortKeys(SORTS[next].to_vec());
+        self.selected = 0;
+    }
+
+    /// Show the next event.
+    pub fn next_event(&mut self) {
+        self.event = (self.event + 1) % self.events.len();
+        self.selected = 0;
+    }
+
+    /// Move the selection by `rows`, within the table.
+    pub fn select(&mut self, rows: isize) {
+        let last = self.rows().len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(rows).min(last);
+    }
+
+    /// The heaviest count in the selected row.
+    fn selected_key(&self) -> Option<EntryKey> {
+        let rows = self.rows();
+        rows.get(self.selected).map(|row| row.heaviest.0.clone())
+    }
+
+    /// Zoom into the file of the selected row, or back out.
+    pub fn zoom_dso(&mut self) {
+        self.dso = match self.dso {
+            Some(_) => None,
+            None => self.selected_key().and_then(|key| key.dso),
+        };
+        self.selected = 0;
+    }
+
+    /// Zoom into the thread of the selected row, or back out.
+    pub fn zoom_thread(&mut self) {
+        self.thread = match self.thread {
+            Some(_) => None,
+            None => self
+                .selected_key()
+                .and_then(|key| Some((key.pid?, key.comm?))),
+        };
+        self.selected = 0;
+    }
+
+    /// The screen, `height` lines of at most `width` characters,
+    /// with `rate` samples a second. An `interactive` screen
+    /// marks the selected row and ends with the keys.
+    pub fn lines(&self, width: usize, height: usize, rate: f64, interactive: bool) -> Vec<String> {
+        let mut title = format!(
+            "   PerfTop: {:8.0} irqs/sec  event: {}",
+            rate, self.events[self.event]
+        );
+        if self.events.len() > 1 {
+            title.push_str(&format!(" ({} of {})", self.event + 1, self.events.len()));
+        }
+        let names: Vec<&str> = self.sort.0.iter().map(SortKey::name).collect();
+        title.push_str(&format!("  sort: {}", names.join(",")));
+        if let Some(dso) = &self.dso {
+            let dso = EntryKey {
+                dso: Some(dso.clone()),
+                ..Default::default()
+            };
+            title.push_str(&format!("  dso: {}", dso.column(SortKey::Dso)));
+        }
+        if let Some((pid, comm)) = &self.thread {
+            title.push_str(&format!("  thread: {} {}", comm, pid));
+        }
+        if self.lost > 0 {
+            title.push_str(&format!("  lost: {}", self.lost));
+        }
+        if self.paused {
+            title.push_str("  [paused]");
+        }
+
+        let total = self.total().max(1);
+        let rows = self.rows();
+        let shown = height.saturating_sub(if interactive { 4 } else { 3 });
+        let rows = &rows[..rows.len().min(shown)];
+        let cells: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| self.sort.0.iter().map(|key| row.key.column(*key)).collect())
+            .collect();
+        let widths: Vec<usize> = self
+            .sort
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                cells
+                    .iter()
+                    .map(|cells| cells[i].chars().count())
+                    .chain(std::iter::once(key.heading().len()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let columns = |cells: &[String]| -> String {
+            let mut line = String::new();
+            for (i, cell) in cells.iter().enumerate() {
+                line.push_str("  ");
+                if i + 1 == cells.len() {
+                    line.push_str(cell);
+                } else {
+                    line.push_str(&format!("{:<width$}", cell, width = widths[i]));
+                }
+            }
+            line
+        };
+
+        let mut lines = vec![title, "-".repeat(width.min(80))];
+        let headings: Vec<String> = self
+            .sort
+            .0
+            .iter()
+            .map(|key| key.heading().to_string())
+            .collect();
+        lines.push(format!(" Overhead{}", columns(&headings)));
+        for (i, (row, cells)) in rows.iter().zip(&cells).enumerate() {
+            let marker = if interactive && i == self.selected {
+                '>'
+            } else {
+                ' '
+            };
+            let overhead = row.period as f64 * 100.0 / total as f64;
+            lines.push(format!("{}{:>7.2}%{}", marker, overhead, columns(cells)));
+        }
+        if interactive {
+            lines.resize(height.saturating_sub(1).max(lines.len()), String::new());
+            lines.push(
+                "s: sort  e: event  d/t: zoom file/thread  arrows: select  p: pause  q: quit"
+                    .to_string(),
+            );
+        }
+        lines
+            .into_iter()
+            .map(|line| line.chars().take(width).collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn view_test() {
+    let location = |comm: &str, pid, dso: &str, symbol: &str| Location {
+        pid,
+        comm: comm.to_string(),
+        dso: Some(dso.to_string()),
+        symbol: Some(symbol.to_string()),
+        ip: 0,
+        kernel: false,
+    };
+    let mut view = View::new(
+        vec!["cycles".to_string(), "instructions".to_string()],
+        "dso,sym".parse().unwrap(),
+    );
+    view.add(0, &location("a", 1, "libc.so", "memcpy"), 300);
+    view.add(0, &location("b", 2, "libc.so", "memcpy"), 100);
+    view.add(0, &location("b", 2, "b", "main"), 200);
+    view.add(1, &location("a", 1, "a", "main"), 5);
+    assert_eq!(view.recent, 4);
+    let rows = view.rows();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        (rows[0].key.column(SortKey::Sym), rows[0].period),
+        ("[.] memcpy".to_string(), 400)
+    );
+
+    view.decay();
+    assert_eq!(view.rows()[1].period, 175);
+    view.next_sort();
+    assert_eq!(view.sort.0, vec![SortKey::Sym]);
+    assert_eq!(view.rows()[1].period, 175);
+
+    // The heaviest count of `memcpy` is thread `a`'s.
+    view.zoom_thread();
+    assert_eq!(view.thread, Some((1, "a".to_string())));
+    assert_eq!(view.rows().len(), 1);
+    view.zoom_thread();
+    view.select(1);
+    view.zoom_dso();
+    assert_eq!(view.dso, Some(Some("b".to_string())));
+    assert_eq!(view.rows()[0].period, 175);
+    view.zoom_dso();
+
+    view.next_event();
+    assert_eq!(view.rows()[0].period, 4);
+    view.next_event();
+    view.paused = true;
+    view.add(0, &location("a", 1, "a", "main"), 5);
+    assert_eq!(view.rows().len(), 2);
+    for _ in 0..200 {
+        view.decay();
+    }
+    assert!(view.rows().is_empty());
+}
+
+#[test]
+fn lines_test() {
+    let location = |dso: &str, symbol: &str| Location {
+        pid: 1,
+        comm: "a".to_string(),
+        dso: Some(dso.to_string()),
+        symbol: Some(symbol.to_string()),
+        ip: 0,
+        kernel: false,
+    };
+    let mut view = View::new(vec!["cpu-clock".to_string()], "dso,sym".parse().unwrap());
+    view.add(0, &location("libc.so.6", "memcpy"), 3);
+    view.add(0, &location("a", "main"), 1);
+    let lines = view.lines(80, 10, 1234.0, true);
+    assert_eq!(lines.len(), 10);
+    assert_eq!(
+        lines[0],
+        "   PerfTop:     1234 irqs/sec  event: cpu-clock  sort: dso,sym"
+    );
+    assert_eq!(lines[1], "-".repeat(80));
+    assert_eq!(lines[2], " Overhead  Shared Object  Symbol");
+    assert_eq!(lines[3], ">  75.00%  libc.so.6      [.] memcpy");
+    assert_eq!(lines[4], "   25.00%  a              [.] main");
+    assert_eq!(lines[5], "");
+    assert!(lines[9].ends_with("q: quit"));
+
+    let lines = view.lines(usize::MAX, 4, 0.0, false);
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3], "   75.00%  libc.so.6      [.] memcpy");
+}
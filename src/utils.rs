@@ -1,9 +1,65 @@
 //! Errors for `ruperf stat`.
+use std::time::Duration;
 use thiserror::Error;
 
 /// Parse errors for CLI
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum ParseError {
     #[error("Invalid Event")]
     InvalidEvent,
+    #[error("Invalid PID/TID list")]
+    InvalidTaskList,
+    #[error("Invalid CPU list")]
+    InvalidCpuList,
+    #[error("Unknown PMU {0}")]
+    UnknownPmu(String),
+    #[error("Unknown term {1} for PMU {0}")]
+    UnknownTerm(String, String),
+    #[error("Invalid PMU event {0}")]
+    InvalidTerm(String),
+    #[error("Unknown tracepoint {0}")]
+    UnknownTracepoint(String),
+    #[error("tracefs is not mounted at /sys/kernel/tracing or /sys/kernel/debug/tracing")]
+    NoTracefs,
+    #[error("Invalid probe {0}")]
+    InvalidProbe(String),
+    #[error("Could not read ELF binary {0}")]
+    InvalidBinary(String),
+    #[error("Symbol {1} not found in {0}")]
+    UnknownSymbol(String, String),
+    #[error("Invalid breakpoint {0}")]
+    InvalidBreakpoint(String),
+    #[error("Breakpoint {0} names a symbol, so it needs a task to resolve it in")]
+    UnresolvedBreakpoint(String),
+    #[error("Unknown sort key {0}, expected comm, pid, dso or sym")]
+    InvalidSortKey(String),
+    #[error("Invalid call graph mode {0}, expected fp, dwarf[,SIZE] or lbr")]
+    InvalidCallGraph(String),
+    #[error("Invalid call order {0}, expected caller or callee")]
+    InvalidCallOrder(String),
+    #[error("Invalid time {0}, expected a positive number of seconds")]
+    InvalidSeconds(String),
+}
+
+/// Parse a positive, finite number of seconds from the
+/// command line, as `--duration` and `top -d` take them.
+pub fn parse_seconds(s: &str) -> Result<Duration, ParseError> {
+    let invalid = || ParseError::InvalidSeconds(s.to_string());
+    // Infinite, negative and NaN values are not durations at all.
+    let secs: f64 = s.parse().map_err(|_| invalid())?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn parse_seconds_test() {
+    assert_eq!(parse_seconds("2").unwrap(), Duration::from_secs(2));
+    assert_eq!(parse_seconds("0.25").unwrap(), Duration::from_millis(250));
+    for s in &["0", "-1", "inf", "NaN", "1e300", "1e-12", "two"] {
+        assert!(parse_seconds(s).is_err(), "{} was accepted", s);
+    }
 }